@@ -0,0 +1,155 @@
+use std::{fs::File, io::Read, path::Path};
+
+use anyhow::{anyhow, Context};
+use scanner_rust::{generic_array::typenum::U384, ScannerAscii};
+
+/// The key and the stored response headers of a cache file.
+#[derive(Debug, Default)]
+pub struct CacheFileHeader {
+    pub key:     Vec<u8>,
+    pub headers: Vec<(Vec<u8>, Vec<u8>)>,
+}
+
+impl CacheFileHeader {
+    /// Get the values of the stored response headers whose name is `name` (case-insensitive).
+    #[inline]
+    pub fn header_values<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a [u8]> + 'a {
+        self.headers
+            .iter()
+            .filter(move |(n, _)| n.eq_ignore_ascii_case(name.as_bytes()))
+            .map(|(_, v)| v.as_slice())
+    }
+
+    /// Get the tags in the stored response headers whose name is `name`. Tags can be separated by commas or whitespaces.
+    #[inline]
+    pub fn tags<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a [u8]> + 'a {
+        self.header_values(name).flat_map(|value| {
+            value.split(|u| *u == b',' || u.is_ascii_whitespace()).filter(|tag| !tag.is_empty())
+        })
+    }
+}
+
+/// Read the key of a cache file and, if `read_headers` is `true`, the response headers stored after it.
+pub fn read_cache_file_header<P: AsRef<Path>>(
+    file_path: P,
+    read_headers: bool,
+) -> anyhow::Result<CacheFileHeader> {
+    let file_path = file_path.as_ref();
+
+    let mut sc: ScannerAscii<_, U384> =
+        ScannerAscii::scan_path2(file_path).with_context(|| anyhow!("{file_path:?}"))?;
+
+    // skip the header
+    sc.drop_next_line().with_context(|| anyhow!("{file_path:?}"))?;
+
+    // skip the label
+    sc.drop_next_bytes("KEY: ".len()).with_context(|| anyhow!("{file_path:?}"))?;
+
+    let key = sc
+        .next_line_raw()
+        .with_context(|| anyhow!("{file_path:?}"))?
+        .ok_or(anyhow!("The content of {file_path:?} is incorrect."))?;
+
+    let mut headers = Vec::new();
+
+    if read_headers {
+        // the status line (if any) is skipped because it has no colon
+        while let Some(line) = sc.next_line_raw().with_context(|| anyhow!("{file_path:?}"))? {
+            if line.is_empty() {
+                break;
+            }
+
+            if let Some(index) = line.iter().position(|u| *u == b':') {
+                let name = trim_ascii_whitespace(&line[..index]).to_vec();
+                let value = trim_ascii_whitespace(&line[index + 1..]).to_vec();
+
+                headers.push((name, value));
+            }
+        }
+    }
+
+    Ok(CacheFileHeader {
+        key,
+        headers,
+    })
+}
+
+/// The version of `ngx_http_file_cache_header_t` written by nginx.
+const CACHE_FILE_VERSION: u64 = 5;
+
+/// The offsets of `header_start` and `body_start` in `ngx_http_file_cache_header_t` on 64-bit platforms.
+const HEADER_START_OFFSET: usize = 54;
+const BODY_START_OFFSET: usize = 56;
+
+/// Validate the binary header of a cache file and read its key. The error explains why the file is corrupt.
+pub fn validate_cache_file<P: AsRef<Path>>(file_path: P) -> anyhow::Result<Vec<u8>> {
+    let file_path = file_path.as_ref();
+
+    let mut file = File::open(file_path).with_context(|| anyhow!("{file_path:?}"))?;
+
+    let file_size = file.metadata().with_context(|| anyhow!("{file_path:?}"))?.len();
+
+    let mut header = [0u8; BODY_START_OFFSET + 2];
+
+    file.read_exact(&mut header).map_err(|_| anyhow!("The header is truncated."))?;
+
+    // nginx writes the header in the native byte order
+    let version = u64::from_ne_bytes(header[..8].try_into().unwrap());
+    let header_start = u16::from_ne_bytes(
+        header[HEADER_START_OFFSET..HEADER_START_OFFSET + 2].try_into().unwrap(),
+    ) as usize;
+    let body_start =
+        u16::from_ne_bytes(header[BODY_START_OFFSET..BODY_START_OFFSET + 2].try_into().unwrap())
+            as u64;
+
+    if version != CACHE_FILE_VERSION {
+        return Err(anyhow!("The version {version} of the header is not supported."));
+    }
+
+    if header_start <= header.len() || header_start as u64 > body_start {
+        return Err(anyhow!(
+            "The header start {header_start} is out of range (the body starts at {body_start})."
+        ));
+    }
+
+    if body_start > file_size {
+        return Err(anyhow!("The body start {body_start} is beyond the file size {file_size}."));
+    }
+
+    // the key line is right before the response headers
+    let mut rest = vec![0u8; header_start - header.len()];
+
+    file.read_exact(&mut rest).map_err(|_| anyhow!("The key is truncated."))?;
+
+    let key_start = rest
+        .windows(6)
+        .position(|w| w == b"\nKEY: ")
+        .ok_or_else(|| anyhow!("The key is missing."))?
+        + 6;
+
+    match rest.last() {
+        Some(b'\n') => Ok(rest[key_start..rest.len() - 1].to_vec()),
+        _ => Err(anyhow!("The key is not terminated.")),
+    }
+}
+
+#[inline]
+fn trim_ascii_whitespace(mut s: &[u8]) -> &[u8] {
+    while let [first, rest @ ..] = s {
+        if first.is_ascii_whitespace() {
+            s = rest;
+        } else {
+            break;
+        }
+    }
+
+    while let [rest @ .., last] = s {
+        if last.is_ascii_whitespace() {
+            s = rest;
+        } else {
+            break;
+        }
+    }
+
+    s
+}
@@ -0,0 +1,331 @@
+use std::{
+    fmt::{self, Display, Formatter},
+    path::PathBuf,
+};
+#[cfg(feature = "async")]
+use std::{fs, io, path::Path, time::Duration};
+
+#[cfg(feature = "async")]
+use anyhow::{anyhow, Context};
+
+#[cfg(feature = "async")]
+use crate::{
+    cache_file::validate_cache_file,
+    functions::{
+        create_cache_file_path, move_file, remove_empty_ancestors, remove_file, resolve_levels,
+    },
+};
+
+/// Temporary files which have been modified within this duration may still be written by nginx, so they are not reported.
+#[cfg(feature = "async")]
+const TEMP_FILE_MIN_AGE: Duration = Duration::from_secs(60 * 60);
+
+/// A problem of a file in a cache zone.
+#[derive(Debug)]
+pub enum Problem {
+    /// The file is named after the md5 of its key but is not in the directory implied by the levels.
+    Misplaced { expected: PathBuf },
+    /// The header cannot be parsed, or the file is not named after the md5 of its key.
+    Corrupt(String),
+    /// The file is not a cache file or a temporary file.
+    Orphaned,
+    /// The file is a temporary file which was left by an interrupted write, as it has not been modified for an hour.
+    Temp,
+}
+
+impl Display for Problem {
+    #[inline]
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Problem::Misplaced {
+                expected,
+            } => write!(f, "misplaced (should be {expected:?})"),
+            Problem::Corrupt(reason) => write!(f, "corrupt ({reason})"),
+            Problem::Orphaned => f.write_str("orphaned"),
+            Problem::Temp => f.write_str("temp"),
+        }
+    }
+}
+
+/// What to do with the problematic files.
+#[derive(Debug)]
+pub enum CheckAction {
+    Report,
+    Remove,
+    /// Move the files into this directory, keeping their paths relative to the cache path.
+    Quarantine(PathBuf),
+}
+
+#[derive(Debug, Default)]
+pub struct CheckReport {
+    /// the number of files which have been checked
+    pub checked:  usize,
+    pub problems: Vec<(PathBuf, Problem)>,
+}
+
+/// Walk a cache zone and check every file in it. If `action` quarantines the files into a directory in the cache zone, the directory is skipped.
+#[cfg(feature = "async")]
+pub async fn check_cache_zone<P: AsRef<Path>, L: AsRef<str>>(
+    cache_path: P,
+    levels: L,
+    action: &CheckAction,
+) -> anyhow::Result<CheckReport> {
+    let cache_path = cache_path.as_ref().to_path_buf();
+
+    let levels = match resolve_levels(cache_path.as_path(), levels).await? {
+        Some(levels) => levels,
+        None => return Ok(CheckReport::default()),
+    };
+
+    let quarantine_path = match action {
+        CheckAction::Quarantine(quarantine_path) => Some(quarantine_path.clone()),
+        _ => None,
+    };
+
+    tokio::task::spawn_blocking(move || {
+        check_cache_files(cache_path.as_path(), &levels, quarantine_path.as_deref())
+    })
+    .await?
+}
+
+#[cfg(feature = "async")]
+fn check_cache_files(
+    cache_path: &Path,
+    levels: &[usize],
+    quarantine_path: Option<&Path>,
+) -> anyhow::Result<CheckReport> {
+    let mut report = CheckReport::default();
+
+    // the quarantined files are not checked again
+    let skip_path = quarantine_path.and_then(|quarantine_path| {
+        let quarantine_path = quarantine_path.canonicalize().ok()?;
+        let cache_path_canonical = cache_path.canonicalize().ok()?;
+
+        Some(cache_path.join(quarantine_path.strip_prefix(cache_path_canonical).ok()?))
+    });
+
+    let mut directories = vec![cache_path.to_path_buf()];
+
+    while let Some(directory) = directories.pop() {
+        let entries = match fs::read_dir(directory.as_path()) {
+            Ok(entries) => entries,
+            Err(error) if error.kind() == io::ErrorKind::NotFound => continue,
+            Err(error) => return Err(error).with_context(|| anyhow!("{directory:?}")),
+        };
+
+        for entry in entries {
+            let entry = entry.with_context(|| anyhow!("{directory:?}"))?;
+            let path = entry.path();
+
+            let file_type = match entry.file_type() {
+                Ok(file_type) => file_type,
+                Err(error) if error.kind() == io::ErrorKind::NotFound => continue,
+                Err(error) => return Err(error).with_context(|| anyhow!("{path:?}")),
+            };
+
+            if file_type.is_dir() {
+                if skip_path.as_ref() != Some(&path) {
+                    directories.push(path);
+                }
+
+                continue;
+            }
+
+            report.checked += 1;
+
+            let file_name = entry.file_name();
+            let name = file_name.to_string_lossy();
+
+            if is_temp_file_name(&name) {
+                let modified = match entry.metadata().and_then(|metadata| metadata.modified()) {
+                    Ok(modified) => modified,
+                    // renamed by nginx during the walk
+                    Err(error) if error.kind() == io::ErrorKind::NotFound => {
+                        report.checked -= 1;
+
+                        continue;
+                    },
+                    Err(error) => return Err(error).with_context(|| anyhow!("{path:?}")),
+                };
+
+                // nginx may still be writing it
+                if modified.elapsed().is_ok_and(|age| age >= TEMP_FILE_MIN_AGE) {
+                    report.problems.push((path, Problem::Temp));
+                }
+
+                continue;
+            }
+
+            if !file_type.is_file() || !is_cache_file_name(&name) {
+                report.problems.push((path, Problem::Orphaned));
+
+                continue;
+            }
+
+            let key = match validate_cache_file(path.as_path()) {
+                Ok(key) => key,
+                Err(error) => {
+                    // removed by nginx during the walk
+                    if !path.exists() {
+                        report.checked -= 1;
+
+                        continue;
+                    }
+
+                    report.problems.push((path, Problem::Corrupt(format!("{error:#}"))));
+
+                    continue;
+                },
+            };
+
+            let expected = create_cache_file_path(cache_path, levels, &key);
+
+            if expected.file_name() != Some(file_name.as_os_str()) {
+                report.problems.push((
+                    path,
+                    Problem::Corrupt(String::from("the name is not the md5 of the key")),
+                ));
+            } else if expected != path {
+                report.problems.push((path, Problem::Misplaced {
+                    expected,
+                }));
+            }
+        }
+    }
+
+    report.problems.sort_by(|(a, _), (b, _)| a.cmp(b));
+
+    Ok(report)
+}
+
+/// Remove or quarantine the problematic files in a report.
+#[cfg(feature = "async")]
+pub async fn fix_problems<P: AsRef<Path>>(
+    cache_path: P,
+    report: &CheckReport,
+    action: &CheckAction,
+) -> anyhow::Result<()> {
+    let cache_path = cache_path.as_ref();
+
+    if let CheckAction::Report = action {
+        return Ok(());
+    }
+
+    for (path, _) in report.problems.iter() {
+        let relative_path = path.strip_prefix(cache_path).unwrap_or(path);
+
+        match action {
+            CheckAction::Report => unreachable!(),
+            CheckAction::Remove => match remove_file(path).await {
+                Ok(_) => (),
+                Err(error) if error.kind() == io::ErrorKind::NotFound => continue,
+                Err(error) => return Err(error).with_context(|| anyhow!("{path:?}")),
+            },
+            CheckAction::Quarantine(quarantine_path) => {
+                let to = quarantine_path.join(relative_path);
+
+                move_file(path, to.as_path())
+                    .await
+                    .with_context(|| anyhow!("{path:?} -> {to:?}"))?;
+            },
+        }
+
+        // keep the cache path itself
+        remove_empty_ancestors(path, relative_path.components().count() - 1).await?;
+    }
+
+    Ok(())
+}
+
+#[inline]
+pub(crate) fn is_cache_file_name(name: &str) -> bool {
+    name.len() == 32 && name.bytes().all(|u| matches!(u, b'0'..=b'9' | b'a'..=b'f'))
+}
+
+/// nginx names temporary files like `0000000001`, or like `<md5>.0000000001` if `use_temp_path=off`.
+#[cfg(feature = "async")]
+#[inline]
+fn is_temp_file_name(name: &str) -> bool {
+    let digits = match name.split_once('.') {
+        Some((hash, digits)) if is_cache_file_name(hash) => digits,
+        Some(_) => return false,
+        None => name,
+    };
+
+    digits.len() == 10 && digits.bytes().all(|u| u.is_ascii_digit())
+}
+
+#[cfg(all(test, feature = "async"))]
+mod tests {
+    use super::*;
+    use crate::test_zone::{cache_file, TestZone};
+
+    #[tokio::test]
+    async fn check_and_quarantine() {
+        let test_zone = TestZone::new("check", "1:2");
+
+        let good = test_zone.add("http/blog/", "");
+
+        // a cache file in the directory of another hash
+        let misplaced = test_zone.add("http/blog/2", "");
+        let misplaced_directory =
+            if misplaced.parent().unwrap().ends_with("0/00") { "1/11" } else { "0/00" };
+        let misplaced_to =
+            test_zone.path.join(misplaced_directory).join(misplaced.file_name().unwrap());
+
+        fs::create_dir_all(misplaced_to.parent().unwrap()).unwrap();
+        fs::rename(&misplaced, &misplaced_to).unwrap();
+
+        // a cache file whose name is not the md5 of its key
+        let renamed = test_zone.add("http/static/a.jpg", "");
+
+        fs::write(&renamed, cache_file(b"http/static/b.jpg", "")).unwrap();
+
+        let corrupt = test_zone.add("http/static/c.jpg", "");
+
+        fs::write(&corrupt, "not a cache file").unwrap();
+
+        let orphaned = good.with_file_name("index.html");
+
+        fs::write(&orphaned, "").unwrap();
+
+        // nginx may still be writing a fresh temporary file
+        fs::write(good.with_file_name("0000000001"), "").unwrap();
+
+        let report = check_cache_zone(&test_zone.path, "1:2", &CheckAction::Report).await.unwrap();
+
+        let problem = |path: &Path| {
+            report
+                .problems
+                .iter()
+                .find(|(problem_path, _)| problem_path == path)
+                .map(|(_, problem)| problem.to_string())
+                .unwrap_or_default()
+        };
+
+        assert_eq!(report.checked, 6);
+        assert_eq!(report.problems.len(), 4);
+        assert_eq!(problem(&misplaced_to), format!("misplaced (should be {misplaced:?})"));
+        assert_eq!(problem(&renamed), "corrupt (the name is not the md5 of the key)");
+        assert!(problem(&corrupt).starts_with("corrupt"));
+        assert_eq!(problem(&orphaned), "orphaned");
+
+        // the quarantine directory in the cache zone is not checked again
+        let quarantine_path = test_zone.path.join("quarantine");
+        let action = CheckAction::Quarantine(quarantine_path.clone());
+
+        fs::create_dir(&quarantine_path).unwrap();
+
+        let report = check_cache_zone(&test_zone.path, "1:2", &action).await.unwrap();
+
+        fix_problems(&test_zone.path, &report, &action).await.unwrap();
+
+        assert!(!orphaned.exists());
+        assert!(quarantine_path.join(orphaned.strip_prefix(&test_zone.path).unwrap()).exists());
+
+        let report = check_cache_zone(&test_zone.path, "1:2", &action).await.unwrap();
+
+        assert_eq!(report.checked, 2);
+        assert!(report.problems.is_empty());
+    }
+}
@@ -1,5 +1,7 @@
 use std::path::PathBuf;
 
+#[cfg(feature = "service")]
+use clap::ArgMatches;
 use clap::{CommandFactory, FromArgMatches, Parser, Subcommand};
 use concat_with::concat_line;
 use terminal_size::terminal_size;
@@ -18,8 +20,24 @@ const APP_ABOUT: &str = concat!(
         "p /path/to/cache 2:1 '*/help*'              # Purge the caches with the key which contains the substring \"/help\" in the \"cache zone\" whose \"path\" is /path/to/cache, \"levels\" is 2:1",
         "p /path/to/cache 1 '*'                      # Purge all caches in the \"cache zone\" whose \"path\" is /path/to/cache, \"levels\" is 1",
         "p /path/to/cache 2 '*' -e 'http/static/*'   # Purge all caches except for those whose key starts with \"http/static/\" in the \"cache zone\" whose \"path\" is /path/to/cache, \"levels\" is 2",
+        "p /path/to/cache 1 'http/a?b=\\*'            # Purge the cache with the key \"http/a?b=*\" (a literal `*`) in the \"cache zone\" whose \"path\" is /path/to/cache, \"levels\" is 1",
+        "p /path/to/cache 1 '*.{jpg,png}' --glob     # Purge the caches with the key which ends with \".jpg\" or \".png\" in the \"cache zone\" whose \"path\" is /path/to/cache, \"levels\" is 1",
+        "p /path/to/cache 1:2 --keys-from keys.txt   # Purge the caches with the keys (or key patterns) listed in keys.txt, one per line, in a single pass over the \"cache zone\" whose \"path\" is /path/to/cache, \"levels\" is 1:2",
+        "p /path/to/cache 1:2 --url https://a.com/b  # Purge the cache of the URL https://a.com/b, whose key is rendered by the key template set by the --key-template option, in the \"cache zone\" whose \"path\" is /path/to/cache, \"levels\" is 1:2",
+        "p --zone my_cache http/blog/                # Purge the cache with the key \"http/blog/\" in the \"cache zone\" whose \"keys_zone\" is my_cache in /etc/nginx/nginx.conf",
+        "p /path/to/cache 1:2 http/blog/ --json      # Purge the cache with the key \"http/blog/\" in the \"cache zone\" whose \"path\" is /path/to/cache, \"levels\" is 1:2 and print a JSON summary of the result, such as {\"status\":\"ok\",\"exit_code\":0}",
+        "p /path/to/cache 1:2 '/v[0-9]+/' --regex    # Purge the caches with the key which contains a match of the regular expression \"/v[0-9]+/\" in the \"cache zone\" whose \"path\" is /path/to/cache, \"levels\" is 1:2",
+        "m /path/to/cache 1:2 2 --to /new/cache      # Move the caches in the \"cache zone\" whose \"path\" is /path/to/cache, \"levels\" is 1:2 into /new/cache with \"levels\" 2",
+        "t /path/to/cache 1:2 article-123            # Purge the caches whose \"Cache-Tag\" response header contains the tag \"article-123\" in the \"cache zone\" whose \"path\" is /path/to/cache, \"levels\" is 1:2",
+        "t /path/to/cache 1:2 a b --tag-header xkey  # Purge the caches whose \"xkey\" response header contains the tag \"a\" or \"b\" in the \"cache zone\" whose \"path\" is /path/to/cache, \"levels\" is 1:2",
+        "p /path/to/cache 1:2 '*' --trash /var/trash # Move all caches in the \"cache zone\" whose \"path\" is /path/to/cache, \"levels\" is 1:2 into the trash directory /var/trash instead of removing them",
+        "restore 1700000000-1234 --trash /var/trash  # Move the caches of the purge 1700000000-1234 back from the trash directory /var/trash",
+        "trash gc --trash /var/trash --retention 7d  # Permanently remove the purges which are older than 7 days from the trash directory /var/trash",
+        "c /path/to/cache 1:2                        # Check the files in the \"cache zone\" whose \"path\" is /path/to/cache, \"levels\" is 1:2 and report the misplaced, corrupt, orphaned and temporary ones",
         "s                                           # Start a server which listens on \"/tmp/nginx-cache-purge.sock\" to handle purge requests",
         "s /run/nginx-cache-purge.sock               # Start a server which listens on \"/run/nginx-cache-purge.sock\" to handle purge requests",
+        "s --index-dir /path/to/index                # Start a server which maintains the indexes of the purged \"cache zones\" in /path/to/index to speed up wildcard and tag purges",
+        "s --index-dir /idx --watch /path/to/cache 2 # Start a server which maintains the indexes in /idx and keeps the index of the \"cache zone\" whose \"path\" is /path/to/cache, \"levels\" is 2 up to date with inotify",
     )
 );
 
@@ -40,6 +58,113 @@ pub enum CLICommands {
     #[command(about = "Purge the cache immediately")]
     #[command(after_help = AFTER_HELP)]
     Purge {
+        #[arg(required_unless_present = "zone", value_hint = clap::ValueHint::DirPath)]
+        #[arg(help = "Assign the path set by proxy_cache_path or fastcgi_cache_path. With \
+                      --zone, it is the first key")]
+        cache_path: Option<PathBuf>,
+
+        #[arg(required_unless_present = "zone")]
+        #[arg(help = "Assign the levels set by proxy_cache_path or fastcgi_cache_path, or \
+                      `auto` to detect them from the cache directory. With --zone, it is the \
+                      second key")]
+        levels: Option<String>,
+
+        #[arg(required_unless_present_any = ["zone", "keys_from", "urls"])]
+        #[arg(help = "Assign the keys set by proxy_cache_key or fastcgi_cache_key")]
+        keys: Vec<String>,
+
+        #[arg(long)]
+        #[arg(help = "Assign the name of a cache zone (keys_zone) defined in the nginx \
+                      configuration, whose path, levels and key template are used")]
+        zone: Option<String>,
+
+        #[arg(long, default_value = "/etc/nginx/nginx.conf", value_hint = clap::ValueHint::FilePath)]
+        #[arg(help = "Assign the nginx configuration file used by --zone. Use `-` to read the \
+                      output of `nginx -T` from stdin")]
+        nginx_conf: PathBuf,
+
+        #[arg(long, value_hint = clap::ValueHint::FilePath)]
+        #[arg(help = "Read more keys from a file, one per line. Use `-` to read them from stdin")]
+        keys_from: Option<PathBuf>,
+
+        #[arg(long = "url", value_hint = clap::ValueHint::Url)]
+        #[arg(help = "Assign the URLs whose cache keys are rendered by the key template (or the \
+                      cache key of the zone) and purged exactly")]
+        urls: Vec<String>,
+
+        #[arg(long)]
+        #[arg(help = "Assign the key template set by proxy_cache_key or fastcgi_cache_key, like \
+                      $scheme$host$request_uri")]
+        key_template: Option<String>,
+
+        #[arg(long, default_value = "GET")]
+        #[arg(help = "Assign the request method used to render the keys of the URLs")]
+        method: String,
+
+        #[arg(short = 'H', long = "header")]
+        #[arg(
+            help = "Assign a request header like `Name: value` used to render the keys of the URLs"
+        )]
+        headers: Vec<String>,
+
+        #[arg(short, long, visible_alias = "exclude-key")]
+        #[arg(num_args = 1..)]
+        #[arg(help = "Assign the keys that should be excluded")]
+        exclude_keys: Vec<String>,
+
+        #[arg(long, value_hint = clap::ValueHint::FilePath)]
+        #[arg(help = "Read more keys that should be excluded from a file, one per line. Use `-` \
+                      to read them from stdin")]
+        exclude_from: Option<PathBuf>,
+
+        #[arg(long)]
+        #[arg(help = "Treat the key and the exclude keys as regular expressions which are \
+                      searched in the keys of caches")]
+        regex: bool,
+
+        #[arg(long, conflicts_with = "regex")]
+        #[arg(help = "Treat the key and the exclude keys as glob patterns which also support \
+                      `?`, `[a-z]` and `{jpg,png}` and have to match the whole key")]
+        glob: bool,
+
+        #[arg(long)]
+        #[arg(help = "Match the host part of keys (before the first `/`) case-insensitively")]
+        ignore_host_case: bool,
+
+        #[arg(long)]
+        #[arg(help = "Decode percent-encoded characters in the key and the keys of caches \
+                      before matching them")]
+        decode_percent: bool,
+
+        #[arg(long)]
+        #[arg(help = "Collapse duplicate slashes in the key and the keys of caches before \
+                      matching them")]
+        collapse_slashes: bool,
+
+        #[arg(long, value_hint = clap::ValueHint::DirPath)]
+        #[arg(help = "Move the purged caches into a timestamped area in this directory instead \
+                      of removing them, so that they can be restored with the restore command")]
+        trash: Option<PathBuf>,
+
+        #[arg(long)]
+        #[arg(help = "Allow purging all caches in a directory which is a mount root or does not \
+                      look like a cache zone of the levels")]
+        force: bool,
+
+        #[arg(long, value_name = "N")]
+        #[arg(help = "Stop a wildcard purge after removing this number of caches")]
+        max_entries: Option<usize>,
+
+        #[arg(long)]
+        #[arg(help = "Print a JSON summary of the result on stdout")]
+        json: bool,
+    },
+    #[cfg(feature = "async")]
+    #[command(visible_alias = "c")]
+    #[command(about = "Check the files in a cache zone and report the misplaced, corrupt, \
+                       orphaned and temporary ones")]
+    #[command(after_help = AFTER_HELP)]
+    Check {
         #[arg(value_hint = clap::ValueHint::DirPath)]
         #[arg(help = "Assign the path set by proxy_cache_path or fastcgi_cache_path")]
         cache_path: PathBuf,
@@ -47,13 +172,96 @@ pub enum CLICommands {
         #[arg(help = "Assign the levels set by proxy_cache_path or fastcgi_cache_path")]
         levels: String,
 
-        #[arg(help = "Assign the key set by proxy_cache_key or fastcgi_cache_key")]
-        key: String,
+        #[arg(long)]
+        #[arg(help = "Remove the reported files")]
+        remove: bool,
+
+        #[arg(long, conflicts_with = "remove", value_hint = clap::ValueHint::DirPath)]
+        #[arg(help = "Move the reported files into this directory, keeping their paths relative \
+                      to the cache path")]
+        quarantine: Option<PathBuf>,
+    },
+    #[cfg(feature = "async")]
+    #[command(visible_alias = "m")]
+    #[command(about = "Move the caches of a cache zone into the directory layout of other levels")]
+    #[command(after_help = AFTER_HELP)]
+    Migrate {
+        #[arg(value_hint = clap::ValueHint::DirPath)]
+        #[arg(help = "Assign the path set by proxy_cache_path or fastcgi_cache_path")]
+        cache_path: PathBuf,
+
+        #[arg(help = "Assign the levels set by proxy_cache_path or fastcgi_cache_path, or \
+                      `auto` to detect them from the cache directory")]
+        levels: String,
+
+        #[arg(help = "Assign the new levels")]
+        new_levels: String,
+
+        #[arg(long, value_hint = clap::ValueHint::DirPath)]
+        #[arg(help = "Assign the new path. The caches are moved with renames on the same file \
+                      system, or copied and then renamed across file systems")]
+        to: Option<PathBuf>,
+    },
+    #[command(visible_alias = "t")]
+    #[command(about = "Purge the caches tagged with specific tags immediately")]
+    #[command(after_help = AFTER_HELP)]
+    Tag {
+        #[arg(value_hint = clap::ValueHint::DirPath)]
+        #[arg(help = "Assign the path set by proxy_cache_path or fastcgi_cache_path")]
+        cache_path: PathBuf,
+
+        #[arg(help = "Assign the levels set by proxy_cache_path or fastcgi_cache_path, or \
+                      `auto` to detect them from the cache directory")]
+        levels: String,
+
+        #[arg(required = true)]
+        #[arg(help = "Assign the tags whose caches should be purged")]
+        tags: Vec<String>,
+
+        #[arg(long, default_value = "Cache-Tag")]
+        #[arg(help = "Assign the name of the stored response header which contains the tags")]
+        tag_header: String,
 
         #[arg(short, long, visible_alias = "exclude-key")]
         #[arg(num_args = 1..)]
         #[arg(help = "Assign the keys that should be excluded")]
         exclude_keys: Vec<String>,
+
+        #[arg(long, value_hint = clap::ValueHint::FilePath)]
+        #[arg(help = "Read more keys that should be excluded from a file, one per line. Use `-` \
+                      to read them from stdin")]
+        exclude_from: Option<PathBuf>,
+
+        #[arg(long, value_hint = clap::ValueHint::DirPath)]
+        #[arg(help = "Move the purged caches into a timestamped area in this directory instead \
+                      of removing them, so that they can be restored with the restore command")]
+        trash: Option<PathBuf>,
+
+        #[arg(long, value_name = "N")]
+        #[arg(help = "Stop the purge after removing this number of caches")]
+        max_entries: Option<usize>,
+
+        #[arg(long)]
+        #[arg(help = "Print a JSON summary of the result on stdout")]
+        json: bool,
+    },
+    #[cfg(feature = "async")]
+    #[command(about = "Move the caches of a purge back from the trash into the cache zone, \
+                       except for the ones which nginx has recreated")]
+    #[command(after_help = AFTER_HELP)]
+    Restore {
+        #[arg(help = "Assign the ID of the purge, which is printed after a purge with --trash")]
+        purge_id: String,
+
+        #[arg(long, value_hint = clap::ValueHint::DirPath)]
+        #[arg(help = "Assign the trash directory")]
+        trash: PathBuf,
+    },
+    #[command(about = "Manage the trash of purged caches")]
+    #[command(after_help = AFTER_HELP)]
+    Trash {
+        #[command(subcommand)]
+        command: TrashCommands,
     },
     #[cfg(feature = "service")]
     #[command(visible_alias = "s")]
@@ -63,6 +271,126 @@ pub enum CLICommands {
         #[arg(default_value = "/tmp/nginx-cache-purge.sock")]
         #[arg(value_hint = clap::ValueHint::FilePath)]
         socket_file_path: PathBuf,
+
+        #[arg(long, value_hint = clap::ValueHint::DirPath)]
+        #[arg(help = "Assign a directory to store the indexes of keys and tags, so that \
+                      wildcard and tag purges do not have to read every cache file")]
+        index_dir: Option<PathBuf>,
+
+        #[arg(long, default_value = "Cache-Tag")]
+        #[arg(help = "Assign the name of the stored response header whose tags are indexed")]
+        index_tag_header: String,
+
+        #[arg(long)]
+        #[arg(num_args = 3, value_names = ["NAME", "CACHE_PATH", "LEVELS"])]
+        #[arg(help = "Assign a named cache zone which can be referred to by its name in batch \
+                      purge requests")]
+        zone: Vec<String>,
+
+        #[arg(long, value_hint = clap::ValueHint::FilePath)]
+        #[arg(help = "Register the cache zones defined in the nginx configuration file, with \
+                      their key templates. Use `-` to read the output of `nginx -T` from stdin")]
+        nginx_conf: Option<PathBuf>,
+
+        #[arg(long)]
+        #[arg(num_args = 2, value_names = ["NAME", "TEMPLATE"])]
+        #[arg(help = "Assign the key template of a named cache zone, like \
+                      $scheme$host$request_uri, so that URLs can be purged in the zone")]
+        key_template: Vec<String>,
+
+        #[arg(long)]
+        #[arg(num_args = 2, value_names = ["NAME", "PREFIX"])]
+        #[arg(help = "Remove the prefix from the keys of the purge requests in a named cache \
+                      zone if they start with it")]
+        strip_prefix: Vec<String>,
+
+        #[arg(long)]
+        #[arg(num_args = 3, value_names = ["NAME", "REGEX", "REPLACEMENT"])]
+        #[arg(help = "Replace the first match of the regular expression in the keys of the \
+                      purge requests in a named cache zone. The replacement can refer to \
+                      capture groups like $1")]
+        rewrite: Vec<String>,
+
+        #[arg(long)]
+        #[arg(num_args = 3, value_names = ["NAME", "PARAM", "VALUE"])]
+        #[arg(
+            help = "Set a query parameter in the keys of the purge requests in a named cache zone"
+        )]
+        add_query: Vec<String>,
+
+        #[arg(long)]
+        #[arg(num_args = 2, value_names = ["NAME", "PARAM"])]
+        #[arg(help = "Remove a query parameter from the keys of the purge requests in a named \
+                      cache zone")]
+        remove_query: Vec<String>,
+
+        #[arg(long)]
+        #[arg(help = "Assign a key pattern in the wildcard mode whose caches can never be \
+                      purged in any cache zone, like a health check page")]
+        protect: Vec<String>,
+
+        #[arg(long)]
+        #[arg(num_args = 2, value_names = ["NAME", "PATTERN"])]
+        #[arg(help = "Assign a key pattern in the wildcard mode whose caches can never be \
+                      purged in a named cache zone")]
+        zone_protect: Vec<String>,
+
+        #[arg(long)]
+        #[arg(num_args = 2, value_names = ["NAME", "N"])]
+        #[arg(help = "Stop the wildcard purges in a named cache zone after removing this number \
+                      of caches, unless the purge requests lift the limit")]
+        max_entries: Vec<String>,
+
+        #[arg(long)]
+        #[arg(help = "Allow purging all caches in a directory which is a mount root or does not \
+                      look like a cache zone of the levels")]
+        force: bool,
+
+        #[cfg(target_os = "linux")]
+        #[arg(long, requires = "index_dir")]
+        #[arg(num_args = 2, value_names = ["CACHE_PATH", "LEVELS"])]
+        #[arg(help = "Assign a cache zone whose index should be kept up to date by watching its \
+                      level directories with inotify")]
+        watch: Vec<String>,
+
+        /// the rewrite options in the order they appear on the command line
+        #[arg(skip)]
+        rewrite_order: Vec<RewriteOption>,
+    },
+}
+
+/// An option of the `start` command which adds a rewrite rule.
+#[cfg(feature = "service")]
+#[derive(Debug, Clone, Copy)]
+pub enum RewriteOption {
+    StripPrefix,
+    Rewrite,
+    RemoveQuery,
+    AddQuery,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum TrashCommands {
+    #[command(about = "List the purges in the trash")]
+    #[command(after_help = AFTER_HELP)]
+    List {
+        #[arg(long, value_hint = clap::ValueHint::DirPath)]
+        #[arg(help = "Assign the trash directory")]
+        trash: PathBuf,
+    },
+    #[cfg(feature = "async")]
+    #[command(
+        about = "Permanently remove the purges which are older than the retention from the trash"
+    )]
+    #[command(after_help = AFTER_HELP)]
+    Gc {
+        #[arg(long, value_hint = clap::ValueHint::DirPath)]
+        #[arg(help = "Assign the trash directory")]
+        trash: PathBuf,
+
+        #[arg(long, default_value = "7d")]
+        #[arg(help = "Assign how long the purges are kept, like 3600s, 30m, 12h or 7d")]
+        retention: String,
     },
 }
 
@@ -75,10 +403,46 @@ pub fn get_args() -> CLIArgs {
 
     let matches = args.get_matches();
 
-    match CLIArgs::from_arg_matches(&matches) {
+    let args = match CLIArgs::from_arg_matches(&matches) {
         Ok(args) => args,
         Err(err) => {
             err.exit();
         },
+    };
+
+    #[cfg(feature = "service")]
+    let args = with_rewrite_order(args, &matches);
+
+    args
+}
+
+/// Record the order of the rewrite options, which clap does not keep across different options.
+#[cfg(feature = "service")]
+fn with_rewrite_order(mut args: CLIArgs, matches: &ArgMatches) -> CLIArgs {
+    if let (
+        CLICommands::Start {
+            rewrite_order, ..
+        },
+        Some(("start", matches)),
+    ) = (&mut args.command, matches.subcommand())
+    {
+        let mut positions = Vec::new();
+
+        for (id, option, num_args) in [
+            ("strip_prefix", RewriteOption::StripPrefix, 2),
+            ("rewrite", RewriteOption::Rewrite, 3),
+            ("remove_query", RewriteOption::RemoveQuery, 2),
+            ("add_query", RewriteOption::AddQuery, 3),
+        ] {
+            if let Some(indices) = matches.indices_of(id) {
+                positions.extend(indices.step_by(num_args).map(|index| (index, option)));
+            }
+        }
+
+        positions.sort_by_key(|(index, _)| *index);
+
+        *rewrite_order = positions.into_iter().map(|(_, option)| option).collect();
     }
+
+    args
 }
@@ -0,0 +1,219 @@
+use std::{
+    error::Error,
+    fmt::{self, Display, Formatter},
+    io,
+    path::{Path, PathBuf},
+};
+
+use scanner_rust::ScannerError;
+
+/// Why a purge fails.
+#[derive(Debug)]
+pub enum PurgeError {
+    /// The arguments, such as the query of a purge request, are incorrect. The exit status is 2.
+    InvalidArguments(String),
+    /// No cache zone has this name. The exit status is 67.
+    ZoneNotFound(String),
+    /// The levels are incorrect or cannot be detected from the cache zone. The exit status is 64.
+    InvalidLevels(String),
+    /// A key, an exclude key, a tag or a pattern is incorrect. The exit status is 65.
+    InvalidKey(String),
+    /// The cache path is not a directory. The exit status is 66.
+    NotADirectory(PathBuf),
+    /// The permission of a file or a directory is denied. The exit status is 77.
+    PermissionDenied(PathBuf),
+    /// A cache file, or the index of a cache zone, cannot be parsed. The exit status is 76.
+    CorruptEntry { path: PathBuf, reason: String },
+    /// Any other I/O error of a file or a directory. The exit status is 74.
+    Io { path: PathBuf, source: io::Error },
+    /// The directory does not look like a cache zone, so it is not wiped. The exit status is 78.
+    Refused(String),
+    /// The purge task has been cancelled, such as by a shutdown. The exit status is 75.
+    Cancelled,
+    /// Some caches have been purged before the error occurred. The exit status is 48.
+    Partial(Box<PurgeError>),
+}
+
+impl PurgeError {
+    /// Create an error from an I/O error of `path`. A permission error becomes `PermissionDenied`.
+    #[inline]
+    pub fn io(path: &Path, error: io::Error) -> Self {
+        let path = path.to_path_buf();
+
+        if error.kind() == io::ErrorKind::PermissionDenied {
+            PurgeError::PermissionDenied(path)
+        } else {
+            PurgeError::Io {
+                path,
+                source: error,
+            }
+        }
+    }
+
+    #[inline]
+    pub(crate) fn invalid_key(error: anyhow::Error) -> Self {
+        PurgeError::InvalidKey(format!("{error:#}"))
+    }
+
+    /// Create an error from an error of reading a file. The I/O errors keep their kinds, and the other errors mean that the file is corrupt.
+    pub(crate) fn read(path: &Path, error: anyhow::Error) -> Self {
+        for cause in error.chain() {
+            let io_error = if let Some(io_error) = cause.downcast_ref::<io::Error>() {
+                io_error
+            } else if let Some(ScannerError::IOError(io_error)) =
+                cause.downcast_ref::<ScannerError>()
+            {
+                io_error
+            } else {
+                continue;
+            };
+
+            return PurgeError::io(path, io::Error::new(io_error.kind(), io_error.to_string()));
+        }
+
+        PurgeError::CorruptEntry {
+            path: path.to_path_buf(), reason: format!("{error:#}")
+        }
+    }
+
+    /// Whether the file of this error does not exist, which means it has been removed by someone else.
+    #[inline]
+    pub(crate) fn is_not_found(&self) -> bool {
+        matches!(self, PurgeError::Io { source, .. } if source.kind() == io::ErrorKind::NotFound)
+    }
+
+    /// The HTTP status code which the purge service responds with.
+    #[inline]
+    pub fn status_code(&self) -> u16 {
+        match self {
+            PurgeError::InvalidArguments(_)
+            | PurgeError::InvalidLevels(_)
+            | PurgeError::InvalidKey(_) => 400,
+            PurgeError::PermissionDenied(_) => 403,
+            PurgeError::ZoneNotFound(_) | PurgeError::NotADirectory(_) => 404,
+            PurgeError::Refused(_) => 422,
+            PurgeError::CorruptEntry {
+                ..
+            }
+            | PurgeError::Io {
+                ..
+            } => 500,
+            PurgeError::Cancelled => 503,
+            PurgeError::Partial(error) => error.status_code(),
+        }
+    }
+
+    /// The exit status of the CLI. Most of them are from `sysexits.h`.
+    #[inline]
+    pub fn exit_code(&self) -> u8 {
+        match self {
+            // the same as the errors of the command-line arguments
+            PurgeError::InvalidArguments(_) => 2,
+            PurgeError::Partial(_) => 48,
+            PurgeError::InvalidLevels(_) => 64,
+            PurgeError::InvalidKey(_) => 65,
+            PurgeError::NotADirectory(_) => 66,
+            PurgeError::ZoneNotFound(_) => 67,
+            PurgeError::Io {
+                ..
+            } => 74,
+            PurgeError::Cancelled => 75,
+            PurgeError::CorruptEntry {
+                ..
+            } => 76,
+            PurgeError::PermissionDenied(_) => 77,
+            PurgeError::Refused(_) => 78,
+        }
+    }
+
+    /// A short name of the variant, such as `permission_denied`.
+    #[inline]
+    pub fn kind(&self) -> &'static str {
+        match self {
+            PurgeError::InvalidArguments(_) => "invalid_arguments",
+            PurgeError::ZoneNotFound(_) => "zone_not_found",
+            PurgeError::InvalidLevels(_) => "invalid_levels",
+            PurgeError::InvalidKey(_) => "invalid_key",
+            PurgeError::NotADirectory(_) => "not_a_directory",
+            PurgeError::PermissionDenied(_) => "permission_denied",
+            PurgeError::CorruptEntry {
+                ..
+            } => "corrupt_entry",
+            PurgeError::Io {
+                ..
+            } => "io",
+            PurgeError::Refused(_) => "refused",
+            PurgeError::Cancelled => "cancelled",
+            PurgeError::Partial(_) => "partial_failure",
+        }
+    }
+}
+
+impl Display for PurgeError {
+    #[inline]
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            PurgeError::InvalidArguments(message)
+            | PurgeError::InvalidLevels(message)
+            | PurgeError::InvalidKey(message)
+            | PurgeError::Refused(message) => f.write_str(message),
+            PurgeError::ZoneNotFound(name) => write!(f, "The cache zone {name:?} does not exist."),
+            PurgeError::NotADirectory(path) => write!(f, "{path:?} is not a directory."),
+            PurgeError::PermissionDenied(path) => write!(f, "{path:?}: permission denied"),
+            PurgeError::CorruptEntry {
+                path,
+                reason,
+            } => write!(f, "{path:?} is corrupt: {reason}"),
+            // the I/O error is the source
+            PurgeError::Io {
+                path, ..
+            } => write!(f, "{path:?}"),
+            PurgeError::Cancelled => f.write_str("The purge has been cancelled."),
+            // the error is the source
+            PurgeError::Partial(_) => f.write_str("Some caches have been purged before an error."),
+        }
+    }
+}
+
+impl Error for PurgeError {
+    #[inline]
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            PurgeError::Io {
+                source, ..
+            } => Some(source),
+            PurgeError::Partial(error) => Some(error.as_ref()),
+            _ => None,
+        }
+    }
+}
+
+impl Clone for PurgeError {
+    #[inline]
+    fn clone(&self) -> Self {
+        match self {
+            PurgeError::InvalidArguments(message) => PurgeError::InvalidArguments(message.clone()),
+            PurgeError::ZoneNotFound(name) => PurgeError::ZoneNotFound(name.clone()),
+            PurgeError::InvalidLevels(message) => PurgeError::InvalidLevels(message.clone()),
+            PurgeError::InvalidKey(message) => PurgeError::InvalidKey(message.clone()),
+            PurgeError::NotADirectory(path) => PurgeError::NotADirectory(path.clone()),
+            PurgeError::PermissionDenied(path) => PurgeError::PermissionDenied(path.clone()),
+            PurgeError::CorruptEntry {
+                path,
+                reason,
+            } => PurgeError::CorruptEntry {
+                path: path.clone(), reason: reason.clone()
+            },
+            PurgeError::Io {
+                path,
+                source,
+            } => PurgeError::Io {
+                path:   path.clone(),
+                source: io::Error::new(source.kind(), source.to_string()),
+            },
+            PurgeError::Refused(message) => PurgeError::Refused(message.clone()),
+            PurgeError::Cancelled => PurgeError::Cancelled,
+            PurgeError::Partial(error) => PurgeError::Partial(error.clone()),
+        }
+    }
+}
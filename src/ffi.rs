@@ -0,0 +1,399 @@
+/*!
+A small C ABI for purging in-process, such as from OpenResty with the LuaJIT FFI.
+
+The functions which purge return the same statuses as the exit statuses of the CLI, so `0` means that any cache has been purged and `44` means that the cache has already been purged. If an error occurs, its message can be got by `ncp_last_error` on the same thread.
+
+Every function is synchronous and blocks the calling thread, which is an nginx worker when called from OpenResty, until it finishes. `ncp_purge_pattern` walks the whole cache zone, so it should only be called with a small cache zone or from a process which does not serve requests, such as the privileged agent of OpenResty. Its `threads` argument is how many threads remove the matched caches. `0` and `1` remove them on the calling thread without starting any thread.
+
+The shared library is built by `cargo rustc --profile ffi --no-default-features --features ffi --lib --crate-type cdylib`. The `ffi` profile unwinds on panic, so that a panic in a purge returns `1` instead of aborting the nginx worker.
+
+```c
+typedef struct ncp_zone ncp_zone;
+typedef int (*ncp_progress)(const char *path, const char *key, void *user_data);
+
+ncp_zone *ncp_zone_new(const char *cache_path, const char *levels);
+void ncp_zone_free(ncp_zone *zone);
+int ncp_purge_key(const ncp_zone *zone, const char *key);
+int ncp_purge_pattern(const ncp_zone *zone, const char *pattern, int mode, unsigned int flags, unsigned int threads, ncp_progress progress, void *user_data);
+const char *ncp_last_error(void);
+```
+*/
+
+use std::{
+    cell::RefCell,
+    ffi::{c_char, c_int, c_uint, c_void, CStr, CString},
+    panic::{self, AssertUnwindSafe},
+    ptr,
+};
+
+use crate::{
+    error::PurgeError,
+    functions::PurgeOptions,
+    matcher::{MatchMode, MatchOptions},
+    zone::{CacheEntry, CacheZone},
+    AppResult,
+};
+
+/// The same as `*`, which matches any sequence of characters.
+pub const NCP_MODE_WILDCARD: c_int = 0;
+/// The glob syntax, such as `?`, `[a-z]` and `{jpg,png}`.
+pub const NCP_MODE_GLOB: c_int = 1;
+/// A regular expression which is searched in the key.
+pub const NCP_MODE_REGEX: c_int = 2;
+
+/// Purge all caches in a directory even if it is a mount root or does not look like a cache zone, like the `--force` option.
+pub const NCP_FLAG_FORCE: c_uint = 1;
+
+/// The status returned when the purge panics, if the library is built with `panic = "unwind"`.
+const PANIC_STATUS: c_int = 1;
+
+/// Called with the path and the key of each removed cache. Return `0` to stop the purge.
+pub type NcpProgress = Option<
+    unsafe extern "C" fn(path: *const c_char, key: *const c_char, user_data: *mut c_void) -> c_int,
+>;
+
+thread_local! {
+    static LAST_ERROR: RefCell<CString> = RefCell::new(CString::default());
+}
+
+fn set_last_error(message: String) {
+    // an error message does not contain NUL, but just in case
+    let message = CString::new(message.replace('\0', "")).unwrap_or_default();
+
+    LAST_ERROR.with(|last_error| *last_error.borrow_mut() = message);
+}
+
+/// Set the last error and return its exit status.
+fn fail(error: PurgeError) -> c_int {
+    let status = c_int::from(error.exit_code());
+
+    set_last_error(format!("{:#}", anyhow::Error::from(error)));
+
+    status
+}
+
+/// Convert a C string argument. `name` is used in the error message.
+unsafe fn str_arg<'a>(s: *const c_char, name: &str) -> Result<&'a str, PurgeError> {
+    if s.is_null() {
+        return Err(PurgeError::InvalidArguments(format!("The {name} is NULL.")));
+    }
+
+    CStr::from_ptr(s)
+        .to_str()
+        .map_err(|_| PurgeError::InvalidArguments(format!("The {name} is not valid UTF-8.")))
+}
+
+/// Run a purge and convert its result to a status. A panic is not unwound across the C ABI. With `panic = "abort"`, the process aborts instead.
+fn run(f: impl FnOnce() -> Result<AppResult, PurgeError>) -> c_int {
+    match panic::catch_unwind(AssertUnwindSafe(f)) {
+        Ok(Ok(result)) => c_int::from(result.exit_code()),
+        Ok(Err(error)) => fail(error),
+        Err(_) => {
+            set_last_error(String::from("The purge panicked."));
+
+            PANIC_STATUS
+        },
+    }
+}
+
+/// Create a handle of a cache zone. `levels` is like `1:2`, or `auto` to detect the levels from the layout of the cache zone. Return `NULL` if it fails.
+///
+/// # Safety
+///
+/// `cache_path` and `levels` have to be NUL-terminated strings.
+#[no_mangle]
+pub unsafe extern "C" fn ncp_zone_new(
+    cache_path: *const c_char,
+    levels: *const c_char,
+) -> *mut CacheZone {
+    let zone = panic::catch_unwind(|| {
+        let cache_path = str_arg(cache_path, "cache path")?;
+        let levels = str_arg(levels, "levels")?;
+
+        if levels == "auto" {
+            CacheZone::detect_blocking(cache_path)?.ok_or_else(|| {
+                PurgeError::InvalidLevels(format!(
+                    "The levels of {cache_path:?} cannot be detected because there is no cache \
+                     file."
+                ))
+            })
+        } else {
+            CacheZone::new(cache_path, levels)
+        }
+    });
+
+    match zone {
+        Ok(Ok(zone)) => Box::into_raw(Box::new(zone)),
+        Ok(Err(error)) => {
+            fail(error);
+
+            ptr::null_mut()
+        },
+        Err(_) => {
+            set_last_error(String::from("Creating the cache zone panicked."));
+
+            ptr::null_mut()
+        },
+    }
+}
+
+/// Free a handle created by `ncp_zone_new`. `NULL` is ignored.
+///
+/// # Safety
+///
+/// `zone` has to be created by `ncp_zone_new` and not be freed yet.
+#[no_mangle]
+pub unsafe extern "C" fn ncp_zone_free(zone: *mut CacheZone) {
+    if !zone.is_null() {
+        drop(Box::from_raw(zone));
+    }
+}
+
+/// Purge the cache of an exact key. A protected key is not purged.
+///
+/// # Safety
+///
+/// `zone` has to be a valid handle and `key` has to be a NUL-terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn ncp_purge_key(zone: *const CacheZone, key: *const c_char) -> c_int {
+    run(|| {
+        let zone = zone_arg(zone)?;
+        let key = str_arg(key, "key")?;
+
+        zone.purge_key_blocking(key)
+    })
+}
+
+/// Purge the caches whose keys match a pattern, which is interpreted with `mode` (`NCP_MODE_WILDCARD`, `NCP_MODE_GLOB` or `NCP_MODE_REGEX`). `flags` is `0` or `NCP_FLAG_FORCE`. `threads` is how many threads remove the matched caches, and `0` or `1` removes them on the calling thread without starting any thread. If `progress` is not `NULL`, it is called with each removed cache and `user_data`.
+///
+/// This blocks the calling thread until the whole cache zone has been walked.
+///
+/// # Safety
+///
+/// `zone` has to be a valid handle and `pattern` has to be a NUL-terminated string. The strings passed to `progress` are only valid during the call.
+#[no_mangle]
+pub unsafe extern "C" fn ncp_purge_pattern(
+    zone: *const CacheZone,
+    pattern: *const c_char,
+    mode: c_int,
+    flags: c_uint,
+    threads: c_uint,
+    progress: NcpProgress,
+    user_data: *mut c_void,
+) -> c_int {
+    run(|| {
+        let zone = zone_arg(zone)?;
+        let pattern = str_arg(pattern, "pattern")?;
+
+        if flags & !NCP_FLAG_FORCE != 0 {
+            return Err(PurgeError::InvalidArguments(format!("The flags {flags} are incorrect.")));
+        }
+
+        // a purge in an nginx worker does not start any thread unless asked to
+        let zone = zone.clone().with_options(PurgeOptions {
+            force: zone.options().force || flags & NCP_FLAG_FORCE != 0,
+            threads: threads.max(1) as usize,
+            ..zone.options().clone()
+        });
+
+        let mode = match mode {
+            NCP_MODE_WILDCARD => MatchMode::Wildcard,
+            NCP_MODE_GLOB => MatchMode::Glob,
+            NCP_MODE_REGEX => MatchMode::Regex,
+            _ => {
+                return Err(PurgeError::InvalidArguments(format!(
+                    "The match mode {mode} is incorrect."
+                )))
+            },
+        };
+
+        let options = MatchOptions::from(mode);
+
+        match progress {
+            Some(progress) => zone.purge_pattern_with_progress(pattern, options, |entry| {
+                report_progress(progress, entry, user_data)
+            }),
+            None => zone.purge_pattern_blocking(pattern, options),
+        }
+    })
+}
+
+/// Get the message of the last error on this thread, or an empty string if there is no error. The string is valid until the next call on this thread.
+#[no_mangle]
+pub extern "C" fn ncp_last_error() -> *const c_char {
+    LAST_ERROR.with(|last_error| last_error.borrow().as_ptr())
+}
+
+unsafe fn zone_arg<'a>(zone: *const CacheZone) -> Result<&'a CacheZone, PurgeError> {
+    zone.as_ref().ok_or_else(|| PurgeError::InvalidArguments(String::from("The zone is NULL.")))
+}
+
+unsafe fn report_progress(
+    progress: unsafe extern "C" fn(*const c_char, *const c_char, *mut c_void) -> c_int,
+    entry: &CacheEntry,
+    user_data: *mut c_void,
+) -> bool {
+    let path = CString::new(entry.path.to_string_lossy().into_owned()).unwrap_or_default();
+    let key = CString::new(entry.key.as_slice()).unwrap_or_default();
+
+    progress(path.as_ptr(), key.as_ptr(), user_data) != 0
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        fs,
+        thread::{self, ThreadId},
+    };
+
+    use super::*;
+    use crate::test_zone::TestZone;
+
+    /// The keys reported to `collect`, with the threads which report them, and how many keys to collect before stopping.
+    struct Collected {
+        keys:  Vec<(String, ThreadId)>,
+        limit: usize,
+    }
+
+    unsafe extern "C" fn collect(
+        _path: *const c_char,
+        key: *const c_char,
+        user_data: *mut c_void,
+    ) -> c_int {
+        let collected = &mut *(user_data as *mut Collected);
+
+        collected
+            .keys
+            .push((CStr::from_ptr(key).to_string_lossy().into_owned(), thread::current().id()));
+
+        c_int::from(collected.keys.len() < collected.limit)
+    }
+
+    fn c_string(s: &str) -> CString {
+        CString::new(s).unwrap()
+    }
+
+    fn last_error() -> String {
+        unsafe { CStr::from_ptr(ncp_last_error()) }.to_string_lossy().into_owned()
+    }
+
+    #[test]
+    fn purge() {
+        let test_zone = TestZone::new("ffi", "1:2");
+
+        test_zone.add("http/blog/", "");
+        test_zone.add("http/blog/2", "");
+        test_zone.add("http/blog/3", "");
+        test_zone.add("http/static/a.jpg", "");
+
+        let cache_path = c_string(test_zone.path.to_str().unwrap());
+
+        unsafe {
+            assert!(ncp_zone_new(cache_path.as_ptr(), c_string("3").as_ptr()).is_null());
+            assert!(!last_error().is_empty());
+
+            let zone = ncp_zone_new(cache_path.as_ptr(), c_string("auto").as_ptr());
+
+            assert!(!zone.is_null());
+            assert_eq!(ncp_purge_key(zone, c_string("http/blog/").as_ptr()), 0);
+            assert_eq!(ncp_purge_key(zone, c_string("http/blog/").as_ptr()), 44);
+
+            // the caches are removed and reported on the calling thread, and the purge stops when the callback returns 0
+            let mut collected = Collected {
+                keys: Vec::new(), limit: 1
+            };
+
+            let status = ncp_purge_pattern(
+                zone,
+                c_string("http/blog/*").as_ptr(),
+                NCP_MODE_WILDCARD,
+                0,
+                1,
+                Some(collect),
+                &mut collected as *mut Collected as *mut c_void,
+            );
+
+            assert_eq!(status, 0);
+            assert_eq!(collected.keys.len(), 1);
+            assert!(collected.keys[0].0.starts_with("http/blog/"));
+            assert_eq!(collected.keys[0].1, thread::current().id());
+            assert_eq!(test_zone.files().len(), 2);
+
+            let mut collected = Collected {
+                keys: Vec::new(), limit: usize::MAX
+            };
+
+            let status = ncp_purge_pattern(
+                zone,
+                c_string("^http/").as_ptr(),
+                NCP_MODE_REGEX,
+                0,
+                4,
+                Some(collect),
+                &mut collected as *mut Collected as *mut c_void,
+            );
+
+            assert_eq!(status, 0);
+            assert_eq!(collected.keys.len(), 2);
+            assert!(test_zone.files().is_empty());
+
+            let pattern = c_string("*");
+
+            assert_eq!(
+                ncp_purge_pattern(zone, pattern.as_ptr(), 9, 0, 1, None, ptr::null_mut()),
+                2
+            );
+            assert_eq!(
+                ncp_purge_pattern(zone, pattern.as_ptr(), 0, 4, 1, None, ptr::null_mut()),
+                2
+            );
+            assert_eq!(
+                ncp_purge_pattern(zone, c_string("(").as_ptr(), 2, 0, 1, None, ptr::null_mut()),
+                65
+            );
+            assert_eq!(ncp_purge_key(ptr::null(), pattern.as_ptr()), 2);
+            assert_eq!(last_error(), "The zone is NULL.");
+
+            ncp_zone_free(zone);
+            ncp_zone_free(ptr::null_mut());
+        }
+    }
+
+    #[test]
+    fn purge_all_with_force() {
+        let test_zone = TestZone::new("ffi-force", "1:2");
+
+        test_zone.add("http/blog/", "");
+        fs::write(test_zone.path.join("index.html"), "").unwrap();
+
+        let pattern = c_string("*");
+
+        unsafe {
+            let zone = ncp_zone_new(
+                c_string(test_zone.path.to_str().unwrap()).as_ptr(),
+                c_string("1:2").as_ptr(),
+            );
+
+            assert_eq!(
+                ncp_purge_pattern(zone, pattern.as_ptr(), 0, 0, 1, None, ptr::null_mut()),
+                78
+            );
+            assert_eq!(test_zone.files().len(), 2);
+            assert_eq!(
+                ncp_purge_pattern(
+                    zone,
+                    pattern.as_ptr(),
+                    0,
+                    NCP_FLAG_FORCE,
+                    1,
+                    None,
+                    ptr::null_mut()
+                ),
+                0
+            );
+            assert!(test_zone.files().is_empty());
+
+            ncp_zone_free(zone);
+        }
+    }
+}
@@ -1,81 +1,207 @@
+#[cfg(feature = "service")]
+use std::collections::HashMap;
 use std::{
-    io,
+    collections::HashSet,
+    fs, io,
     path::{Path, PathBuf},
-    sync::Arc,
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        mpsc, Arc, Mutex as StdMutex, PoisonError,
+    },
+    thread,
 };
 
-use anyhow::{anyhow, Context};
-use async_recursion::async_recursion;
 use md5::{Digest, Md5};
-use scanner_rust::{generic_array::typenum::U384, ScannerAscii};
-use tokio::sync::Mutex;
+#[cfg(feature = "service")]
+use tokio::sync::{Mutex, OwnedMutexGuard};
+#[cfg(feature = "async")]
+use tokio::task::JoinHandle;
+
+use crate::{
+    cache_file::{read_cache_file_header, CacheFileHeader},
+    error::PurgeError,
+    matcher::{KeyMatcher, MatchMode, MatchOptions},
+    safety::check_directory_to_wipe,
+    trash::Trash,
+    walk::{walk, EntryIter, WalkOptions, DEFAULT_PREFETCH},
+    zone::CacheEntry,
+    AppResult,
+};
+#[cfg(feature = "service")]
+use crate::{
+    index::CacheIndex,
+    walk::{EntryStream, SkipPaths},
+};
 
-use crate::AppResult;
+/// How a purge removes caches, besides which caches it matches.
+#[derive(Debug, Clone, Default)]
+pub struct PurgeOptions {
+    /// A wildcard or tag purge stops after removing this number of caches.
+    pub max_entries: Option<usize>,
+    /// Allow purging all caches in a directory which is a mount root or does not look like a cache zone of the levels. System directories are refused anyway.
+    pub force:       bool,
+    /// Key patterns in the wildcard mode whose caches are never purged, as if they were always excluded.
+    pub protect:     Vec<String>,
+    /// Move the removed caches into this trash instead of removing them.
+    pub trash:       Option<Arc<Trash>>,
+    /// Print the files which would be removed to stdout instead of removing them.
+    pub dry_run:     bool,
+    /// How many threads remove the matched caches at the same time. `1` walks the cache zone and removes the caches on the calling thread without starting any thread. `0` uses the default, which is 16.
+    pub threads:     usize,
+}
+
+impl PurgeOptions {
+    #[inline]
+    fn remove_threads(&self) -> usize {
+        if self.threads == 0 {
+            REMOVE_THREADS
+        } else {
+            self.threads
+        }
+    }
+}
+
+impl From<Option<usize>> for PurgeOptions {
+    #[inline]
+    fn from(max_entries: Option<usize>) -> Self {
+        PurgeOptions {
+            max_entries,
+            ..PurgeOptions::default()
+        }
+    }
+}
 
 #[inline]
-async fn remove_file<P: AsRef<Path>>(path: P) -> io::Result<()> {
-    let path = path.as_ref();
+pub(crate) fn remove_file_blocking<P: AsRef<Path>>(path: P) -> io::Result<()> {
+    fs::remove_file(path)
+}
 
-    if cfg!(debug_assertions) {
-        println!("Remove file: {path:?}");
+#[cfg(feature = "async")]
+#[inline]
+pub(crate) async fn remove_file<P: AsRef<Path>>(path: P) -> io::Result<()> {
+    let path = path.as_ref().to_path_buf();
 
-        Ok(())
-    } else {
-        tokio::fs::remove_file(path).await
+    unblock(move || remove_file_blocking(path)).await
+}
+
+/// Remove a cache file, or move it into the trash if `purge_options` has one.
+fn purge_file_blocking(path: &Path, purge_options: &PurgeOptions) -> io::Result<()> {
+    match purge_options.trash.as_ref() {
+        Some(_) if purge_options.dry_run => {
+            println!("Trash file: {path:?}");
+
+            Ok(())
+        },
+        Some(trash) => trash.put(path, false),
+        None if purge_options.dry_run => {
+            println!("Remove file: {path:?}");
+
+            Ok(())
+        },
+        None => remove_file_blocking(path),
     }
 }
 
-#[inline]
-async fn remove_dir_all<P: AsRef<Path>>(path: P) -> io::Result<()> {
-    let path = path.as_ref();
+/// Move a file with a rename. If the rename fails, such as across file systems, copy the file to a temporary file beside the destination and rename it, so that the destination is never partially written.
+pub(crate) fn move_file_blocking<F: AsRef<Path>, T: AsRef<Path>>(from: F, to: T) -> io::Result<()> {
+    let from = from.as_ref();
+    let to = to.as_ref();
 
-    if cfg!(debug_assertions) {
-        println!("Remove dir all: {path:?}");
+    if let Some(parent) = to.parent() {
+        fs::create_dir_all(parent)?;
+    }
 
-        Ok(())
-    } else {
-        tokio::fs::remove_dir_all(path).await
+    match fs::rename(from, to) {
+        Ok(_) => return Ok(()),
+        Err(error) if error.kind() == io::ErrorKind::NotFound => return Err(error),
+        Err(_) => (),
     }
+
+    let mut temp = to.as_os_str().to_os_string();
+    temp.push(format!(".{}", std::process::id()));
+
+    if let Err(error) = fs::copy(from, &temp) {
+        let _ = fs::remove_file(&temp);
+
+        return Err(error);
+    }
+
+    fs::rename(&temp, to)?;
+
+    fs::remove_file(from)
 }
 
+#[cfg(feature = "async")]
 #[inline]
-async fn remove_dir<P: AsRef<Path>>(path: P) -> io::Result<()> {
-    let path = path.as_ref();
+pub(crate) async fn move_file<F: AsRef<Path>, T: AsRef<Path>>(from: F, to: T) -> io::Result<()> {
+    let from = from.as_ref().to_path_buf();
+    let to = to.as_ref().to_path_buf();
 
-    if cfg!(debug_assertions) {
-        println!("Remove dir: {path:?}");
-    } else {
-        match tokio::fs::remove_dir(path).await {
-            Ok(_) => (),
-            Err(error) => {
-                // check if the error is caused by directory is not empty
-                // TODO we should just use `io::ErrorKind::DirectoryNotEmpty` in the future
-                return if error.kind().to_string() == "directory not empty" {
-                    Err(io::Error::new(io::ErrorKind::Other, error))
-                } else {
-                    Err(error)
-                };
-            },
-        }
+    unblock(move || move_file_blocking(from, to)).await
+}
+
+#[inline]
+pub(crate) fn remove_dir_all_blocking<P: AsRef<Path>>(path: P) -> io::Result<()> {
+    fs::remove_dir_all(path)
+}
+
+#[cfg(feature = "async")]
+#[inline]
+pub(crate) async fn remove_dir_all<P: AsRef<Path>>(path: P) -> io::Result<()> {
+    let path = path.as_ref().to_path_buf();
+
+    unblock(move || remove_dir_all_blocking(path)).await
+}
+
+/// Remove a directory in a cache zone recursively, or move it into the trash if `purge_options` has one.
+#[inline]
+fn purge_dir_all_blocking(path: &Path, purge_options: &PurgeOptions) -> io::Result<()> {
+    match purge_options.trash.as_ref() {
+        Some(_) if purge_options.dry_run => {
+            println!("Trash dir all: {path:?}");
+
+            Ok(())
+        },
+        Some(trash) => trash.put(path, true),
+        None if purge_options.dry_run => {
+            println!("Remove dir all: {path:?}");
+
+            Ok(())
+        },
+        None => remove_dir_all_blocking(path),
     }
+}
 
-    Ok(())
+#[inline]
+fn remove_dir_blocking<P: AsRef<Path>>(path: P) -> io::Result<()> {
+    match fs::remove_dir(path) {
+        Ok(_) => Ok(()),
+        Err(error) => {
+            // check if the error is caused by directory is not empty
+            // TODO we should just use `io::ErrorKind::DirectoryNotEmpty` in the future
+            if error.kind().to_string() == "directory not empty" {
+                Err(io::Error::new(io::ErrorKind::Other, error))
+            } else {
+                Err(error)
+            }
+        },
+    }
 }
 
-async fn remove_empty_ancestors<P: AsRef<Path>>(
+pub(crate) fn remove_empty_ancestors_blocking<P: AsRef<Path>>(
     path: P,
     relative_degree: usize,
-) -> anyhow::Result<()> {
+) -> Result<(), PurgeError> {
     if let Some(mut path) = path.as_ref().parent() {
         for _ in 1..=relative_degree {
-            match remove_dir(path).await {
+            match remove_dir_blocking(path) {
                 Ok(_) => (),
                 Err(error)
                     if matches!(error.kind(), io::ErrorKind::NotFound | io::ErrorKind::Other) =>
                 {
                     return Ok(());
                 },
-                Err(error) => return Err(error).with_context(|| anyhow!("{path:?}")),
+                Err(error) => return Err(PurgeError::io(path, error)),
             }
 
             match path.parent() {
@@ -90,347 +216,1707 @@ async fn remove_empty_ancestors<P: AsRef<Path>>(
     Ok(())
 }
 
-/// Do something like `rm -rf /path/to/*`. The `/path/to` directory will not be deleted. This function may be dangerous.
-pub async fn remove_all_files_in_directory<P: AsRef<Path>>(path: P) -> anyhow::Result<bool> {
+#[cfg(feature = "async")]
+#[inline]
+pub(crate) async fn remove_empty_ancestors<P: AsRef<Path>>(
+    path: P,
+    relative_degree: usize,
+) -> Result<(), PurgeError> {
+    let path = path.as_ref().to_path_buf();
+
+    unblock_purge(move || remove_empty_ancestors_blocking(path, relative_degree)).await
+}
+
+/// Do something like `rm -rf /path/to/*`. The `/path/to` directory will not be deleted. This function may be dangerous, so the directory is checked against `levels` first, unless `purge_options.force` is set.
+pub fn remove_all_files_in_directory_blocking<P: AsRef<Path>>(
+    path: P,
+    levels: &[usize],
+    purge_options: &PurgeOptions,
+) -> Result<bool, PurgeError> {
     let mut result = false;
 
     let path = path.as_ref();
 
-    for dir_entry in path.read_dir().with_context(|| anyhow!("{path:?}"))? {
-        let dir_entry = dir_entry.with_context(|| anyhow!("{path:?}"))?;
+    check_directory_to_wipe(path, levels, purge_options.force)?;
+
+    for dir_entry in path.read_dir().map_err(|error| PurgeError::io(path, error))? {
+        let dir_entry = dir_entry.map_err(|error| PurgeError::io(path, error))?;
 
         let file_type = match dir_entry.file_type() {
             Ok(file_type) => file_type,
             Err(error) if error.kind() == io::ErrorKind::NotFound => continue,
-            Err(error) => return Err(error).with_context(|| anyhow!("{dir_entry:?}")),
+            Err(error) => return Err(PurgeError::io(&dir_entry.path(), error)),
         };
 
         let path = dir_entry.path();
 
         if file_type.is_dir() {
-            match remove_dir_all(&path).await {
+            match purge_dir_all_blocking(&path, purge_options) {
                 Ok(_) => result = true,
                 Err(error) if error.kind() == io::ErrorKind::NotFound => {
                     result = true;
 
                     continue;
                 },
-                Err(error) => return Err(error).with_context(|| anyhow!("{path:?}")),
+                Err(error) => return Err(PurgeError::io(&path, error)),
             }
         } else {
-            match remove_file(&path).await {
+            match purge_file_blocking(&path, purge_options) {
                 Ok(_) => result = true,
                 Err(error) if error.kind() == io::ErrorKind::NotFound => {
                     result = true;
 
-                    continue;
-                },
-                Err(error) => return Err(error).with_context(|| anyhow!("{path:?}")),
-            }
+                    continue;
+                },
+                Err(error) => return Err(PurgeError::io(&path, error)),
+            }
+        }
+    }
+
+    Ok(result)
+}
+
+/// The same as `remove_all_files_in_directory_blocking`, but on a blocking thread of Tokio.
+#[cfg(feature = "async")]
+#[inline]
+pub async fn remove_all_files_in_directory<P: AsRef<Path>>(
+    path: P,
+    levels: &[usize],
+    purge_options: &PurgeOptions,
+) -> Result<bool, PurgeError> {
+    let path = path.as_ref().to_path_buf();
+    let levels = levels.to_vec();
+    let purge_options = purge_options.clone();
+
+    unblock_purge(move || remove_all_files_in_directory_blocking(path, &levels, &purge_options))
+        .await
+}
+
+/// Purge a cache with a specific key unless the exclusions parsed by `parse_exclude_keys` exclude it.
+pub(crate) fn remove_one_cache_blocking(
+    cache_path: &Path,
+    levels: &[usize],
+    key: &str,
+    exclusions: &Option<Exclusions>,
+    purge_options: &PurgeOptions,
+) -> Result<AppResult, PurgeError> {
+    match cache_file_to_remove(cache_path, levels, key, exclusions) {
+        Some(file_path) => remove_cache_file_blocking(file_path, levels.len(), purge_options),
+        None => Ok(AppResult::CacheIgnored),
+    }
+}
+
+#[cfg(feature = "async")]
+#[inline]
+pub(crate) async fn remove_one_cache(
+    cache_path: &Path,
+    levels: &[usize],
+    key: &str,
+    exclusions: &Option<Exclusions>,
+    purge_options: &PurgeOptions,
+) -> Result<AppResult, PurgeError> {
+    match cache_file_to_remove(cache_path, levels, key, exclusions) {
+        Some(file_path) => remove_cache_file(file_path, levels.len(), purge_options).await,
+        None => Ok(AppResult::CacheIgnored),
+    }
+}
+
+/// Get the path of the cache file of a key. Return `None` if the key is excluded.
+fn cache_file_to_remove(
+    cache_path: &Path,
+    levels: &[usize],
+    key: &str,
+    exclusions: &Option<Exclusions>,
+) -> Option<PathBuf> {
+    let (exclude_matchers, exclude_paths) = exclusions.as_ref()?;
+
+    let file_path = create_cache_file_path(cache_path, levels, key);
+
+    if exclude_paths.contains(&file_path)
+        || exclude_matchers.iter().any(|matcher| matcher.is_match(key))
+    {
+        None
+    } else {
+        Some(file_path)
+    }
+}
+
+/// Remove a cache file whose path has been known and its empty ancestors.
+fn remove_cache_file_blocking(
+    file_path: PathBuf,
+    number_of_levels: usize,
+    purge_options: &PurgeOptions,
+) -> Result<AppResult, PurgeError> {
+    match purge_file_blocking(&file_path, purge_options) {
+        Ok(_) => {
+            if !purge_options.dry_run {
+                remove_empty_ancestors_blocking(file_path, number_of_levels)?;
+            }
+
+            Ok(AppResult::Ok)
+        },
+        Err(error) if error.kind() == io::ErrorKind::NotFound => {
+            Ok(AppResult::AlreadyPurged(file_path))
+        },
+        Err(error) => Err(PurgeError::io(&file_path, error)),
+    }
+}
+
+#[cfg(feature = "async")]
+#[inline]
+async fn remove_cache_file(
+    file_path: PathBuf,
+    number_of_levels: usize,
+    purge_options: &PurgeOptions,
+) -> Result<AppResult, PurgeError> {
+    let purge_options = purge_options.clone();
+
+    unblock_purge(move || remove_cache_file_blocking(file_path, number_of_levels, &purge_options))
+        .await
+}
+
+/// Purge the caches with multiple keys. Exact keys are purged directly and all of the patterns are matched in a single directory walk. The exclude keys are interpreted with the same `options`.
+pub fn remove_caches_blocking<P: AsRef<Path>, L: AsRef<str>, K: AsRef<str>, EK: AsRef<str>>(
+    cache_path: P,
+    levels: L,
+    keys: Vec<K>,
+    exclude_keys: Vec<EK>,
+    options: MatchOptions,
+    purge_options: &PurgeOptions,
+) -> Result<AppResult, PurgeError> {
+    let cache_path = cache_path.as_ref();
+
+    // detect the levels only once for all of the keys
+    let levels = match resolve_levels_blocking(cache_path, levels)? {
+        Some(levels) => levels,
+        None => return Ok(AppResult::AlreadyPurgedWildcard),
+    };
+
+    let (literals, patterns) = split_keys(keys, options)?;
+
+    let mut result = KeysResult::default();
+
+    if !literals.is_empty() {
+        // parse the exclude keys only once for all of the exact keys
+        let exclusions = parse_exclude_keys(
+            cache_path,
+            &levels,
+            exclude_keys.iter().map(|k| k.as_ref()).collect::<Vec<&str>>(),
+            options,
+            &purge_options.protect,
+        )?;
+
+        for key in literals {
+            result.push(remove_one_cache_blocking(
+                cache_path,
+                &levels,
+                &key,
+                &exclusions,
+                purge_options,
+            ));
+        }
+    }
+
+    if !patterns.is_empty() {
+        result.push(remove_caches_via_patterns_blocking(
+            cache_path,
+            format_levels(&levels),
+            patterns,
+            exclude_keys,
+            options,
+            purge_options,
+        ));
+    }
+
+    result.finish()
+}
+
+/// The same as `remove_caches_blocking`, but on a blocking thread of Tokio.
+#[cfg(feature = "async")]
+#[inline]
+pub async fn remove_caches<P: AsRef<Path>, L: AsRef<str>, K: AsRef<str>, EK: AsRef<str>>(
+    cache_path: P,
+    levels: L,
+    keys: Vec<K>,
+    exclude_keys: Vec<EK>,
+    options: MatchOptions,
+    purge_options: &PurgeOptions,
+) -> Result<AppResult, PurgeError> {
+    let cache_path = cache_path.as_ref().to_path_buf();
+    let levels = levels.as_ref().to_string();
+    let keys = to_strings(keys);
+    let exclude_keys = to_strings(exclude_keys);
+    let purge_options = purge_options.clone();
+
+    unblock_purge(move || {
+        remove_caches_blocking(cache_path, levels, keys, exclude_keys, options, &purge_options)
+    })
+    .await
+}
+
+/// The combined result of purging multiple keys. The purge goes on after an error, and the first error is kept.
+#[derive(Debug, Default)]
+struct KeysResult {
+    result: Option<AppResult>,
+    error:  Option<PurgeError>,
+}
+
+impl KeysResult {
+    #[inline]
+    fn push(&mut self, result: Result<AppResult, PurgeError>) {
+        match result {
+            Ok(r) => {
+                self.result = Some(match self.result.take() {
+                    Some(result) => result.merge(r),
+                    None => r,
+                });
+            },
+            Err(error) => {
+                self.error.get_or_insert(error);
+            },
+        }
+    }
+
+    /// If any cache has been purged before the error, the purge partially fails.
+    #[inline]
+    fn finish(self) -> Result<AppResult, PurgeError> {
+        match (self.result, self.error) {
+            (Some(AppResult::Ok | AppResult::TooManyEntries(_)), Some(error)) => {
+                Err(PurgeError::Partial(Box::new(error)))
+            },
+            (_, Some(error)) => Err(error),
+            (Some(result), None) => Ok(result),
+            (None, None) => {
+                Err(PurgeError::InvalidKey(String::from("At least one key is needed.")))
+            },
+        }
+    }
+}
+
+/// Purge multiple caches whose keys match any of the patterns in `keys` in a single directory walk. The exclude keys are interpreted with the same `options`. The purge stops after `purge_options.max_entries` caches have been removed.
+pub fn remove_caches_via_patterns_blocking<
+    P: AsRef<Path>,
+    L: AsRef<str>,
+    K: AsRef<str>,
+    EK: AsRef<str>,
+>(
+    cache_path: P,
+    levels: L,
+    keys: Vec<K>,
+    exclude_keys: Vec<EK>,
+    options: MatchOptions,
+    purge_options: &PurgeOptions,
+) -> Result<AppResult, PurgeError> {
+    let target =
+        PurgeTarget::Key(KeyMatcher::new_many(&keys, options).map_err(PurgeError::invalid_key)?);
+
+    remove_caches_via_walk_blocking(
+        cache_path,
+        levels,
+        target,
+        exclude_keys,
+        options,
+        purge_options,
+        None,
+    )
+}
+
+/// Like `remove_caches_via_patterns_blocking`, but `progress` is called after each cache is removed. The purge stops if it returns `false`, and the caches which are being removed at that time are still reported.
+pub fn remove_caches_via_patterns_with_progress<
+    P: AsRef<Path>,
+    L: AsRef<str>,
+    K: AsRef<str>,
+    EK: AsRef<str>,
+>(
+    cache_path: P,
+    levels: L,
+    keys: Vec<K>,
+    exclude_keys: Vec<EK>,
+    options: MatchOptions,
+    purge_options: &PurgeOptions,
+    mut progress: impl FnMut(&CacheEntry) -> bool,
+) -> Result<AppResult, PurgeError> {
+    let target =
+        PurgeTarget::Key(KeyMatcher::new_many(&keys, options).map_err(PurgeError::invalid_key)?);
+
+    remove_caches_via_walk_blocking(
+        cache_path,
+        levels,
+        target,
+        exclude_keys,
+        options,
+        purge_options,
+        Some(&mut progress),
+    )
+}
+
+/// The same as `remove_caches_via_patterns_blocking`, but on a blocking thread of Tokio.
+#[cfg(feature = "async")]
+#[inline]
+pub async fn remove_caches_via_patterns<
+    P: AsRef<Path>,
+    L: AsRef<str>,
+    K: AsRef<str>,
+    EK: AsRef<str>,
+>(
+    cache_path: P,
+    levels: L,
+    keys: Vec<K>,
+    exclude_keys: Vec<EK>,
+    options: MatchOptions,
+    purge_options: &PurgeOptions,
+) -> Result<AppResult, PurgeError> {
+    let cache_path = cache_path.as_ref().to_path_buf();
+    let levels = levels.as_ref().to_string();
+    let keys = to_strings(keys);
+    let exclude_keys = to_strings(exclude_keys);
+    let purge_options = purge_options.clone();
+
+    unblock_purge(move || {
+        remove_caches_via_patterns_blocking(
+            cache_path,
+            levels,
+            keys,
+            exclude_keys,
+            options,
+            &purge_options,
+        )
+    })
+    .await
+}
+
+/// Purge multiple caches whose stored response header `tag_header` contains any of the `tags`.
+pub fn remove_caches_via_tags_blocking<
+    P: AsRef<Path>,
+    L: AsRef<str>,
+    H: Into<String>,
+    T: AsRef<str>,
+    EK: AsRef<str>,
+>(
+    cache_path: P,
+    levels: L,
+    tag_header: H,
+    tags: Vec<T>,
+    exclude_keys: Vec<EK>,
+    purge_options: &PurgeOptions,
+) -> Result<AppResult, PurgeError> {
+    let target = parse_tags_target(tag_header, tags)?;
+
+    remove_caches_via_walk_blocking(
+        cache_path,
+        levels,
+        target,
+        exclude_keys,
+        MatchMode::Wildcard.into(),
+        purge_options,
+        None,
+    )
+}
+
+/// The same as `remove_caches_via_tags_blocking`, but on a blocking thread of Tokio.
+#[cfg(feature = "async")]
+#[inline]
+pub async fn remove_caches_via_tags<
+    P: AsRef<Path>,
+    L: AsRef<str>,
+    H: Into<String>,
+    T: AsRef<str>,
+    EK: AsRef<str>,
+>(
+    cache_path: P,
+    levels: L,
+    tag_header: H,
+    tags: Vec<T>,
+    exclude_keys: Vec<EK>,
+    purge_options: &PurgeOptions,
+) -> Result<AppResult, PurgeError> {
+    let cache_path = cache_path.as_ref().to_path_buf();
+    let levels = levels.as_ref().to_string();
+    let tag_header = tag_header.into();
+    let tags = to_strings(tags);
+    let exclude_keys = to_strings(exclude_keys);
+    let purge_options = purge_options.clone();
+
+    unblock_purge(move || {
+        remove_caches_via_tags_blocking(
+            cache_path,
+            levels,
+            tag_header,
+            tags,
+            exclude_keys,
+            &purge_options,
+        )
+    })
+    .await
+}
+
+/// Purge the caches with multiple keys. Exact keys are purged directly and the candidates of the patterns are looked up in the index and verified before being purged.
+#[cfg(feature = "service")]
+pub async fn remove_caches_with_index<K: AsRef<str>, EK: AsRef<str>>(
+    index: Arc<Mutex<CacheIndex>>,
+    keys: Vec<K>,
+    exclude_keys: Vec<EK>,
+    options: MatchOptions,
+    purge_options: &PurgeOptions,
+) -> Result<AppResult, PurgeError> {
+    let (literals, patterns) = split_keys(keys, options)?;
+
+    let mut index = index.lock_owned().await;
+
+    let mut result = KeysResult::default();
+
+    if !literals.is_empty() {
+        let exclusions = parse_exclude_keys(
+            index.cache_path(),
+            index.levels(),
+            exclude_keys.iter().map(|k| k.as_ref()).collect::<Vec<&str>>(),
+            options,
+            &purge_options.protect,
+        )?;
+
+        for key in literals {
+            let removed = remove_one_cache(
+                index.cache_path(),
+                index.levels(),
+                &key,
+                &exclusions,
+                purge_options,
+            )
+            .await;
+
+            // a dry run removes nothing, so the index is left as it is
+            if !purge_options.dry_run
+                && matches!(removed, Ok(AppResult::Ok | AppResult::AlreadyPurged(_)))
+            {
+                let file_path = create_cache_file_path(index.cache_path(), index.levels(), &key);
+
+                index.remove_entry(file_path);
+            }
+
+            result.push(removed);
+        }
+    }
+
+    if patterns.is_empty() {
+        unblock_index(index, save_index).await?;
+    } else {
+        let target = PurgeTarget::Key(
+            KeyMatcher::new_many(&patterns, options).map_err(PurgeError::invalid_key)?,
+        );
+
+        result.push(
+            remove_caches_via_index(index, target, exclude_keys, options, purge_options).await,
+        );
+    }
+
+    result.finish()
+}
+
+/// Purge multiple caches whose stored response header `tag_header` contains any of the `tags`. The candidates are looked up in the index and verified before being purged.
+#[cfg(feature = "service")]
+pub async fn remove_caches_via_tags_with_index<H: Into<String>, T: AsRef<str>, EK: AsRef<str>>(
+    index: Arc<Mutex<CacheIndex>>,
+    tag_header: H,
+    tags: Vec<T>,
+    exclude_keys: Vec<EK>,
+    purge_options: &PurgeOptions,
+) -> Result<AppResult, PurgeError> {
+    let target = parse_tags_target(tag_header, tags)?;
+
+    let index = index.lock_owned().await;
+
+    if !index.tag_header().eq_ignore_ascii_case(target.tag_header().unwrap_or_default()) {
+        // the index does not record this header, but the removed caches still have to be forgotten
+        let exclude_keys = to_strings(exclude_keys);
+        let purge_options = purge_options.clone();
+
+        return unblock_index(index, move |index| {
+            let mut removed = Vec::new();
+
+            let result = remove_caches_via_walk_blocking(
+                index.cache_path(),
+                format_levels(index.levels()),
+                target,
+                exclude_keys,
+                MatchMode::Wildcard.into(),
+                &purge_options,
+                Some(&mut |entry: &CacheEntry| {
+                    removed.push(entry.path.clone());
+
+                    true
+                }),
+            );
+
+            if !purge_options.dry_run {
+                for file_path in removed {
+                    index.remove_entry(file_path);
+                }
+
+                save_index(index)?;
+            }
+
+            result
+        })
+        .await
+        .map(|(_, result)| result);
+    }
+
+    remove_caches_via_index(index, target, exclude_keys, MatchMode::Wildcard.into(), purge_options)
+        .await
+}
+
+/// One item of a batch purge.
+#[cfg(feature = "service")]
+#[derive(Debug)]
+pub struct BatchItem {
+    pub keys:         Vec<String>,
+    pub exclude_keys: Vec<String>,
+    pub options:      MatchOptions,
+}
+
+/// The compiled patterns and exclusions of an item of a batch purge.
+#[cfg(feature = "service")]
+#[derive(Debug)]
+struct BatchPatterns {
+    matcher:          KeyMatcher,
+    exclude_matchers: Vec<KeyMatcher>,
+    exclude_paths:    HashSet<PathBuf>,
+}
+
+/// What the patterns of each item of a batch purge have done.
+#[cfg(feature = "service")]
+#[derive(Debug)]
+struct BatchHits {
+    /// whether any cache of the item has been removed
+    removed: Vec<AtomicBool>,
+    /// whether any cache of the item has been left because the budget had run out
+    denied:  Vec<AtomicBool>,
+}
+
+#[cfg(feature = "service")]
+impl BatchHits {
+    #[inline]
+    fn new(len: usize) -> Self {
+        BatchHits {
+            removed: (0..len).map(|_| AtomicBool::new(false)).collect(),
+            denied:  (0..len).map(|_| AtomicBool::new(false)).collect(),
+        }
+    }
+
+    #[inline]
+    fn all_denied(&self) -> bool {
+        self.denied.iter().all(|denied| denied.load(Ordering::Relaxed))
+    }
+
+    /// The result of an item, which only reports the limit if the item itself has been stopped by it.
+    #[inline]
+    fn result(&self, i: usize, budget: &EntryBudget) -> AppResult {
+        if self.denied[i].load(Ordering::Relaxed) {
+            AppResult::TooManyEntries(budget.max())
+        } else if self.removed[i].load(Ordering::Relaxed) {
+            AppResult::Ok
+        } else {
+            AppResult::AlreadyPurgedWildcard
+        }
+    }
+}
+
+/// An item of a batch purge whose exact keys and patterns have been separated.
+#[cfg(feature = "service")]
+#[derive(Debug, Default)]
+struct PreparedBatchItem {
+    /// the result which has been decided before purging, such as for excluded exact keys
+    result:   Option<AppResult>,
+    literals: HashSet<String>,
+    patterns: Option<BatchPatterns>,
+}
+
+/// Purge the caches of multiple items in one cache zone and return the result of each item. Every distinct exact key is purged only once, and the patterns of all items are matched in a single directory walk, or looked up in `index` if it is given. The walk stops removing caches after `purge_options.max_entries` caches have been removed by the patterns.
+#[cfg(feature = "service")]
+pub async fn remove_caches_via_batch<P: AsRef<Path>, L: AsRef<str>>(
+    cache_path: P,
+    levels: L,
+    index: Option<Arc<Mutex<CacheIndex>>>,
+    items: Vec<BatchItem>,
+    purge_options: &PurgeOptions,
+) -> Result<Vec<Result<AppResult, PurgeError>>, PurgeError> {
+    let mut index = match index {
+        Some(index) => Some(index.lock_owned().await),
+        None => None,
+    };
+
+    let levels = match index.as_ref() {
+        Some(index) => index.levels().to_vec(),
+        None => match resolve_levels(cache_path.as_ref(), levels).await? {
+            Some(levels) => levels,
+            None => {
+                return Ok(items.iter().map(|_| Ok(AppResult::AlreadyPurgedWildcard)).collect())
+            },
+        },
+    };
+    let number_of_levels = levels.len();
+
+    let cache_path = match index.as_ref() {
+        Some(index) => index.cache_path().to_path_buf(),
+        None => match canonicalize_cache_path(cache_path)? {
+            Some(cache_path) => cache_path,
+            None => {
+                return Ok(items.iter().map(|_| Ok(AppResult::AlreadyPurgedWildcard)).collect())
+            },
+        },
+    };
+
+    let mut results: Vec<Option<Result<AppResult, PurgeError>>> =
+        items.iter().map(|_| None).collect();
+
+    // exact key -> the items which purge it
+    let mut literals: HashMap<String, Vec<usize>> = HashMap::new();
+    let mut pattern_items: Vec<usize> = Vec::new();
+    let mut patterns: Vec<BatchPatterns> = Vec::new();
+
+    for (i, item) in items.into_iter().enumerate() {
+        match prepare_batch_item(cache_path.as_path(), &levels, item, &purge_options.protect) {
+            Ok(prepared) => {
+                if let Some(result) = prepared.result {
+                    merge_batch_result(&mut results[i], Ok(result));
+                }
+
+                for key in prepared.literals {
+                    literals.entry(key).or_default().push(i);
+                }
+
+                if let Some(item_patterns) = prepared.patterns {
+                    pattern_items.push(i);
+                    patterns.push(item_patterns);
+                }
+            },
+            Err(error) => results[i] = Some(Err(error)),
+        }
+    }
+
+    for (key, item_indices) in literals {
+        let file_path = create_cache_file_path(cache_path.as_path(), &levels, key);
+
+        let result = remove_cache_file(file_path.clone(), number_of_levels, purge_options).await;
+
+        if let Some(index) = index.as_mut().filter(|_| !purge_options.dry_run) {
+            if matches!(result, Ok(AppResult::Ok | AppResult::AlreadyPurged(_))) {
+                index.remove_entry(file_path);
+            }
+        }
+
+        for i in item_indices {
+            merge_batch_result(&mut results[i], clone_batch_result(&result));
+        }
+    }
+
+    if !patterns.is_empty() {
+        let patterns = Arc::new(patterns);
+        let hits = Arc::new(BatchHits::new(patterns.len()));
+        let budget = Arc::new(EntryBudget::new(purge_options.max_entries));
+        let purge_options = Arc::new(purge_options.clone());
+
+        let walked = match index.take() {
+            Some(index) => {
+                remove_caches_via_batch_index(
+                    index,
+                    patterns.clone(),
+                    hits.clone(),
+                    budget.clone(),
+                    purge_options,
+                )
+                .await
+            },
+            None => {
+                walk_batch(
+                    cache_path,
+                    levels,
+                    patterns.clone(),
+                    hits.clone(),
+                    budget.clone(),
+                    purge_options,
+                )
+                .await
+            },
+        };
+
+        for (k, i) in pattern_items.into_iter().enumerate() {
+            let result = match walked.as_ref() {
+                Ok(()) => Ok(hits.result(k, &budget)),
+                Err(error) => Err(error.clone()),
+            };
+
+            merge_batch_result(&mut results[i], result);
+        }
+    }
+
+    if let Some(index) = index {
+        unblock_index(index, save_index).await?;
+    }
+
+    Ok(results
+        .into_iter()
+        .map(|result| result.unwrap_or(Ok(AppResult::AlreadyPurgedWildcard)))
+        .collect())
+}
+
+#[cfg(feature = "service")]
+fn prepare_batch_item(
+    cache_path: &Path,
+    levels: &[usize],
+    item: BatchItem,
+    protect: &[String],
+) -> Result<PreparedBatchItem, PurgeError> {
+    let (literals, patterns) = split_keys(item.keys, item.options)?;
+
+    if literals.is_empty() && patterns.is_empty() {
+        return Err(PurgeError::InvalidKey(String::from("At least one key is needed.")));
+    }
+
+    let (exclude_matchers, exclude_paths) =
+        match parse_exclude_keys(cache_path, levels, item.exclude_keys, item.options, protect)? {
+            Some(exclusions) => exclusions,
+            None => {
+                // every cache is excluded
+                return Ok(PreparedBatchItem {
+                    result: Some(if literals.is_empty() {
+                        AppResult::AlreadyPurgedWildcard
+                    } else {
+                        AppResult::CacheIgnored
+                    }),
+                    ..PreparedBatchItem::default()
+                });
+            },
+        };
+
+    let mut prepared = PreparedBatchItem::default();
+
+    for key in literals {
+        let excluded = exclude_paths.contains(&create_cache_file_path(cache_path, levels, &key))
+            || exclude_matchers.iter().any(|matcher| matcher.is_match(key.as_bytes()));
+
+        if excluded {
+            prepared.result = Some(match prepared.result {
+                Some(result) => result.merge(AppResult::CacheIgnored),
+                None => AppResult::CacheIgnored,
+            });
+        } else {
+            prepared.literals.insert(key);
+        }
+    }
+
+    if !patterns.is_empty() {
+        prepared.patterns = Some(BatchPatterns {
+            matcher: KeyMatcher::new_many(&patterns, item.options)
+                .map_err(PurgeError::invalid_key)?,
+            exclude_matchers,
+            exclude_paths,
+        });
+    }
+
+    Ok(prepared)
+}
+
+#[cfg(feature = "service")]
+#[inline]
+fn merge_batch_result(
+    slot: &mut Option<Result<AppResult, PurgeError>>,
+    result: Result<AppResult, PurgeError>,
+) {
+    *slot = Some(match (slot.take(), result) {
+        (None, result) => result,
+        (Some(Err(error)), _) | (_, Err(error)) => Err(error),
+        (Some(Ok(a)), Ok(b)) => Ok(a.merge(b)),
+    });
+}
+
+#[cfg(feature = "service")]
+#[inline]
+fn clone_batch_result(result: &Result<AppResult, PurgeError>) -> Result<AppResult, PurgeError> {
+    match result {
+        Ok(AppResult::Ok) => Ok(AppResult::Ok),
+        Ok(AppResult::AlreadyPurged(file_path)) => Ok(AppResult::AlreadyPurged(file_path.clone())),
+        Ok(AppResult::CacheIgnored) => Ok(AppResult::CacheIgnored),
+        Ok(AppResult::AlreadyPurgedWildcard) => Ok(AppResult::AlreadyPurgedWildcard),
+        Ok(AppResult::TooManyEntries(max_entries)) => Ok(AppResult::TooManyEntries(*max_entries)),
+        Err(error) => Err(error.clone()),
+    }
+}
+
+#[cfg(feature = "service")]
+async fn walk_batch(
+    cache_path: PathBuf,
+    levels: Vec<usize>,
+    patterns: Arc<Vec<BatchPatterns>>,
+    hits: Arc<BatchHits>,
+    budget: Arc<EntryBudget>,
+    purge_options: Arc<PurgeOptions>,
+) -> Result<(), PurgeError> {
+    let number_of_levels = levels.len();
+
+    let mut entries = EntryStream::new(
+        cache_path,
+        levels,
+        WalkOptions {
+            filter: None, read_headers: false, prefetch: DEFAULT_PREFETCH
+        },
+        SkipPaths::new(),
+    );
+
+    while let Some(entry) = entries.next().await {
+        let entry = entry?;
+
+        let removed = hit_batch_items(&patterns, &hits, &budget, &entry.path, &entry.key);
+
+        if removed {
+            remove_cache_file(entry.path, number_of_levels, &purge_options).await?;
+        }
+
+        // after the budget has run out, the walk goes on only to find out which items have caches left
+        if !removed && budget.is_exceeded() && hits.all_denied() {
+            break;
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(feature = "service")]
+async fn remove_caches_via_batch_index(
+    mut index: IndexGuard,
+    patterns: Arc<Vec<BatchPatterns>>,
+    hits: Arc<BatchHits>,
+    budget: Arc<EntryBudget>,
+    purge_options: Arc<PurgeOptions>,
+) -> Result<(), PurgeError> {
+    if !index.is_watched() {
+        (index, _) = unblock_index(index, refresh_index).await?;
+    }
+
+    let number_of_levels = index.levels().len();
+
+    let candidates: Vec<PathBuf> = index
+        .entries()
+        .filter(|(_, entry)| {
+            patterns.iter().any(|patterns| patterns.matcher.is_match(entry.key.as_slice()))
+        })
+        .map(|(file_path, _)| file_path)
+        .collect();
+
+    // the candidates are verified and removed on a few blocking threads
+    unblock_index(index, move |index| {
+        let removed = run_in_threads(&candidates, purge_options.remove_threads(), |file_path| {
+            match_and_remove_one_batch_cache_blocking(
+                number_of_levels,
+                &patterns,
+                &hits,
+                &budget,
+                &purge_options,
+                file_path,
+            )
+        });
+
+        for (file_path, removed) in candidates.into_iter().zip(removed) {
+            let removed = match removed {
+                Ok(removed) => removed,
+                // the candidate is outdated
+                Err(error) if error.is_not_found() => false,
+                Err(error) => return Err(error),
+            };
+
+            if removed && !purge_options.dry_run {
+                index.remove_entry(file_path);
+            }
+        }
+
+        save_index(index)
+    })
+    .await
+    .map(|_| ())
+}
+
+/// Remove a cache file if the patterns of any item match its key and the item does not exclude it.
+#[cfg(feature = "service")]
+fn match_and_remove_one_batch_cache_blocking(
+    number_of_levels: usize,
+    patterns: &[BatchPatterns],
+    hits: &BatchHits,
+    budget: &EntryBudget,
+    purge_options: &PurgeOptions,
+    file_path: &Path,
+) -> Result<bool, PurgeError> {
+    let header = read_cache_file_header(file_path, false)
+        .map_err(|error| PurgeError::read(file_path, error))?;
+
+    let hit = hit_batch_items(patterns, hits, budget, file_path, &header.key);
+
+    if hit {
+        remove_cache_file_blocking(file_path.to_path_buf(), number_of_levels, purge_options)?;
+    }
+
+    Ok(hit)
+}
+
+/// Find out whether a cache file should be removed, which is when the patterns of any item match its key, the item does not exclude it and the budget has not run out. The items which match it are marked as removed in `hits`, or as denied if the budget has run out.
+#[cfg(feature = "service")]
+fn hit_batch_items(
+    patterns: &[BatchPatterns],
+    hits: &BatchHits,
+    budget: &EntryBudget,
+    file_path: &Path,
+    key: &[u8],
+) -> bool {
+    let hit_items: Vec<usize> = patterns
+        .iter()
+        .enumerate()
+        .filter(|(_, patterns)| {
+            !patterns.exclude_paths.contains(file_path)
+                && !patterns.exclude_matchers.iter().any(|matcher| matcher.is_match(key))
+                && patterns.matcher.is_match(key)
+        })
+        .map(|(i, _)| i)
+        .collect();
+
+    let hit = !hit_items.is_empty() && budget.take();
+
+    if hit {
+        for i in hit_items {
+            hits.removed[i].store(true, Ordering::Relaxed);
+        }
+    } else {
+        for i in hit_items {
+            hits.denied[i].store(true, Ordering::Relaxed);
+        }
+    }
+
+    hit
+}
+
+/// Run a blocking file operation on a blocking thread of Tokio, like `tokio::fs` does.
+#[cfg(feature = "async")]
+#[inline]
+pub(crate) async fn unblock<T: Send + 'static>(
+    f: impl FnOnce() -> io::Result<T> + Send + 'static,
+) -> io::Result<T> {
+    join(tokio::task::spawn_blocking(f))
+        .await
+        .map_err(|error| io::Error::new(io::ErrorKind::Other, error))?
+}
+
+/// Run a blocking purge on a blocking thread of Tokio.
+#[cfg(feature = "async")]
+#[inline]
+async fn unblock_purge<T: Send + 'static>(
+    f: impl FnOnce() -> Result<T, PurgeError> + Send + 'static,
+) -> Result<T, PurgeError> {
+    join(tokio::task::spawn_blocking(f)).await?
+}
+
+#[cfg(feature = "async")]
+#[inline]
+fn to_strings<K: AsRef<str>>(keys: Vec<K>) -> Vec<String> {
+    keys.iter().map(|key| key.as_ref().to_string()).collect()
+}
+
+/// A locked index which can be moved to a blocking thread.
+#[cfg(feature = "service")]
+type IndexGuard = OwnedMutexGuard<CacheIndex>;
+
+/// Run a blocking operation on a locked index, such as refreshing or saving it, on a blocking thread of Tokio. The index is given back with the output.
+#[cfg(feature = "service")]
+async fn unblock_index<T: Send + 'static>(
+    mut index: IndexGuard,
+    f: impl FnOnce(&mut CacheIndex) -> Result<T, PurgeError> + Send + 'static,
+) -> Result<(IndexGuard, T), PurgeError> {
+    unblock_purge(move || f(&mut index).map(|output| (index, output))).await
+}
+
+#[cfg(feature = "service")]
+#[inline]
+fn refresh_index(index: &mut CacheIndex) -> Result<(), PurgeError> {
+    index.refresh().map_err(|error| PurgeError::read(index.cache_path(), error))
+}
+
+#[cfg(feature = "service")]
+#[inline]
+fn save_index(index: &mut CacheIndex) -> Result<(), PurgeError> {
+    index.save().map_err(|error| PurgeError::read(index.cache_path(), error))
+}
+
+/// Wait for a purge task. A panic in the task is resumed.
+#[cfg(feature = "async")]
+#[inline]
+async fn join<T>(task: JoinHandle<T>) -> Result<T, PurgeError> {
+    match task.await {
+        Ok(output) => Ok(output),
+        Err(error) if error.is_panic() => std::panic::resume_unwind(error.into_panic()),
+        Err(_) => Err(PurgeError::Cancelled),
+    }
+}
+
+/// How many more caches a wildcard purge can remove.
+#[derive(Debug)]
+struct EntryBudget {
+    max:       Option<usize>,
+    remaining: AtomicUsize,
+    exceeded:  AtomicBool,
+}
+
+impl EntryBudget {
+    #[inline]
+    fn new(max: Option<usize>) -> Self {
+        EntryBudget {
+            max,
+            remaining: AtomicUsize::new(max.unwrap_or(usize::MAX)),
+            exceeded: AtomicBool::new(false),
+        }
+    }
+
+    #[inline]
+    fn is_unlimited(&self) -> bool {
+        self.max.is_none()
+    }
+
+    #[inline]
+    fn max(&self) -> usize {
+        self.max.unwrap_or(usize::MAX)
+    }
+
+    /// Take one cache from the budget. Return `false` if the budget has run out.
+    #[inline]
+    fn take(&self) -> bool {
+        let taken = self
+            .remaining
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |remaining| {
+                remaining.checked_sub(1)
+            })
+            .is_ok();
+
+        if !taken {
+            self.exceeded.store(true, Ordering::Relaxed);
+        }
+
+        taken
+    }
+
+    #[inline]
+    fn is_exceeded(&self) -> bool {
+        self.exceeded.load(Ordering::Relaxed)
+    }
+
+    #[inline]
+    fn result(&self, modified: bool) -> AppResult {
+        if self.is_exceeded() {
+            AppResult::TooManyEntries(self.max())
+        } else if modified {
+            AppResult::Ok
+        } else {
+            AppResult::AlreadyPurgedWildcard
+        }
+    }
+}
+
+/// Called with each removed cache during a directory walk. The walk stops if it returns `false`.
+type Progress<'a> = &'a mut dyn FnMut(&CacheEntry) -> bool;
+
+/// How many threads remove the matched caches at the same time by default.
+const REMOVE_THREADS: usize = 16;
+
+/// Run `f` on every item on up to `threads` threads and return the outputs in the order of the items.
+#[cfg(feature = "service")]
+fn run_in_threads<T: Sync, O: Send>(
+    items: &[T],
+    threads: usize,
+    f: impl Fn(&T) -> O + Sync,
+) -> Vec<O> {
+    if items.len() <= 1 || threads <= 1 {
+        return items.iter().map(f).collect();
+    }
+
+    let chunk_size = (items.len() + threads - 1) / threads;
+
+    thread::scope(|scope| {
+        let f = &f;
+
+        let threads: Vec<_> = items
+            .chunks(chunk_size)
+            .map(|chunk| scope.spawn(move || chunk.iter().map(f).collect::<Vec<O>>()))
+            .collect();
+
+        threads
+            .into_iter()
+            .flat_map(|thread| match thread.join() {
+                Ok(outputs) => outputs,
+                Err(panic) => std::panic::resume_unwind(panic),
+            })
+            .collect()
+    })
+}
+
+/// A cache which a removing thread has tried to remove.
+type RemovedCache = (CacheEntry, Result<AppResult, PurgeError>);
+
+/// What a cache file has to match in order to be purged during a directory walk.
+#[derive(Debug)]
+enum PurgeTarget {
+    /// The key pattern.
+    Key(KeyMatcher),
+    /// Any of the tags in the stored response header.
+    Tags { tag_header: String, tags: Vec<Vec<u8>> },
+}
+
+impl PurgeTarget {
+    #[cfg(feature = "service")]
+    #[inline]
+    fn tag_header(&self) -> Option<&str> {
+        match self {
+            PurgeTarget::Key(_) => None,
+            PurgeTarget::Tags {
+                tag_header, ..
+            } => Some(tag_header.as_str()),
+        }
+    }
+
+    #[inline]
+    fn is_all(&self) -> bool {
+        match self {
+            PurgeTarget::Key(matcher) => matcher.is_all(),
+            PurgeTarget::Tags {
+                ..
+            } => false,
         }
     }
 
-    Ok(result)
+    #[inline]
+    fn need_headers(&self) -> bool {
+        matches!(self, PurgeTarget::Tags { .. })
+    }
+
+    #[inline]
+    fn hit(&self, header: &CacheFileHeader) -> bool {
+        match self {
+            PurgeTarget::Key(matcher) => matcher.is_match(header.key.as_slice()),
+            PurgeTarget::Tags {
+                tag_header,
+                tags,
+            } => header
+                .tags(tag_header)
+                .any(|read_tag| tags.iter().any(|tag| tag.as_slice() == read_tag)),
+        }
+    }
 }
 
-/// Purge a cache with a specific key.
-pub async fn remove_one_cache<P: AsRef<Path>, L: AsRef<str>, K: AsRef<str>, EK: AsRef<str>>(
+fn remove_caches_via_walk_blocking<P: AsRef<Path>, L: AsRef<str>, EK: AsRef<str>>(
     cache_path: P,
     levels: L,
-    key: K,
+    target: PurgeTarget,
     exclude_keys: Vec<EK>,
-) -> anyhow::Result<AppResult> {
-    let levels = parse_levels(levels)?;
+    exclude_options: MatchOptions,
+    purge_options: &PurgeOptions,
+    mut progress: Option<Progress>,
+) -> Result<AppResult, PurgeError> {
+    let cache_path = match canonicalize_cache_path(cache_path)? {
+        Some(cache_path) => cache_path,
+        None => return Ok(AppResult::AlreadyPurgedWildcard),
+    };
+
+    let levels = match resolve_levels_blocking(cache_path.as_path(), levels)? {
+        Some(levels) => levels,
+        None => return Ok(AppResult::AlreadyPurgedWildcard),
+    };
     let number_of_levels = levels.len();
 
-    let key = key.as_ref();
+    let (exclude_matchers, exclude_paths) = match parse_exclude_keys(
+        cache_path.as_path(),
+        &levels,
+        exclude_keys,
+        exclude_options,
+        &purge_options.protect,
+    )? {
+        Some(exclusions) => exclusions,
+        None => return Ok(AppResult::AlreadyPurgedWildcard),
+    };
 
-    for exclude_key in exclude_keys {
-        let exclude_key = exclude_key.as_ref();
+    let budget = EntryBudget::new(purge_options.max_entries);
 
-        if exclude_key.is_empty() && key.is_empty() {
-            return Ok(AppResult::CacheIgnored);
-        }
+    // the caches have to be counted if there is a limit, and be reported if there is a progress callback
+    if target.is_all()
+        && exclude_matchers.is_empty()
+        && exclude_paths.is_empty()
+        && budget.is_unlimited()
+        && progress.is_none()
+    {
+        return remove_all_files_in_directory_blocking(
+            cache_path.as_path(),
+            &levels,
+            purge_options,
+        )
+        .map(
+            |modified| {
+                if modified {
+                    AppResult::Ok
+                } else {
+                    AppResult::AlreadyPurgedWildcard
+                }
+            },
+        );
+    }
 
-        let keys = parse_key(&exclude_key);
+    let read_headers = target.need_headers();
 
-        if hit_key(key, &keys) {
-            return Ok(AppResult::CacheIgnored);
-        }
-    }
+    // a key pattern is matched during the walk, and the tags are matched here
+    let (filter, tags_target) = match target {
+        PurgeTarget::Key(matcher) => (Some(matcher), None),
+        target => (None, Some(target)),
+    };
 
-    let file_path = create_cache_file_path(cache_path, levels, key);
+    let walk_options = WalkOptions {
+        filter,
+        read_headers,
+        prefetch: DEFAULT_PREFETCH,
+    };
 
-    match remove_file(&file_path).await {
-        Ok(_) => {
-            remove_empty_ancestors(file_path, number_of_levels).await?;
+    let is_target = |entry: &CacheEntry| {
+        !exclude_matchers.iter().any(|matcher| matcher.is_match(&entry.key))
+            && tags_target.as_ref().map_or(true, |target| target.hit(&entry.header))
+    };
 
-            Ok(AppResult::Ok)
-        },
-        Err(error) if error.kind() == io::ErrorKind::NotFound => {
-            Ok(AppResult::AlreadyPurged(file_path))
-        },
-        Err(error) => Err(error).with_context(|| anyhow!("{file_path:?}")),
-    }
-}
+    let remove_threads = purge_options.remove_threads();
 
-/// Purge multiple caches via wildcard.
-pub async fn remove_caches_via_wildcard<
-    P: AsRef<Path>,
-    L: AsRef<str>,
-    K: AsRef<str>,
-    EK: AsRef<str>,
->(
-    cache_path: P,
-    levels: L,
-    key: K,
-    exclude_keys: Vec<EK>,
-) -> anyhow::Result<AppResult> {
-    #[async_recursion]
-    async fn iterate(
-        number_of_levels: usize,
-        keys: Arc<Vec<Vec<u8>>>,
-        exclude_key_keys: Arc<Vec<Vec<Vec<u8>>>>,
-        exclude_paths: Arc<Mutex<Vec<PathBuf>>>,
-        path: PathBuf,
-        level: usize,
-    ) -> anyhow::Result<bool> {
-        let mut result = false;
+    let mut modified = false;
 
-        let mut tasks = Vec::new();
+    let walked = if remove_threads == 1 {
+        // the caches are removed one by one on the calling thread while walking
+        let mut walked = Ok(());
 
-        for dir_entry in path.read_dir().with_context(|| anyhow!("{path:?}"))? {
-            let dir_entry = dir_entry.with_context(|| anyhow!("{path:?}"))?;
+        walk(cache_path.as_path(), &levels, &walk_options, &exclude_paths, |entry| {
+            let entry = match entry {
+                Ok(entry) => entry,
+                Err(error) => {
+                    if walked.is_ok() {
+                        walked = Err(error);
+                    }
 
-            let file_type = match dir_entry.file_type() {
-                Ok(file_type) => file_type,
-                Err(error) if error.kind() == io::ErrorKind::NotFound => continue,
-                Err(error) => return Err(error).with_context(|| anyhow!("{dir_entry:?}")),
+                    return false;
+                },
             };
 
-            if number_of_levels == level {
-                if file_type.is_file() {
-                    let file_path = dir_entry.path();
+            if !is_target(&entry) {
+                return true;
+            }
 
-                    {
-                        let mut exclude_paths = exclude_paths.lock().await;
+            if !budget.take() {
+                // the rest of the caches do not need to be walked
+                return false;
+            }
 
-                        let exclude_paths_len = exclude_paths.len();
+            let result =
+                remove_cache_file_blocking(entry.path.clone(), number_of_levels, purge_options);
 
-                        let mut i = 0;
+            report_removed((entry, result), &mut modified, &mut progress, &mut walked)
+        });
+
+        walked
+    } else {
+        remove_walked_caches_in_threads(
+            EntryIter::new(cache_path, levels, walk_options, exclude_paths),
+            remove_threads,
+            is_target,
+            &budget,
+            number_of_levels,
+            purge_options,
+            &mut modified,
+            &mut progress,
+        )
+    };
 
-                        let file_path = file_path.as_path();
+    match walked {
+        Ok(()) => Ok(budget.result(modified)),
+        // some caches have been removed before the error
+        Err(error) if modified => Err(PurgeError::Partial(Box::new(error))),
+        Err(error) => Err(error),
+    }
+}
 
-                        while i < exclude_paths_len {
-                            let exclude_path = &exclude_paths[i];
+/// Remove the caches walked by `entries` which `is_target` accepts on up to `remove_threads` threads while the walk goes on.
+#[allow(clippy::too_many_arguments)]
+fn remove_walked_caches_in_threads(
+    entries: EntryIter,
+    remove_threads: usize,
+    is_target: impl Fn(&CacheEntry) -> bool,
+    budget: &EntryBudget,
+    number_of_levels: usize,
+    purge_options: &PurgeOptions,
+    modified: &mut bool,
+    progress: &mut Option<Progress>,
+) -> Result<(), PurgeError> {
+    let (entry_sender, entry_receiver) = mpsc::sync_channel::<CacheEntry>(remove_threads);
+    let (removed_sender, removed_receiver) = mpsc::channel::<RemovedCache>();
 
-                            if exclude_path == file_path {
-                                break;
-                            }
+    let entry_receiver = StdMutex::new(entry_receiver);
 
-                            i += 1;
-                        }
+    // the matched caches are removed by a few threads while the walk goes on
+    thread::scope(|scope| {
+        let entry_receiver = &entry_receiver;
 
-                        if i != exclude_paths_len {
-                            exclude_paths.remove(i);
+        let mut threads = 0;
+        let mut walked = Ok(());
 
-                            continue;
-                        }
-                    }
+        for entry in entries {
+            let entry = match entry {
+                Ok(entry) => entry,
+                Err(error) => {
+                    walked = Err(error);
 
-                    tasks.push(tokio::spawn(match_key_and_remove_one_cache(
-                        number_of_levels,
-                        keys.clone(),
-                        exclude_key_keys.clone(),
-                        file_path,
-                    )));
-                }
-            } else if file_type.is_dir() {
-                tasks.push(tokio::spawn(iterate(
-                    number_of_levels,
-                    keys.clone(),
-                    exclude_key_keys.clone(),
-                    exclude_paths.clone(),
-                    dir_entry.path(),
-                    level + 1,
-                )));
+                    break;
+                },
+            };
+
+            if !is_target(&entry) {
+                continue;
             }
-        }
 
-        for task in tasks {
-            result = task.await.unwrap()? || result;
-        }
+            if !budget.take() {
+                // the rest of the caches do not need to be walked
+                break;
+            }
 
-        Ok(result)
-    }
+            if threads < remove_threads {
+                let removed_sender = removed_sender.clone();
 
-    let cache_path = cache_path.as_ref();
+                scope.spawn(move || loop {
+                    let entry = match entry_receiver
+                        .lock()
+                        .unwrap_or_else(PoisonError::into_inner)
+                        .recv()
+                    {
+                        Ok(entry) => entry,
+                        Err(_) => break,
+                    };
 
-    let cache_path = match cache_path.canonicalize() {
-        Ok(path) => {
-            if !path.is_dir() {
-                return Err(anyhow!("{cache_path:?} is not a directory."));
-            }
+                    let result = remove_cache_file_blocking(
+                        entry.path.clone(),
+                        number_of_levels,
+                        purge_options,
+                    );
 
-            path
-        },
-        Err(error) if error.kind() == io::ErrorKind::NotFound => {
-            return Ok(AppResult::AlreadyPurgedWildcard);
-        },
-        Err(error) => return Err(error).with_context(|| anyhow!("{cache_path:?}")),
-    };
+                    if removed_sender.send((entry, result)).is_err() {
+                        break;
+                    }
+                });
 
-    let levels = parse_levels(levels)?;
-    let number_of_levels = levels.len();
+                threads += 1;
+            }
 
-    let mut exclude_key_keys: Vec<Vec<Vec<u8>>> = Vec::new();
-    let mut exclude_paths: Vec<PathBuf> = Vec::new();
+            // this does not fail because the removing threads keep receiving until the sender is dropped
+            let _ = entry_sender.send(entry);
 
-    for exclude_key in exclude_keys {
-        let exclude_key = exclude_key.as_ref();
+            let mut go_on = true;
 
-        if exclude_key.contains('*') {
-            let keys: Vec<Vec<u8>> =
-                parse_key(&exclude_key).into_iter().map(|v| v.to_vec()).collect();
+            // every removed cache is reported even if the walk stops
+            for removed in removed_receiver.try_iter() {
+                go_on &= report_removed(removed, modified, progress, &mut walked);
+            }
 
-            if keys.len() == 1 && keys[0].is_empty() {
-                return Ok(AppResult::AlreadyPurgedWildcard);
+            if !go_on {
+                break;
             }
+        }
 
-            exclude_key_keys.push(keys);
-        } else {
-            let file_path = create_cache_file_path(cache_path.as_path(), &levels, exclude_key);
+        drop(entry_sender);
+        drop(removed_sender);
 
-            exclude_paths.push(file_path);
+        // wait for the caches which are being removed
+        for removed in removed_receiver {
+            report_removed(removed, modified, progress, &mut walked);
         }
+
+        walked
+    })
+}
+
+/// Report a cache which has been removed during a directory walk to `progress`, or keep the first error in `walked`. Return `false` if the walk should stop.
+#[inline]
+fn report_removed(
+    (entry, result): RemovedCache,
+    modified: &mut bool,
+    progress: &mut Option<Progress>,
+    walked: &mut Result<(), PurgeError>,
+) -> bool {
+    match result {
+        Ok(AppResult::Ok) => {
+            *modified = true;
+
+            match progress.as_mut() {
+                Some(progress) => progress(&entry),
+                None => true,
+            }
+        },
+        // the cache has been removed by someone else after it was walked
+        Ok(_) => true,
+        Err(error) => {
+            if walked.is_ok() {
+                *walked = Err(error);
+            }
+
+            false
+        },
     }
+}
+
+#[cfg(feature = "service")]
+async fn remove_caches_via_index<EK: AsRef<str>>(
+    mut index: IndexGuard,
+    target: PurgeTarget,
+    exclude_keys: Vec<EK>,
+    exclude_options: MatchOptions,
+    purge_options: &PurgeOptions,
+) -> Result<AppResult, PurgeError> {
+    let number_of_levels = index.levels().len();
+
+    let (exclude_matchers, exclude_paths) = match parse_exclude_keys(
+        index.cache_path(),
+        index.levels(),
+        exclude_keys,
+        exclude_options,
+        &purge_options.protect,
+    )? {
+        Some(exclusions) => exclusions,
+        None => return Ok(AppResult::AlreadyPurgedWildcard),
+    };
 
-    let keys = parse_key(&key);
+    let budget = EntryBudget::new(purge_options.max_entries);
+    let purge_options = purge_options.clone();
 
-    if keys.len() == 1
-        && keys[0].is_empty()
-        && exclude_key_keys.is_empty()
+    if target.is_all()
+        && exclude_matchers.is_empty()
         && exclude_paths.is_empty()
+        && budget.is_unlimited()
     {
-        return remove_all_files_in_directory(cache_path).await.map(|modified| {
-            if modified {
-                AppResult::Ok
-            } else {
-                AppResult::AlreadyPurgedWildcard
+        return unblock_index(index, move |index| {
+            let modified = remove_all_files_in_directory_blocking(
+                index.cache_path(),
+                index.levels(),
+                &purge_options,
+            );
+
+            // forget the removed caches even if only some of them have been removed
+            if !purge_options.dry_run {
+                index.clear();
+
+                save_index(index)?;
             }
-        });
+
+            modified
+        })
+        .await
+        .map(
+            |(_, modified)| {
+                if modified {
+                    AppResult::Ok
+                } else {
+                    AppResult::AlreadyPurgedWildcard
+                }
+            },
+        );
     }
 
-    let keys = keys.into_iter().map(|v| v.to_vec()).collect::<Vec<Vec<u8>>>();
+    if !index.is_watched() {
+        (index, _) = unblock_index(index, refresh_index).await?;
+    }
 
-    iterate(
-        number_of_levels,
-        Arc::new(keys),
-        Arc::new(exclude_key_keys),
-        Arc::new(Mutex::new(exclude_paths)),
-        cache_path,
-        0,
-    )
-    .await
-    .map(|modified| if modified { AppResult::Ok } else { AppResult::AlreadyPurgedWildcard })
-}
+    let candidates: HashSet<PathBuf> = match &target {
+        PurgeTarget::Key(matcher) => index
+            .entries()
+            .filter(|(_, entry)| matcher.is_match(entry.key.as_slice()))
+            .map(|(file_path, _)| file_path)
+            .collect(),
+        PurgeTarget::Tags {
+            tags, ..
+        } => tags.iter().flat_map(|tag| index.files_with_tag(tag)).collect(),
+    };
 
-fn hit_key<RK: AsRef<[u8]>, K: AsRef<[u8]>>(read_key: RK, keys: &[K]) -> bool {
-    let read_key = read_key.as_ref();
+    let candidates: Vec<PathBuf> =
+        candidates.into_iter().filter(|file_path| !exclude_paths.contains(file_path)).collect();
+
+    // the candidates are verified and removed on a few blocking threads
+    unblock_index(index, move |index| {
+        let removed = run_in_threads(&candidates, purge_options.remove_threads(), |file_path| {
+            match_and_remove_one_cache_blocking(
+                number_of_levels,
+                &target,
+                &exclude_matchers,
+                &budget,
+                &purge_options,
+                file_path,
+            )
+        });
 
-    let mut p = 0;
-    let mut i = 0;
-    let read_key_len = read_key.len();
-    let keys_len = keys.len();
+        let mut result = false;
 
-    loop {
-        let key = keys[i].as_ref();
-        let key_len = key.len();
+        for (file_path, removed) in candidates.into_iter().zip(removed) {
+            let removed = match removed {
+                Ok(removed) => removed,
+                // the candidate is outdated
+                Err(error) if error.is_not_found() => false,
+                Err(error) => return Err(error),
+            };
 
-        if key_len == 0 {
-            i += 1;
+            if removed {
+                if !purge_options.dry_run {
+                    index.remove_entry(file_path);
+                }
 
-            if i == keys_len {
-                break true;
+                result = true;
             }
+        }
 
-            let key = keys[i].as_ref();
-            let key_len = key.len();
-            debug_assert!(!key.is_empty());
+        save_index(index)?;
 
-            match read_key[p..].windows(key_len).position(|window| window == key).map(|i| i + p) {
-                Some(index) => {
-                    i += 1;
+        Ok(budget.result(result))
+    })
+    .await
+    .map(|(_, result)| result)
+}
 
-                    if i == keys_len {
-                        break true;
-                    }
+/// Return `None` if the cache path does not exist.
+pub(crate) fn canonicalize_cache_path<P: AsRef<Path>>(
+    cache_path: P,
+) -> Result<Option<PathBuf>, PurgeError> {
+    let cache_path = cache_path.as_ref();
 
-                    p = index + key_len;
-                },
-                None => {
-                    break false;
-                },
+    match cache_path.canonicalize() {
+        Ok(path) => {
+            if !path.is_dir() {
+                return Err(PurgeError::NotADirectory(cache_path.to_path_buf()));
             }
-        } else if read_key_len - p < key_len {
-            break false;
+
+            Ok(Some(path))
+        },
+        Err(error) if error.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(error) => Err(PurgeError::io(cache_path, error)),
+    }
+}
+
+/// The compiled exclude patterns and the paths of the exact exclude keys.
+pub(crate) type Exclusions = (Vec<KeyMatcher>, HashSet<PathBuf>);
+
+/// Split the exclude keys into patterns, which are compiled into one matcher, and the paths of exact keys, and add the `protect` patterns in the wildcard mode. Return `None` if every cache is excluded.
+pub(crate) fn parse_exclude_keys<EK: AsRef<str>>(
+    cache_path: &Path,
+    levels: &[usize],
+    exclude_keys: Vec<EK>,
+    options: MatchOptions,
+    protect: &[String],
+) -> Result<Option<Exclusions>, PurgeError> {
+    let mut exclude_matchers: Vec<KeyMatcher> = Vec::new();
+    let mut exclude_paths: HashSet<PathBuf> = HashSet::new();
+    let mut exclude_patterns: Vec<EK> = Vec::new();
+
+    for exclude_key in exclude_keys {
+        if let Some(literal) =
+            options.literal(exclude_key.as_ref()).map_err(PurgeError::invalid_key)?
+        {
+            exclude_paths.insert(create_cache_file_path(cache_path, levels, literal));
         } else {
-            let e = p + key_len;
+            exclude_patterns.push(exclude_key);
+        }
+    }
 
-            if &read_key[p..e] == key {
-                i += 1;
+    if !exclude_patterns.is_empty() {
+        let matcher =
+            KeyMatcher::new_many(&exclude_patterns, options).map_err(PurgeError::invalid_key)?;
 
-                if i == keys_len {
-                    break true;
-                }
+        if matcher.is_all() {
+            return Ok(None);
+        }
 
-                p = e;
-            } else {
-                break false;
-            }
+        exclude_matchers.push(matcher);
+    }
+
+    if !protect.is_empty() {
+        let matcher =
+            KeyMatcher::new_many(protect, MatchMode::Wildcard).map_err(PurgeError::invalid_key)?;
+
+        if matcher.is_all() {
+            return Ok(None);
         }
+
+        exclude_matchers.push(matcher);
     }
+
+    Ok(Some((exclude_matchers, exclude_paths)))
 }
 
-async fn match_key_and_remove_one_cache<P: AsRef<Path>>(
-    number_of_levels: usize,
-    keys: Arc<Vec<Vec<u8>>>,
-    exclude_key_keys: Arc<Vec<Vec<Vec<u8>>>>,
-    file_path: P,
-) -> anyhow::Result<bool> {
-    let file_path = file_path.as_ref();
+/// Split the keys into deduplicated exact keys (with the escapes removed) and patterns.
+fn split_keys<K: AsRef<str>>(
+    keys: Vec<K>,
+    options: MatchOptions,
+) -> Result<(HashSet<String>, Vec<K>), PurgeError> {
+    let mut literals: HashSet<String> = HashSet::new();
+    let mut patterns: Vec<K> = Vec::new();
+
+    for key in keys {
+        match options.literal(key.as_ref()).map_err(PurgeError::invalid_key)? {
+            Some(literal) => {
+                literals.insert(literal);
+            },
+            None => patterns.push(key),
+        }
+    }
+
+    Ok((literals, patterns))
+}
 
-    let mut sc: ScannerAscii<_, U384> =
-        ScannerAscii::scan_path2(file_path).with_context(|| anyhow!("{file_path:?}"))?;
+fn parse_tags_target<H: Into<String>, T: AsRef<str>>(
+    tag_header: H,
+    tags: Vec<T>,
+) -> Result<PurgeTarget, PurgeError> {
+    let tag_header = tag_header.into();
 
-    // skip the header
-    sc.drop_next_line().with_context(|| anyhow!("{file_path:?}"))?;
+    if tag_header.is_empty() {
+        return Err(PurgeError::InvalidKey(String::from(
+            "The name of the tag header cannot be empty.",
+        )));
+    }
 
-    // skip the label
-    sc.drop_next_bytes("KEY: ".len()).with_context(|| anyhow!("{file_path:?}"))?;
+    let tags: Vec<Vec<u8>> = tags
+        .iter()
+        .flat_map(|tag| tag.as_ref().split(|c: char| c == ',' || c.is_ascii_whitespace()))
+        .filter(|tag| !tag.is_empty())
+        .map(|tag| tag.as_bytes().to_vec())
+        .collect();
 
-    let read_key = sc
-        .next_line_raw()
-        .with_context(|| anyhow!("{file_path:?}"))?
-        .ok_or(anyhow!("The content of {file_path:?} is incorrect."))?;
+    if tags.is_empty() {
+        return Err(PurgeError::InvalidKey(String::from("At least one tag is needed.")));
+    }
 
-    // drop sc
-    drop(sc);
+    Ok(PurgeTarget::Tags {
+        tag_header,
+        tags,
+    })
+}
 
-    for exclude_key_key in exclude_key_keys.as_ref() {
-        if hit_key(read_key.as_slice(), exclude_key_key) {
+#[cfg(feature = "service")]
+fn match_and_remove_one_cache_blocking(
+    number_of_levels: usize,
+    target: &PurgeTarget,
+    exclude_matchers: &[KeyMatcher],
+    budget: &EntryBudget,
+    purge_options: &PurgeOptions,
+    file_path: &Path,
+) -> Result<bool, PurgeError> {
+    let header = read_cache_file_header(file_path, target.need_headers())
+        .map_err(|error| PurgeError::read(file_path, error))?;
+
+    for exclude_matcher in exclude_matchers {
+        if exclude_matcher.is_match(header.key.as_slice()) {
             return Ok(false);
         }
     }
 
-    if hit_key(read_key, keys.as_ref()) {
-        match remove_file(file_path).await {
-            Ok(_) => (),
-            Err(error) if error.kind() == io::ErrorKind::NotFound => (),
-            Err(error) => return Err(error).with_context(|| anyhow!("{file_path:?}")),
-        }
-
-        remove_empty_ancestors(file_path, number_of_levels).await?;
+    if target.hit(&header) && budget.take() {
+        remove_cache_file_blocking(file_path.to_path_buf(), number_of_levels, purge_options)?;
 
         Ok(true)
     } else {
@@ -438,23 +1924,29 @@ async fn match_key_and_remove_one_cache<P: AsRef<Path>>(
     }
 }
 
-fn parse_levels<L: AsRef<str>>(levels: L) -> anyhow::Result<Vec<usize>> {
+pub(crate) fn parse_levels<L: AsRef<str>>(levels: L) -> Result<Vec<usize>, PurgeError> {
     let levels: Vec<&str> = levels.as_ref().split(':').collect();
 
     if levels.len() > 3 {
-        Err(anyhow!("The number of hierarchy levels cannot be bigger than 3."))
+        Err(PurgeError::InvalidLevels(String::from(
+            "The number of hierarchy levels cannot be bigger than 3.",
+        )))
     } else {
         let number_of_levels = levels.len();
 
         let mut levels_usize = Vec::with_capacity(number_of_levels);
 
         for level in levels {
-            let level_usize = level
-                .parse()
-                .with_context(|| anyhow!("The value of levels should be a positive integer."))?;
+            let level_usize = level.parse().map_err(|_| {
+                PurgeError::InvalidLevels(String::from(
+                    "The value of levels should be a positive integer.",
+                ))
+            })?;
 
             if !(1..=2).contains(&level_usize) {
-                return Err(anyhow!("The value of levels should be 1 or 2."));
+                return Err(PurgeError::InvalidLevels(String::from(
+                    "The value of levels should be 1 or 2.",
+                )));
             }
 
             levels_usize.push(level_usize);
@@ -464,47 +1956,141 @@ fn parse_levels<L: AsRef<str>>(levels: L) -> anyhow::Result<Vec<usize>> {
     }
 }
 
-fn parse_key<K: AsRef<str>>(key: &K) -> Vec<&[u8]> {
-    let key = key.as_ref().as_bytes();
+#[inline]
+pub(crate) fn format_levels(levels: &[usize]) -> String {
+    levels.iter().map(|level| level.to_string()).collect::<Vec<_>>().join(":")
+}
+
+/// The number of cache files which are checked to detect the levels of a cache zone.
+const LEVELS_DETECTION_SAMPLES: usize = 32;
 
-    debug_assert!(!key.is_empty());
+/// Parse `levels`, or detect the levels from the layout of the cache zone if `levels` is `auto`. Return `None` if the levels need to be detected but there is no cache file.
+pub(crate) fn resolve_levels_blocking<P: AsRef<Path>, L: AsRef<str>>(
+    cache_path: P,
+    levels: L,
+) -> Result<Option<Vec<usize>>, PurgeError> {
+    let levels = levels.as_ref();
 
-    let mut v = Vec::new();
+    if levels == "auto" {
+        detect_levels(cache_path.as_ref())
+    } else {
+        parse_levels(levels).map(Some)
+    }
+}
 
-    let mut p = 0;
-    let key_len = key.len();
+#[cfg(feature = "async")]
+#[inline]
+pub(crate) async fn resolve_levels<P: AsRef<Path>, L: AsRef<str>>(
+    cache_path: P,
+    levels: L,
+) -> Result<Option<Vec<usize>>, PurgeError> {
+    let levels = levels.as_ref();
 
-    loop {
-        match key[p..].iter().cloned().position(|u| u == b'*').map(|i| i + p) {
-            Some(i) => {
-                if i == p {
-                    // don't allow duplicated empty string to be added into Vec (when key is like foo**bar)
-                    if v.is_empty() {
-                        v.push([].as_slice());
-                    }
-                } else {
-                    v.push(&key[p..i]);
-                    v.push(&[]);
+    if levels == "auto" {
+        let cache_path = cache_path.as_ref().to_path_buf();
+
+        unblock_purge(move || detect_levels(cache_path.as_path())).await
+    } else {
+        parse_levels(levels).map(Some)
+    }
+}
+
+/// Detect the levels from the depth and the name lengths of the level directories. Some cache files are checked to make sure that they are named after the md5 of their keys and are at the same depth.
+fn detect_levels(cache_path: &Path) -> Result<Option<Vec<usize>>, PurgeError> {
+    let mut detected: Option<Vec<usize>> = None;
+    let mut samples = 0;
+
+    // (directory, the name lengths of the level directories from the cache path to it)
+    let mut directories = vec![(cache_path.to_path_buf(), Vec::new())];
+
+    while let Some((directory, levels)) = directories.pop() {
+        let entries = match fs::read_dir(directory.as_path()) {
+            Ok(entries) => entries,
+            Err(error) if levels.is_empty() && error.kind() == io::ErrorKind::NotFound => {
+                return Ok(None);
+            },
+            Err(error) => return Err(PurgeError::io(&directory, error)),
+        };
+
+        for entry in entries {
+            let entry = entry.map_err(|error| PurgeError::io(&directory, error))?;
+            let file_name = entry.file_name();
+
+            let name = match file_name.to_str() {
+                Some(name) if name.bytes().all(|u| matches!(u, b'0'..=b'9' | b'a'..=b'f')) => name,
+                // not a level directory or a cache file, such as the temporary directory
+                _ => continue,
+            };
+
+            let path = entry.path();
+            let file_type = entry.file_type().map_err(|error| PurgeError::io(&path, error))?;
+
+            if file_type.is_dir() && (1..=2).contains(&name.len()) {
+                if levels.len() == 3 {
+                    return Err(PurgeError::InvalidLevels(format!(
+                        "The layout of {cache_path:?} is inconsistent. {path:?} is deeper than 3 \
+                         levels."
+                    )));
                 }
 
-                p = i + 1;
+                let mut levels = levels.clone();
+                levels.push(name.len());
 
-                if p >= key_len {
-                    break;
+                directories.push((path, levels));
+            } else if file_type.is_file() && name.len() == 32 {
+                if levels.is_empty() {
+                    return Err(PurgeError::InvalidLevels(format!(
+                        "{path:?} is not in any level directory. Cache zones without levels are \
+                         not supported."
+                    )));
                 }
-            },
-            None => {
-                v.push(&key[p..]);
 
-                break;
-            },
+                if create_cache_file_path_by_hash(cache_path, &levels, name) != path {
+                    return Err(PurgeError::InvalidLevels(format!(
+                        "The layout of {cache_path:?} is inconsistent. {path:?} is not in the \
+                         level directories of its name."
+                    )));
+                }
+
+                let header = match read_cache_file_header(path.as_path(), false) {
+                    Ok(header) => header,
+                    // it may be being written or removed
+                    Err(_) => continue,
+                };
+
+                if create_cache_file_path(cache_path, &levels, &header.key) != path {
+                    return Err(PurgeError::InvalidLevels(format!(
+                        "{path:?} is not named after the md5 of its key."
+                    )));
+                }
+
+                match detected.as_ref() {
+                    Some(detected) if *detected != levels => {
+                        return Err(PurgeError::InvalidLevels(format!(
+                            "The layout of {cache_path:?} is inconsistent. Both levels {} and {} \
+                             are used.",
+                            format_levels(detected),
+                            format_levels(&levels)
+                        )));
+                    },
+                    Some(_) => (),
+                    None => detected = Some(levels.clone()),
+                }
+
+                samples += 1;
+
+                if samples == LEVELS_DETECTION_SAMPLES {
+                    return Ok(detected);
+                }
+            }
         }
     }
 
-    v
+    Ok(detected)
 }
 
-fn create_cache_file_path<P: AsRef<Path>, L: AsRef<[usize]>, K: AsRef<str>>(
+/// Get the path of the cache file of a key. The key is hashed as raw bytes like nginx does, so it does not have to be UTF-8.
+pub(crate) fn create_cache_file_path<P: AsRef<Path>, L: AsRef<[usize]>, K: AsRef<[u8]>>(
     cache_path: P,
     levels: L,
     key: K,
@@ -515,6 +2101,16 @@ fn create_cache_file_path<P: AsRef<Path>, L: AsRef<[usize]>, K: AsRef<str>>(
     let key_md5_value = u128::from_be_bytes(hasher.finalize().into());
     let hashed_key = format!("{:032x}", key_md5_value);
 
+    create_cache_file_path_by_hash(cache_path, levels, hashed_key)
+}
+
+fn create_cache_file_path_by_hash<P: AsRef<Path>, L: AsRef<[usize]>, H: AsRef<str>>(
+    cache_path: P,
+    levels: L,
+    hashed_key: H,
+) -> PathBuf {
+    let hashed_key = hashed_key.as_ref();
+
     let mut file_path = cache_path.as_ref().to_path_buf();
     let mut p = 32; // md5's hex string length
 
@@ -528,3 +2124,197 @@ fn create_cache_file_path<P: AsRef<Path>, L: AsRef<[usize]>, K: AsRef<str>>(
 
     file_path
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_zone::TestZone;
+
+    #[test]
+    fn purge_keys_and_patterns_in_one_walk() {
+        let zone = TestZone::new("walk", "1:2");
+
+        zone.add("http/blog/", "");
+        zone.add("http/blog/2", "");
+        zone.add("http/static/a.jpg", "");
+
+        let other = zone.add("http/Blog/x", "");
+
+        let result = remove_caches_blocking(
+            &zone.path,
+            "1:2",
+            vec!["http/blog/*", "http/static/a.jpg", "http/none"],
+            Vec::<&str>::new(),
+            MatchOptions::default(),
+            &PurgeOptions::default(),
+        )
+        .unwrap();
+
+        assert!(matches!(result, AppResult::Ok));
+        assert_eq!(zone.files(), [other]);
+
+        let result = remove_caches_blocking(
+            &zone.path,
+            "1:2",
+            vec!["http/blog/*"],
+            Vec::<&str>::new(),
+            MatchOptions::default(),
+            &PurgeOptions::default(),
+        )
+        .unwrap();
+
+        assert!(matches!(result, AppResult::AlreadyPurgedWildcard));
+    }
+
+    #[test]
+    fn purge_patterns_with_exclusions_and_threads() {
+        for threads in [1, 4] {
+            let zone = TestZone::new("walk-threads", "2");
+
+            let kept = zone.add("http/blog/2", "");
+
+            for i in 0..20 {
+                zone.add(format!("http/static/{i}.jpg"), "");
+            }
+
+            let purge_options = PurgeOptions {
+                threads,
+                ..PurgeOptions::default()
+            };
+
+            let result = remove_caches_blocking(
+                &zone.path,
+                "auto",
+                vec!["http/*"],
+                vec!["http/blog/*"],
+                MatchOptions::default(),
+                &purge_options,
+            )
+            .unwrap();
+
+            assert!(matches!(result, AppResult::Ok), "{threads}");
+            assert_eq!(zone.files(), [kept], "{threads}");
+        }
+    }
+
+    #[cfg(feature = "service")]
+    fn item(keys: &[&str], exclude_keys: &[&str], mode: MatchMode) -> BatchItem {
+        BatchItem {
+            keys:         keys.iter().map(|key| key.to_string()).collect(),
+            exclude_keys: exclude_keys.iter().map(|key| key.to_string()).collect(),
+            options:      MatchOptions::from(mode),
+        }
+    }
+
+    #[cfg(feature = "service")]
+    #[tokio::test]
+    async fn purge_batch_items() {
+        let zone = TestZone::new("batch", "1:2");
+
+        let blog = zone.add("http/blog/", "");
+        let kept = zone.add("http/static/b.jpg", "");
+
+        zone.add("http/static/a.jpg", "");
+        zone.add("http/static/c.jpg", "");
+
+        let items = vec![
+            item(&["http/blog/"], &[], MatchMode::Wildcard),
+            // the same exact key is purged only once, but both items report it
+            item(&["http/blog/"], &[], MatchMode::Wildcard),
+            item(&["http/static/*"], &["http/static/b.jpg"], MatchMode::Wildcard),
+            item(&["http/none/*"], &[], MatchMode::Wildcard),
+            item(&["http/none"], &[], MatchMode::Wildcard),
+            item(&["http/["], &[], MatchMode::Glob),
+        ];
+
+        let results =
+            remove_caches_via_batch(&zone.path, "1:2", None, items, &PurgeOptions::default())
+                .await
+                .unwrap();
+
+        assert!(matches!(results[0], Ok(AppResult::Ok)));
+        assert!(matches!(results[1], Ok(AppResult::Ok)));
+        assert!(matches!(results[2], Ok(AppResult::Ok)));
+        assert!(matches!(results[3], Ok(AppResult::AlreadyPurgedWildcard)));
+        assert!(matches!(results[4], Ok(AppResult::AlreadyPurged(_))));
+        assert!(matches!(results[5], Err(PurgeError::InvalidKey(_))));
+        assert!(!blog.exists());
+        assert_eq!(zone.files(), [kept]);
+    }
+
+    #[cfg(feature = "service")]
+    #[tokio::test]
+    async fn purge_batch_items_with_limit() {
+        let zone = TestZone::new("batch-limit", "1:2");
+
+        for i in 0..4 {
+            zone.add(format!("http/static/{i}.jpg"), "");
+        }
+
+        let items = vec![
+            item(&["http/static/*"], &[], MatchMode::Wildcard),
+            item(&["http/*.jpg"], &[], MatchMode::Wildcard),
+        ];
+
+        let purge_options = PurgeOptions {
+            max_entries: Some(2),
+            ..PurgeOptions::default()
+        };
+
+        let results =
+            remove_caches_via_batch(&zone.path, "1:2", None, items, &purge_options).await.unwrap();
+
+        assert!(results.iter().any(|result| matches!(result, Ok(AppResult::TooManyEntries(2)))));
+        assert_eq!(zone.files().len(), 2);
+    }
+
+    #[cfg(feature = "service")]
+    #[tokio::test]
+    async fn purge_batch_items_via_index() {
+        for dry_run in [true, false] {
+            let zone = TestZone::new("batch-index", "1:2");
+            let index_dir = TestZone::new("batch-index-dir", "1");
+
+            zone.add("http/blog/", "");
+            zone.add("http/blog/2", "");
+
+            let kept = zone.add("http/static/a.jpg", "");
+
+            let mut index =
+                CacheIndex::open(&index_dir.path, &zone.path, zone.levels.clone(), "Cache-Tag")
+                    .unwrap();
+
+            index.rescan().unwrap();
+
+            let index = Arc::new(Mutex::new(index));
+
+            let files = zone.files();
+
+            let purge_options = PurgeOptions {
+                dry_run,
+                ..PurgeOptions::default()
+            };
+
+            let results = remove_caches_via_batch(
+                &zone.path,
+                "1:2",
+                Some(index.clone()),
+                vec![item(&["http/blog/*"], &[], MatchMode::Wildcard)],
+                &purge_options,
+            )
+            .await
+            .unwrap();
+
+            assert!(matches!(results[0], Ok(AppResult::Ok)), "{dry_run}");
+
+            // a dry run leaves the caches and the index as they are
+            if dry_run {
+                assert_eq!(zone.files(), files);
+                assert_eq!(index.lock().await.entries().count(), 3);
+            } else {
+                assert_eq!(zone.files(), [kept.as_path()]);
+                assert_eq!(index.lock().await.entries().count(), 1);
+            }
+        }
+    }
+}
@@ -0,0 +1,885 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fs,
+    fs::File,
+    io,
+    io::{BufRead, BufReader, BufWriter, Write},
+    os::unix::ffi::OsStrExt,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{anyhow, Context};
+use md5::{Digest, Md5};
+use tokio::sync::Mutex;
+
+use crate::{
+    cache_file::read_cache_file_header,
+    functions::{format_levels, resolve_levels, unblock},
+};
+
+const INDEX_FILE_SIGNATURE: &str = "nginx-cache-purge-index 1";
+
+/// Directories modified within this duration are rescanned on the next refresh because files may still be added within the same timestamp.
+const UNSTABLE_DURATION: Duration = Duration::from_secs(2);
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+struct ModifiedTime {
+    secs:  u64,
+    nanos: u32,
+}
+
+impl ModifiedTime {
+    /// A value which never equals the real modified time, to force a rescan.
+    const UNKNOWN: ModifiedTime = ModifiedTime {
+        secs: 0, nanos: 0
+    };
+
+    #[inline]
+    fn from_metadata(metadata: &fs::Metadata) -> io::Result<Self> {
+        let duration = metadata.modified()?.duration_since(UNIX_EPOCH).unwrap_or_default();
+
+        Ok(ModifiedTime {
+            secs: duration.as_secs(), nanos: duration.subsec_nanos()
+        })
+    }
+
+    #[inline]
+    fn is_stable(&self) -> bool {
+        let modified = UNIX_EPOCH + Duration::new(self.secs, self.nanos);
+
+        match SystemTime::now().duration_since(modified) {
+            Ok(elapsed) => elapsed >= UNSTABLE_DURATION,
+            Err(_) => false,
+        }
+    }
+}
+
+/// A cache file recorded in the index.
+#[derive(Debug)]
+pub struct IndexedEntry {
+    modified: ModifiedTime,
+    pub key:  Vec<u8>,
+    pub tags: Vec<Vec<u8>>,
+}
+
+#[derive(Debug, Default)]
+struct IndexedDirectory {
+    modified: ModifiedTime,
+    /// hash (file name) -> entry
+    entries:  HashMap<String, IndexedEntry>,
+}
+
+/// An on-disk index of the keys and tags of a cache zone.
+///
+/// It is refreshed incrementally. Only the level directories whose modified time has changed are rescanned, and only the cache files whose modified time has changed are read again.
+#[derive(Debug)]
+pub struct CacheIndex {
+    cache_path:      PathBuf,
+    levels:          Vec<usize>,
+    tag_header:      String,
+    index_file_path: PathBuf,
+    /// relative path of a last-level directory (e.g. `6/33`) -> directory
+    directories:     HashMap<String, IndexedDirectory>,
+    /// tag -> relative paths of cache files
+    tag_map:         HashMap<Vec<u8>, HashSet<String>>,
+    dirty:           bool,
+    /// whether the index is kept up to date by a watcher
+    watched:         bool,
+}
+
+impl CacheIndex {
+    /// Load the index of a cache zone from `index_dir`, or create an empty one if it does not exist or is outdated. `cache_path` should be canonicalized.
+    pub fn open<D: AsRef<Path>, P: Into<PathBuf>, L: Into<Vec<usize>>, H: Into<String>>(
+        index_dir: D,
+        cache_path: P,
+        levels: L,
+        tag_header: H,
+    ) -> anyhow::Result<Self> {
+        let cache_path = cache_path.into();
+        let levels = levels.into();
+        let tag_header = tag_header.into();
+
+        let index_file_path =
+            index_dir.as_ref().join(create_index_file_name(cache_path.as_path(), &levels));
+
+        let mut index = CacheIndex {
+            cache_path,
+            levels,
+            tag_header,
+            index_file_path,
+            directories: HashMap::new(),
+            tag_map: HashMap::new(),
+            dirty: true,
+            watched: false,
+        };
+
+        match File::open(&index.index_file_path) {
+            Ok(file) => {
+                if let Err(error) = index.load(file) {
+                    // an outdated or broken index file is just rebuilt
+                    index.directories.clear();
+
+                    tracing::debug!("rebuild index {:?}: {error:?}", index.index_file_path);
+                } else {
+                    index.dirty = false;
+                }
+            },
+            Err(error) if error.kind() == io::ErrorKind::NotFound => (),
+            Err(error) => {
+                return Err(error).with_context(|| anyhow!("{:?}", index.index_file_path))
+            },
+        }
+
+        index.rebuild_tag_map();
+
+        Ok(index)
+    }
+
+    #[inline]
+    pub fn cache_path(&self) -> &Path {
+        self.cache_path.as_path()
+    }
+
+    #[inline]
+    pub fn levels(&self) -> &[usize] {
+        self.levels.as_slice()
+    }
+
+    #[inline]
+    pub fn tag_header(&self) -> &str {
+        self.tag_header.as_str()
+    }
+
+    /// Iterate over the indexed cache files with their absolute paths.
+    pub fn entries(&self) -> impl Iterator<Item = (PathBuf, &IndexedEntry)> {
+        self.directories.iter().flat_map(move |(directory_path, directory)| {
+            directory
+                .entries
+                .iter()
+                .map(move |(hash, entry)| (self.cache_path.join(directory_path).join(hash), entry))
+        })
+    }
+
+    /// Get the absolute paths of the indexed cache files tagged with `tag`.
+    pub fn files_with_tag<'a>(&'a self, tag: &[u8]) -> impl Iterator<Item = PathBuf> + 'a {
+        self.tag_map
+            .get(tag)
+            .into_iter()
+            .flat_map(move |paths| paths.iter().map(move |path| self.cache_path.join(path)))
+    }
+
+    #[inline]
+    pub fn is_watched(&self) -> bool {
+        self.watched
+    }
+
+    #[inline]
+    pub fn set_watched(&mut self, watched: bool) {
+        self.watched = watched;
+    }
+
+    /// Remove a cache file from the index after it has been purged or deleted.
+    pub fn remove_entry<P: AsRef<Path>>(&mut self, file_path: P) {
+        let Some((directory_path, hash)) = self.split_file_path(file_path.as_ref()) else {
+            return;
+        };
+
+        if let Some(directory) = self.directories.get_mut(directory_path.as_str()) {
+            if let Some(entry) = directory.entries.remove(hash.as_str()) {
+                // the directory has been modified
+                directory.modified = ModifiedTime::UNKNOWN;
+
+                self.unlink_tags(directory_path.as_str(), hash.as_str(), &entry.tags);
+
+                self.dirty = true;
+            }
+        }
+    }
+
+    /// Read a cache file which has just been written and put it into the index.
+    pub fn update_entry<P: AsRef<Path>>(&mut self, file_path: P) -> anyhow::Result<()> {
+        let file_path = file_path.as_ref();
+
+        let Some((directory_path, hash)) = self.split_file_path(file_path) else {
+            return Ok(());
+        };
+
+        if !is_hash(hash.as_str()) {
+            return Ok(());
+        }
+
+        let metadata = match fs::metadata(file_path) {
+            Ok(metadata) => metadata,
+            Err(error) if error.kind() == io::ErrorKind::NotFound => {
+                self.remove_entry(file_path);
+
+                return Ok(());
+            },
+            Err(error) => return Err(error).with_context(|| anyhow!("{file_path:?}")),
+        };
+
+        let modified =
+            ModifiedTime::from_metadata(&metadata).with_context(|| anyhow!("{file_path:?}"))?;
+
+        let header = match read_cache_file_header(file_path, true) {
+            Ok(header) => header,
+            Err(_) => {
+                // the file may have been removed in the meantime
+                self.remove_entry(file_path);
+
+                return Ok(());
+            },
+        };
+
+        let tags: Vec<Vec<u8>> = header.tags(&self.tag_header).map(|tag| tag.to_vec()).collect();
+
+        if let Some(old_entry) = self
+            .directories
+            .get_mut(directory_path.as_str())
+            .and_then(|directory| directory.entries.remove(hash.as_str()))
+        {
+            self.unlink_tags(directory_path.as_str(), hash.as_str(), &old_entry.tags);
+        }
+
+        self.link_tags(directory_path.as_str(), hash.as_str(), &tags);
+
+        let directory = self.directories.entry(directory_path).or_default();
+
+        // the directory has been modified
+        directory.modified = ModifiedTime::UNKNOWN;
+
+        directory.entries.insert(hash, IndexedEntry {
+            modified,
+            key: header.key,
+            tags,
+        });
+
+        self.dirty = true;
+
+        Ok(())
+    }
+
+    /// Scan a last-level directory which has just been created and put its cache files into the index.
+    pub fn update_directory<S: Into<String>>(&mut self, directory_path: S) -> anyhow::Result<()> {
+        let directory_path = directory_path.into();
+
+        let old_directory = self.directories.remove(&directory_path).unwrap_or_default();
+
+        for (hash, entry) in old_directory.entries.iter() {
+            self.unlink_tags(directory_path.as_str(), hash.as_str(), &entry.tags);
+        }
+
+        if let Some((directory, _)) = self.scan_directory(directory_path.as_str(), old_directory)? {
+            for (hash, entry) in directory.entries.iter() {
+                self.link_tags(directory_path.as_str(), hash.as_str(), &entry.tags);
+            }
+
+            self.directories.insert(directory_path, directory);
+        }
+
+        self.dirty = true;
+
+        Ok(())
+    }
+
+    /// Remove a level directory (of any level) which has been deleted, and everything under it, from the index.
+    pub fn remove_directory<S: AsRef<str>>(&mut self, directory_path: S) {
+        let directory_path = directory_path.as_ref();
+        let prefix = format!("{directory_path}/");
+
+        let removed_paths: Vec<String> = self
+            .directories
+            .keys()
+            .filter(|path| path.as_str() == directory_path || path.starts_with(prefix.as_str()))
+            .cloned()
+            .collect();
+
+        for path in removed_paths {
+            if let Some(directory) = self.directories.remove(&path) {
+                for (hash, entry) in directory.entries.iter() {
+                    self.unlink_tags(path.as_str(), hash.as_str(), &entry.tags);
+                }
+
+                self.dirty = true;
+            }
+        }
+    }
+
+    /// Forget all cache files after the whole cache zone has been wiped.
+    pub fn clear(&mut self) {
+        if !self.directories.is_empty() {
+            self.directories.clear();
+            self.tag_map.clear();
+
+            self.dirty = true;
+        }
+    }
+
+    /// Forget the modified times of all directories and refresh the index. This is used when changes might have been missed.
+    pub fn rescan(&mut self) -> anyhow::Result<()> {
+        for directory in self.directories.values_mut() {
+            directory.modified = ModifiedTime::UNKNOWN;
+        }
+
+        self.refresh()
+    }
+
+    /// Rescan the changed level directories and save the index if anything has changed.
+    pub fn refresh(&mut self) -> anyhow::Result<()> {
+        let mut directory_paths = Vec::new();
+
+        collect_last_level_directories(
+            self.cache_path.as_path(),
+            String::new(),
+            self.levels.len(),
+            &mut directory_paths,
+        )?;
+
+        let mut directories = HashMap::with_capacity(directory_paths.len());
+        let mut modified = directory_paths.len() != self.directories.len();
+
+        for directory_path in directory_paths {
+            let old_directory = self.directories.remove(&directory_path).unwrap_or_default();
+
+            match self.scan_directory(directory_path.as_str(), old_directory)? {
+                Some((directory, changed)) => {
+                    modified |= changed;
+
+                    directories.insert(directory_path, directory);
+                },
+                None => modified = true,
+            }
+        }
+
+        self.directories = directories;
+
+        if modified {
+            self.rebuild_tag_map();
+
+            self.dirty = true;
+        }
+
+        self.save()
+    }
+
+    /// Write the index into its file if it is dirty.
+    pub fn save(&mut self) -> anyhow::Result<()> {
+        if !self.dirty {
+            return Ok(());
+        }
+
+        let index_file_path = self.index_file_path.as_path();
+        let temp_file_path = index_file_path.with_extension("tmp");
+
+        {
+            let file =
+                File::create(&temp_file_path).with_context(|| anyhow!("{temp_file_path:?}"))?;
+
+            let mut writer = BufWriter::new(file);
+
+            self.write(&mut writer)
+                .and_then(|_| writer.flush())
+                .with_context(|| anyhow!("{temp_file_path:?}"))?;
+        }
+
+        fs::rename(&temp_file_path, index_file_path)
+            .with_context(|| anyhow!("{index_file_path:?}"))?;
+
+        self.dirty = false;
+
+        Ok(())
+    }
+
+    /// Return `None` if the directory no longer exists. The `bool` indicates whether the directory has changed.
+    fn scan_directory(
+        &self,
+        directory_path: &str,
+        mut old_directory: IndexedDirectory,
+    ) -> anyhow::Result<Option<(IndexedDirectory, bool)>> {
+        let path = self.cache_path.join(directory_path);
+
+        let modified = match fs::metadata(&path) {
+            Ok(metadata) => {
+                ModifiedTime::from_metadata(&metadata).with_context(|| anyhow!("{path:?}"))?
+            },
+            Err(error) if error.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(error) => return Err(error).with_context(|| anyhow!("{path:?}")),
+        };
+
+        if modified == old_directory.modified {
+            return Ok(Some((old_directory, false)));
+        }
+
+        let mut directory = IndexedDirectory {
+            modified: if modified.is_stable() { modified } else { ModifiedTime::UNKNOWN },
+            entries:  HashMap::new(),
+        };
+
+        let read_dir = match path.read_dir() {
+            Ok(read_dir) => read_dir,
+            Err(error) if error.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(error) => return Err(error).with_context(|| anyhow!("{path:?}")),
+        };
+
+        for dir_entry in read_dir {
+            let dir_entry = dir_entry.with_context(|| anyhow!("{path:?}"))?;
+
+            let hash = match dir_entry.file_name().into_string() {
+                Ok(hash) if is_hash(hash.as_str()) => hash,
+                // temporary files or something not created by nginx
+                _ => continue,
+            };
+
+            let metadata = match dir_entry.metadata() {
+                Ok(metadata) if metadata.is_file() => metadata,
+                Ok(_) => continue,
+                Err(error) if error.kind() == io::ErrorKind::NotFound => continue,
+                Err(error) => return Err(error).with_context(|| anyhow!("{dir_entry:?}")),
+            };
+
+            let file_modified =
+                ModifiedTime::from_metadata(&metadata).with_context(|| anyhow!("{dir_entry:?}"))?;
+
+            if let Some(old_entry) = old_directory.entries.remove(&hash) {
+                if old_entry.modified == file_modified {
+                    directory.entries.insert(hash, old_entry);
+
+                    continue;
+                }
+            }
+
+            match read_cache_file_header(dir_entry.path(), true) {
+                Ok(header) => {
+                    let tags = header.tags(&self.tag_header).map(|tag| tag.to_vec()).collect();
+
+                    directory.entries.insert(hash, IndexedEntry {
+                        modified: file_modified,
+                        key: header.key,
+                        tags,
+                    });
+                },
+                Err(_) => {
+                    // the file may be being written or removed, try it again next time
+                    directory.modified = ModifiedTime::UNKNOWN;
+                },
+            }
+        }
+
+        Ok(Some((directory, true)))
+    }
+
+    fn rebuild_tag_map(&mut self) {
+        self.tag_map.clear();
+
+        for (directory_path, directory) in self.directories.iter() {
+            for (hash, entry) in directory.entries.iter() {
+                for tag in entry.tags.iter() {
+                    self.tag_map
+                        .entry(tag.clone())
+                        .or_default()
+                        .insert(format!("{directory_path}/{hash}"));
+                }
+            }
+        }
+    }
+
+    fn link_tags(&mut self, directory_path: &str, hash: &str, tags: &[Vec<u8>]) {
+        for tag in tags {
+            self.tag_map.entry(tag.clone()).or_default().insert(format!("{directory_path}/{hash}"));
+        }
+    }
+
+    fn unlink_tags(&mut self, directory_path: &str, hash: &str, tags: &[Vec<u8>]) {
+        let relative_path = format!("{directory_path}/{hash}");
+
+        for tag in tags {
+            if let Some(paths) = self.tag_map.get_mut(tag) {
+                paths.remove(&relative_path);
+
+                if paths.is_empty() {
+                    self.tag_map.remove(tag);
+                }
+            }
+        }
+    }
+
+    /// Split the absolute path of a cache file into the relative path of its directory and its file name.
+    fn split_file_path(&self, file_path: &Path) -> Option<(String, String)> {
+        let hash = file_path.file_name()?.to_str()?;
+        let directory_path = file_path.parent()?.strip_prefix(&self.cache_path).ok()?.to_str()?;
+
+        Some((directory_path.to_string(), hash.to_string()))
+    }
+
+    fn load(&mut self, file: File) -> anyhow::Result<()> {
+        let mut lines = BufReader::new(file).lines();
+
+        let mut next_line = || -> anyhow::Result<Option<String>> { Ok(lines.next().transpose()?) };
+
+        if next_line()?.as_deref() != Some(INDEX_FILE_SIGNATURE) {
+            return Err(anyhow!("The signature of the index file is incorrect."));
+        }
+
+        let header = next_line()?.ok_or(anyhow!("The index file is truncated."))?;
+        let expected_header = format!(
+            "{}\t{}\t{}",
+            escape(self.cache_path.as_os_str().as_bytes()),
+            format_levels(&self.levels),
+            escape(self.tag_header.as_bytes())
+        );
+
+        if header != expected_header {
+            return Err(anyhow!("The index file is for another cache zone."));
+        }
+
+        let mut current_directory: Option<&mut IndexedDirectory> = None;
+
+        while let Some(line) = next_line()? {
+            let mut fields = line.split('\t');
+
+            match fields.next() {
+                Some("D") => {
+                    let (Some(directory_path), Some(modified)) = (fields.next(), fields.next())
+                    else {
+                        return Err(anyhow!("The index file is broken."));
+                    };
+
+                    let directory = self.directories.entry(directory_path.to_string()).or_default();
+
+                    directory.modified = parse_modified_time(modified)?;
+
+                    current_directory = Some(directory);
+                },
+                Some("E") => {
+                    let (Some(directory), Some(hash), Some(modified), Some(key), Some(tags)) = (
+                        current_directory.as_deref_mut(),
+                        fields.next(),
+                        fields.next(),
+                        fields.next(),
+                        fields.next(),
+                    ) else {
+                        return Err(anyhow!("The index file is broken."));
+                    };
+
+                    let tags = if tags.is_empty() {
+                        Vec::new()
+                    } else {
+                        tags.split(' ').map(unescape).collect::<anyhow::Result<_>>()?
+                    };
+
+                    directory.entries.insert(hash.to_string(), IndexedEntry {
+                        modified: parse_modified_time(modified)?,
+                        key: unescape(key)?,
+                        tags,
+                    });
+                },
+                _ => return Err(anyhow!("The index file is broken.")),
+            }
+        }
+
+        Ok(())
+    }
+
+    fn write<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        writeln!(writer, "{INDEX_FILE_SIGNATURE}")?;
+        writeln!(
+            writer,
+            "{}\t{}\t{}",
+            escape(self.cache_path.as_os_str().as_bytes()),
+            format_levels(&self.levels),
+            escape(self.tag_header.as_bytes())
+        )?;
+
+        for (directory_path, directory) in self.directories.iter() {
+            writeln!(
+                writer,
+                "D\t{directory_path}\t{}.{}",
+                directory.modified.secs, directory.modified.nanos
+            )?;
+
+            for (hash, entry) in directory.entries.iter() {
+                let tags = entry.tags.iter().map(|tag| escape(tag)).collect::<Vec<_>>().join(" ");
+
+                writeln!(
+                    writer,
+                    "E\t{hash}\t{}.{}\t{}\t{tags}",
+                    entry.modified.secs,
+                    entry.modified.nanos,
+                    escape(&entry.key)
+                )?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+type SharedCacheIndexes = HashMap<(PathBuf, Vec<usize>), Arc<Mutex<CacheIndex>>>;
+
+/// The indexes of cache zones maintained by the server. Each index is created when its cache zone is purged for the first time.
+#[derive(Debug)]
+pub struct IndexStore {
+    index_dir:  PathBuf,
+    tag_header: String,
+    indexes:    Mutex<SharedCacheIndexes>,
+}
+
+impl IndexStore {
+    pub fn new<D: Into<PathBuf>, H: Into<String>>(index_dir: D, tag_header: H) -> Self {
+        IndexStore {
+            index_dir:  index_dir.into(),
+            tag_header: tag_header.into(),
+            indexes:    Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Get the index of a cache zone. Return `None` if the cache zone does not exist.
+    pub async fn get<P: AsRef<Path>, L: AsRef<str>>(
+        &self,
+        cache_path: P,
+        levels: L,
+    ) -> anyhow::Result<Option<Arc<Mutex<CacheIndex>>>> {
+        let cache_path = cache_path.as_ref().to_path_buf();
+
+        let canonicalized = {
+            let cache_path = cache_path.clone();
+
+            unblock(move || {
+                cache_path.canonicalize().map(|path| {
+                    let is_dir = path.is_dir();
+
+                    (path, is_dir)
+                })
+            })
+            .await
+        };
+
+        let cache_path = match canonicalized {
+            Ok((path, is_dir)) => {
+                if !is_dir {
+                    return Err(anyhow!("{cache_path:?} is not a directory."));
+                }
+
+                path
+            },
+            Err(error) if error.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(error) => return Err(error).with_context(|| anyhow!("{cache_path:?}")),
+        };
+
+        let levels = match resolve_levels(cache_path.as_path(), levels).await? {
+            Some(levels) => levels,
+            None => return Ok(None),
+        };
+
+        let zone = (cache_path, levels);
+
+        if let Some(index) = self.indexes.lock().await.get(&zone) {
+            return Ok(Some(index.clone()));
+        }
+
+        // the index file is read without holding the lock, so that the other cache zones are not blocked
+        let index = {
+            let index_dir = self.index_dir.clone();
+            let (cache_path, levels) = zone.clone();
+            let tag_header = self.tag_header.clone();
+
+            tokio::task::spawn_blocking(move || {
+                CacheIndex::open(index_dir, cache_path, levels, tag_header)
+            })
+            .await??
+        };
+
+        // another request may have opened the same index in the meantime
+        let index = self
+            .indexes
+            .lock()
+            .await
+            .entry(zone)
+            .or_insert_with(|| Arc::new(Mutex::new(index)))
+            .clone();
+
+        Ok(Some(index))
+    }
+}
+
+fn collect_last_level_directories(
+    path: &Path,
+    relative_path: String,
+    remaining_levels: usize,
+    output: &mut Vec<String>,
+) -> anyhow::Result<()> {
+    if remaining_levels == 0 {
+        output.push(relative_path);
+
+        return Ok(());
+    }
+
+    let read_dir = match path.read_dir() {
+        Ok(read_dir) => read_dir,
+        Err(error) if error.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(error) => return Err(error).with_context(|| anyhow!("{path:?}")),
+    };
+
+    for dir_entry in read_dir {
+        let dir_entry = dir_entry.with_context(|| anyhow!("{path:?}"))?;
+
+        let file_type = match dir_entry.file_type() {
+            Ok(file_type) => file_type,
+            Err(error) if error.kind() == io::ErrorKind::NotFound => continue,
+            Err(error) => return Err(error).with_context(|| anyhow!("{dir_entry:?}")),
+        };
+
+        if !file_type.is_dir() {
+            continue;
+        }
+
+        let Ok(name) = dir_entry.file_name().into_string() else {
+            continue;
+        };
+
+        let relative_path =
+            if relative_path.is_empty() { name } else { format!("{relative_path}/{name}") };
+
+        collect_last_level_directories(
+            dir_entry.path().as_path(),
+            relative_path,
+            remaining_levels - 1,
+            output,
+        )?;
+    }
+
+    Ok(())
+}
+
+#[inline]
+fn is_hash(s: &str) -> bool {
+    s.len() == 32 && s.bytes().all(|u| u.is_ascii_hexdigit())
+}
+
+#[inline]
+fn create_index_file_name(cache_path: &Path, levels: &[usize]) -> String {
+    let mut hasher = Md5::new();
+    hasher.update(cache_path.as_os_str().as_bytes());
+    hasher.update(format_levels(levels));
+
+    let value = u128::from_be_bytes(hasher.finalize().into());
+
+    format!("{value:032x}.idx")
+}
+
+#[inline]
+fn parse_modified_time(s: &str) -> anyhow::Result<ModifiedTime> {
+    let (secs, nanos) = s.split_once('.').ok_or(anyhow!("The index file is broken."))?;
+
+    Ok(ModifiedTime {
+        secs: secs.parse()?, nanos: nanos.parse()?
+    })
+}
+
+/// Percent-encode the bytes which are not visible ASCII characters or which are `%`, so that the result contains no whitespaces.
+fn escape(data: &[u8]) -> String {
+    let mut s = String::with_capacity(data.len());
+
+    for &u in data {
+        if u.is_ascii_graphic() && u != b'%' {
+            s.push(u as char);
+        } else {
+            s.push_str(&format!("%{u:02X}"));
+        }
+    }
+
+    s
+}
+
+fn unescape(s: &str) -> anyhow::Result<Vec<u8>> {
+    let bytes = s.as_bytes();
+    let mut data = Vec::with_capacity(bytes.len());
+
+    let mut i = 0;
+
+    while i < bytes.len() {
+        if bytes[i] == b'%' {
+            let hex = s.get(i + 1..i + 3).ok_or(anyhow!("The index file is broken."))?;
+
+            data.push(u8::from_str_radix(hex, 16)?);
+
+            i += 3;
+        } else {
+            data.push(bytes[i]);
+
+            i += 1;
+        }
+    }
+
+    Ok(data)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn save_and_load() {
+        let index_dir =
+            std::env::temp_dir().join(format!("nginx-cache-purge-index-{}", std::process::id()));
+
+        fs::create_dir_all(&index_dir).unwrap();
+
+        let cache_path = Path::new("/var/cache/nginx cache");
+        let hash = "0123456789abcdef0123456789abcdef";
+
+        let mut index = CacheIndex::open(&index_dir, cache_path, [1, 2], "Cache-Tag").unwrap();
+
+        index.directories.insert("f/ed".to_string(), IndexedDirectory {
+            modified: ModifiedTime {
+                secs: 1, nanos: 2
+            },
+            entries:  HashMap::from([(hash.to_string(), IndexedEntry {
+                modified: ModifiedTime {
+                    secs: 3, nanos: 4
+                },
+                key:      b"httpGET example.com/a b%\t\xff".to_vec(),
+                tags:     vec![b"blog".to_vec(), b"a b%".to_vec()],
+            })]),
+        });
+        index.rebuild_tag_map();
+        index.save().unwrap();
+
+        let index = CacheIndex::open(&index_dir, cache_path, [1, 2], "Cache-Tag").unwrap();
+
+        let entries: Vec<_> = index.entries().collect();
+
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].0, cache_path.join("f/ed").join(hash));
+        assert_eq!(entries[0].1.modified, ModifiedTime {
+            secs: 3, nanos: 4
+        });
+        assert_eq!(entries[0].1.key, b"httpGET example.com/a b%\t\xff");
+        assert_eq!(entries[0].1.tags, [b"blog".to_vec(), b"a b%".to_vec()]);
+        assert_eq!(index.directories["f/ed"].modified, ModifiedTime {
+            secs: 1, nanos: 2
+        });
+        assert_eq!(index.files_with_tag(b"a b%").collect::<Vec<_>>(), [entries[0].0.clone()]);
+        assert!(!index.dirty);
+
+        // an index of another tag header is rebuilt
+        let index = CacheIndex::open(&index_dir, cache_path, [1, 2], "X-Tag").unwrap();
+
+        assert_eq!(index.entries().count(), 0);
+        assert!(index.dirty);
+
+        fs::remove_dir_all(&index_dir).unwrap();
+    }
+
+    #[test]
+    fn escape_and_unescape() {
+        let data = b"a b%c\t\n\xff~";
+
+        assert_eq!(escape(data), "a%20b%25c%09%0A%FF~");
+        assert_eq!(unescape(&escape(data)).unwrap(), data);
+        assert!(unescape("a%2").is_err());
+        assert!(unescape("a%zz").is_err());
+    }
+}
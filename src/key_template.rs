@@ -0,0 +1,381 @@
+use anyhow::anyhow;
+
+use crate::matcher::{percent_decode, MatchOptions};
+
+/// A request whose cache key is rendered by a `KeyTemplate`.
+#[derive(Debug)]
+pub struct KeyRequest {
+    method:      String,
+    scheme:      String,
+    host:        String,
+    port:        Option<u16>,
+    /// the path and the query, as they are in the URL
+    request_uri: String,
+    headers:     Vec<(String, String)>,
+}
+
+impl KeyRequest {
+    /// Parse an absolute URL like `https://example.com/path?a=1`. Each header is like `Name: value`.
+    pub fn new<U: AsRef<str>, M: Into<String>, H: AsRef<str>>(
+        url: U,
+        method: M,
+        headers: &[H],
+    ) -> anyhow::Result<Self> {
+        let url = url.as_ref();
+
+        let (scheme, rest) =
+            url.split_once("://").ok_or_else(|| anyhow!("The URL {url:?} is not absolute."))?;
+
+        if scheme.is_empty() || !scheme.bytes().all(|u| u.is_ascii_alphanumeric()) {
+            return Err(anyhow!("The scheme of the URL {url:?} is incorrect."));
+        }
+
+        let (authority, request_uri) = match rest.find(['/', '?']) {
+            Some(index) => (&rest[..index], &rest[index..]),
+            None => (rest, "/"),
+        };
+
+        // strip the user info
+        let authority = authority.rsplit_once('@').map(|(_, a)| a).unwrap_or(authority);
+
+        let (host, port) = match authority.rsplit_once(':') {
+            Some((host, port)) if !port.contains(']') => {
+                let port = port
+                    .parse()
+                    .map_err(|_| anyhow!("The port of the URL {url:?} is incorrect."))?;
+
+                (host, Some(port))
+            },
+            _ => (authority, None),
+        };
+
+        if host.is_empty() {
+            return Err(anyhow!("The URL {url:?} has no host."));
+        }
+
+        let request_uri = if request_uri.starts_with('?') {
+            format!("/{request_uri}")
+        } else {
+            request_uri.to_string()
+        };
+
+        // the fragment is never sent
+        let request_uri = match request_uri.split_once('#') {
+            Some((request_uri, _)) => request_uri.to_string(),
+            None => request_uri,
+        };
+
+        let headers = headers
+            .iter()
+            .map(|header| {
+                let header = header.as_ref();
+
+                header
+                    .split_once(':')
+                    .map(|(name, value)| (name.trim().to_string(), value.trim().to_string()))
+                    .ok_or_else(|| anyhow!("The header {header:?} should be like `Name: value`."))
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?;
+
+        Ok(KeyRequest {
+            method: method.into().to_ascii_uppercase(),
+            scheme: scheme.to_ascii_lowercase(),
+            host: host.to_ascii_lowercase(),
+            port,
+            request_uri,
+            headers,
+        })
+    }
+
+    #[inline]
+    fn path(&self) -> &str {
+        self.request_uri.split_once('?').map(|(path, _)| path).unwrap_or(&self.request_uri)
+    }
+
+    #[inline]
+    fn query(&self) -> Option<&str> {
+        self.request_uri.split_once('?').map(|(_, query)| query)
+    }
+
+    #[inline]
+    fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+/// A variable supported in key templates.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Variable {
+    Scheme,
+    Host,
+    ServerPort,
+    RequestMethod,
+    RequestUri,
+    Uri,
+    Args,
+    IsArgs,
+    /// `$arg_NAME`
+    Arg(String),
+    /// `$http_NAME`
+    Http(String),
+    /// `$cookie_NAME`
+    Cookie(String),
+}
+
+impl Variable {
+    fn parse(name: &str) -> anyhow::Result<Self> {
+        let variable = match name {
+            "scheme" => Variable::Scheme,
+            "host" => Variable::Host,
+            "server_port" => Variable::ServerPort,
+            "request_method" => Variable::RequestMethod,
+            "request_uri" => Variable::RequestUri,
+            "uri" | "document_uri" => Variable::Uri,
+            "args" | "query_string" => Variable::Args,
+            "is_args" => Variable::IsArgs,
+            _ => {
+                if let Some(arg) = name.strip_prefix("arg_") {
+                    Variable::Arg(arg.to_string())
+                } else if let Some(header) = name.strip_prefix("http_") {
+                    // `$http_x_real_ip` is the `X-Real-IP` header
+                    Variable::Http(header.replace('_', "-"))
+                } else if let Some(cookie) = name.strip_prefix("cookie_") {
+                    Variable::Cookie(cookie.to_string())
+                } else {
+                    return Err(anyhow!(
+                        "The variable ${name} is not supported in key templates. Supported \
+                         variables are $scheme, $host, $server_port, $request_method, \
+                         $request_uri, $uri, $document_uri, $args, $query_string, $is_args, \
+                         $arg_*, $http_* and $cookie_*."
+                    ));
+                }
+            },
+        };
+
+        Ok(variable)
+    }
+
+    fn render(&self, request: &KeyRequest, output: &mut String) {
+        match self {
+            Variable::Scheme => output.push_str(&request.scheme),
+            Variable::Host => output.push_str(&request.host),
+            Variable::ServerPort => {
+                let port = request.port.unwrap_or(if request.scheme == "https" { 443 } else { 80 });
+
+                output.push_str(&port.to_string());
+            },
+            Variable::RequestMethod => output.push_str(&request.method),
+            Variable::RequestUri => output.push_str(&request.request_uri),
+            Variable::Uri => {
+                // nginx decodes the path and merges duplicate slashes
+                let mut path = percent_decode(request.path().as_bytes());
+
+                path.dedup_by(|a, b| *a == b'/' && *b == b'/');
+
+                output.push_str(&String::from_utf8_lossy(&path));
+            },
+            Variable::Args => output.push_str(request.query().unwrap_or_default()),
+            Variable::IsArgs => {
+                if request.query().is_some() {
+                    output.push('?');
+                }
+            },
+            Variable::Arg(name) => {
+                let value = request.query().and_then(|query| {
+                    query.split('&').find_map(|pair| {
+                        let (n, v) = pair.split_once('=').unwrap_or((pair, ""));
+
+                        n.eq_ignore_ascii_case(name).then_some(v)
+                    })
+                });
+
+                output.push_str(value.unwrap_or_default());
+            },
+            Variable::Http(name) => output.push_str(request.header(name).unwrap_or_default()),
+            Variable::Cookie(name) => {
+                let value = request.header("Cookie").and_then(|cookies| {
+                    cookies.split(';').find_map(|cookie| {
+                        let (n, v) = cookie.trim().split_once('=')?;
+
+                        n.eq_ignore_ascii_case(name).then_some(v)
+                    })
+                });
+
+                output.push_str(value.unwrap_or_default());
+            },
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+enum TemplatePart {
+    Literal(String),
+    Variable(Variable),
+}
+
+/// A cache key template written in the same syntax as `proxy_cache_key`, like `$scheme$host$request_uri`.
+#[derive(Debug, Clone)]
+pub struct KeyTemplate {
+    parts: Vec<TemplatePart>,
+}
+
+impl KeyTemplate {
+    pub fn parse<T: AsRef<str>>(template: T) -> anyhow::Result<Self> {
+        let template = template.as_ref();
+
+        let mut parts = Vec::new();
+        let mut literal = String::new();
+        let mut chars = template.char_indices().peekable();
+
+        while let Some((_, c)) = chars.next() {
+            if c != '$' {
+                literal.push(c);
+
+                continue;
+            }
+
+            let braced = chars.next_if(|(_, c)| *c == '{').is_some();
+
+            let mut name = String::new();
+
+            while let Some((_, c)) = chars.next_if(|(_, c)| c.is_ascii_alphanumeric() || *c == '_')
+            {
+                name.push(c);
+            }
+
+            if braced && chars.next_if(|(_, c)| *c == '}').is_none() {
+                return Err(anyhow!("A `{{` is not closed in the key template {template:?}."));
+            }
+
+            if name.is_empty() {
+                return Err(anyhow!(
+                    "A variable name is missing in the key template {template:?}."
+                ));
+            }
+
+            if !literal.is_empty() {
+                parts.push(TemplatePart::Literal(std::mem::take(&mut literal)));
+            }
+
+            parts.push(TemplatePart::Variable(Variable::parse(&name)?));
+        }
+
+        if !literal.is_empty() {
+            parts.push(TemplatePart::Literal(literal));
+        }
+
+        Ok(KeyTemplate {
+            parts,
+        })
+    }
+
+    /// Render the cache key of a request.
+    pub fn render(&self, request: &KeyRequest) -> String {
+        let mut key = String::new();
+
+        for part in self.parts.iter() {
+            match part {
+                TemplatePart::Literal(literal) => key.push_str(literal),
+                TemplatePart::Variable(variable) => variable.render(request, &mut key),
+            }
+        }
+
+        key
+    }
+}
+
+/// Render the cache keys of `urls` and escape them so that they are purged exactly with `options`.
+pub fn render_keys<U: AsRef<str>, H: AsRef<str>>(
+    template: &KeyTemplate,
+    urls: &[U],
+    method: &str,
+    headers: &[H],
+    options: &MatchOptions,
+) -> anyhow::Result<Vec<String>> {
+    urls.iter()
+        .map(|url| {
+            let request = KeyRequest::new(url, method, headers)?;
+
+            Ok(options.escape(template.render(&request)))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::matcher::MatchMode;
+
+    fn render(template: &str, url: &str, headers: &[&str]) -> String {
+        let request = KeyRequest::new(url, "get", headers).unwrap();
+
+        KeyTemplate::parse(template).unwrap().render(&request)
+    }
+
+    #[test]
+    fn render_variables() {
+        assert_eq!(
+            render(
+                "$scheme$host$request_uri",
+                "HTTPS://user@Example.com:8443/a//b%20c?x=1#top",
+                &[]
+            ),
+            "httpsexample.com/a//b%20c?x=1"
+        );
+        assert_eq!(
+            render(
+                "$request_method ${host}:$server_port$uri$is_args$args",
+                "http://a.com//b%20c",
+                &[]
+            ),
+            "GET a.com:80/b c"
+        );
+        assert_eq!(render("$server_port", "https://a.com", &[]), "443");
+        assert_eq!(render("$request_uri", "http://a.com?x=1", &[]), "/?x=1");
+        assert_eq!(render("$arg_ID|$arg_x|$arg_y", "http://a.com/?id=7&x", &[]), "7||");
+        assert_eq!(
+            render("$http_x_real_ip|$cookie_lang|$http_accept", "http://a.com/", &[
+                "X-Real-IP: 1.2.3.4",
+                "Cookie: a=b; LANG=en"
+            ]),
+            "1.2.3.4|en|"
+        );
+    }
+
+    #[test]
+    fn invalid_templates() {
+        assert!(KeyTemplate::parse("$proxy_host$uri").is_err());
+        assert!(KeyTemplate::parse("${host").is_err());
+        assert!(KeyTemplate::parse("$host$").is_err());
+        assert!(KeyTemplate::parse("no variables").is_ok());
+    }
+
+    #[test]
+    fn invalid_requests() {
+        assert!(KeyRequest::new("example.com/a", "GET", &[] as &[&str]).is_err());
+        assert!(KeyRequest::new("ht-tp://example.com/", "GET", &[] as &[&str]).is_err());
+        assert!(KeyRequest::new("http://example.com:x/", "GET", &[] as &[&str]).is_err());
+        assert!(KeyRequest::new("http:///a", "GET", &[] as &[&str]).is_err());
+        assert!(KeyRequest::new("http://example.com/", "GET", &["X-Real-IP"]).is_err());
+        assert!(KeyRequest::new("http://[::1]/", "GET", &[] as &[&str]).is_ok());
+    }
+
+    #[test]
+    fn render_escaped_keys() {
+        let template = KeyTemplate::parse("$host$request_uri").unwrap();
+
+        let keys = render_keys(
+            &template,
+            &["http://a.com/*?x", "http://b.com/"],
+            "GET",
+            &[] as &[&str],
+            &MatchOptions::from(MatchMode::Glob),
+        )
+        .unwrap();
+
+        assert_eq!(keys, [r"a.com/\*\?x", "b.com/"]);
+    }
+}
@@ -2,4 +2,110 @@
 # Nginx Cache Purge
 
 An alternative way to do `proxy_cache_purge` or `fastcgi_cache_purge` for Nginx.
+
+Besides the CLI, this crate can be used as a library. [`CacheZone`] purges and inspects the caches of a cache zone, and walks its cache entries lazily as a `Stream` or an `Iterator`. Every purge also has a blocking equivalent, such as [`CacheZone::purge_key_blocking`], for the programs without a Tokio runtime. With the `ffi` feature, the `ffi` module exports a C ABI for purging in-process, such as from OpenResty. Disable the default features (`async`, `cli` and `service`) so that Tokio, clap and axum are not needed. The async methods, `EntryStream`, and the `check`, `migrate` and `trash` operations which run on Tokio need the `async` feature.
+
+How a `CacheZone` purges can be set with [`PurgeOptions`], such as the key patterns which are never purged, a trash which the removed caches are moved into, and a dry run which only prints the files to be removed.
 */
+
+pub mod cache_file;
+pub mod check;
+pub mod error;
+#[cfg(feature = "ffi")]
+pub mod ffi;
+pub mod functions;
+#[cfg(feature = "service")]
+pub mod index;
+pub mod key_template;
+pub mod matcher;
+#[cfg(feature = "async")]
+pub mod migrate;
+pub mod nginx_conf;
+pub mod protect;
+#[cfg(feature = "service")]
+pub mod rewrite;
+pub mod safety;
+#[cfg(feature = "service")]
+pub mod server;
+#[cfg(test)]
+mod test_zone;
+pub mod trash;
+#[cfg(feature = "service")]
+mod uds_serve;
+pub mod walk;
+#[cfg(all(feature = "service", target_os = "linux"))]
+mod watcher;
+mod zone;
+
+use std::path::PathBuf;
+
+pub use cache_file::CacheFileHeader;
+pub use error::PurgeError;
+pub use functions::PurgeOptions;
+pub use matcher::{MatchMode, MatchOptions};
+#[cfg(feature = "async")]
+pub use walk::EntryStream;
+pub use walk::{EntryIter, WalkOptions};
+pub use zone::{CacheEntry, CacheZone};
+
+/// The result of a purge which does not fail.
+#[derive(Debug)]
+pub enum AppResult {
+    /// Any cache has been purged.
+    Ok,
+    /// The cache file of the exact key does not exist.
+    AlreadyPurged(PathBuf),
+    /// The cache is excluded by the exclude keys or the protected keys.
+    CacheIgnored,
+    /// No cache matches the key patterns.
+    AlreadyPurgedWildcard,
+    /// A wildcard purge stopped after removing this number of caches.
+    TooManyEntries(usize),
+}
+
+impl AppResult {
+    /// Combine the results of purging different keys. If any purge has been stopped by the limit, the combined result is `TooManyEntries`. Otherwise, if any cache has been purged, it is `Ok`.
+    #[inline]
+    pub(crate) fn merge(self, other: AppResult) -> AppResult {
+        match (self, other) {
+            (AppResult::TooManyEntries(max_entries), _)
+            | (_, AppResult::TooManyEntries(max_entries)) => AppResult::TooManyEntries(max_entries),
+            (AppResult::Ok, _) | (_, AppResult::Ok) => AppResult::Ok,
+            (AppResult::CacheIgnored, AppResult::CacheIgnored) => AppResult::CacheIgnored,
+            _ => AppResult::AlreadyPurgedWildcard,
+        }
+    }
+}
+
+impl AppResult {
+    /// The exit status of the CLI.
+    #[inline]
+    pub fn exit_code(&self) -> u8 {
+        match self {
+            AppResult::Ok => 0,
+            AppResult::AlreadyPurged(_) => 44,
+            AppResult::TooManyEntries(_) => 45,
+            AppResult::CacheIgnored => 46,
+            AppResult::AlreadyPurgedWildcard => 47,
+        }
+    }
+
+    /// The name of the result, like `already_purged`, which is used in the JSON summaries.
+    #[inline]
+    pub fn status(&self) -> &'static str {
+        match self {
+            AppResult::Ok => "ok",
+            AppResult::AlreadyPurged(_) => "already_purged",
+            AppResult::CacheIgnored => "cache_ignored",
+            AppResult::AlreadyPurgedWildcard => "already_purged_wildcard",
+            AppResult::TooManyEntries(_) => "too_many_entries",
+        }
+    }
+}
+
+impl From<()> for AppResult {
+    #[inline]
+    fn from(_: ()) -> Self {
+        AppResult::Ok
+    }
+}
@@ -1,100 +1,664 @@
 mod cli;
-mod functions;
-#[cfg(feature = "service")]
-mod server;
-#[cfg(feature = "service")]
-mod uds_serve;
 
+#[cfg(feature = "async")]
+use std::future::Future;
 use std::{
-    path::{Path, PathBuf},
-    process::{ExitCode, Termination},
+    fs,
+    io::{self, Read},
+    path::Path,
+    process::ExitCode,
+    sync::Arc,
 };
 
+#[cfg(feature = "async")]
+use anyhow::anyhow;
 use cli::*;
 #[cfg(feature = "service")]
-use server::*;
+use nginx_cache_purge::rewrite::RewriteRule;
+#[cfg(feature = "service")]
+use nginx_cache_purge::server::*;
+#[cfg(feature = "async")]
+use nginx_cache_purge::{
+    check::{check_cache_zone, fix_problems, CheckAction},
+    migrate::migrate_cache_zone,
+    trash::{self, parse_duration, restore},
+};
+use nginx_cache_purge::{
+    functions,
+    key_template::{render_keys, KeyTemplate},
+    matcher::{MatchMode, MatchOptions},
+    nginx_conf::{find_zone, read_config_dump, read_config_file, CacheZoneConfig},
+    trash::{list_trash, Trash},
+    AppResult, PurgeError, PurgeOptions,
+};
+#[cfg(feature = "async")]
 use tokio::runtime;
 
-#[derive(Debug)]
-pub enum AppResult {
-    Ok,
-    AlreadyPurged(PathBuf),
-    CacheIgnored,
-    AlreadyPurgedWildcard,
-}
+/// In debug builds, the files are not removed or moved, and the operations are printed instead.
+const DRY_RUN: bool = cfg!(debug_assertions);
 
-impl From<()> for AppResult {
-    #[inline]
-    fn from(_: ()) -> Self {
-        AppResult::Ok
+/// Print the hint of the result to stderr and get the exit status.
+fn report(result: AppResult) -> ExitCode {
+    match &result {
+        AppResult::AlreadyPurged(file_path) => {
+            eprintln!("Hint: {file_path:?} does not exist");
+        },
+        AppResult::CacheIgnored => {
+            eprintln!("Warning: cache is excluded from being purged");
+        },
+        AppResult::TooManyEntries(max_entries) => {
+            eprintln!(
+                "Warning: the purge stopped after removing {max_entries} caches because of the \
+                 --max-entries option"
+            );
+        },
+        AppResult::Ok | AppResult::AlreadyPurgedWildcard => (),
     }
+
+    ExitCode::from(result.exit_code())
 }
 
-impl Termination for AppResult {
-    #[inline]
-    fn report(self) -> ExitCode {
-        let exit_code = match self {
-            AppResult::Ok => 0u8,
-            AppResult::AlreadyPurged(file_path) => {
-                eprintln!("Hint: {file_path:?} does not exist");
+/// Read a text file, or stdin if `path` is `-`. A file which is not UTF-8 is an invalid argument.
+fn read_input(path: &Path) -> Result<String, PurgeError> {
+    let read = if path.as_os_str() == "-" {
+        let mut content = String::new();
 
-                44
-            },
-            AppResult::CacheIgnored => {
-                eprintln!("Warning: cache is excluded from being purged");
+        io::stdin().read_to_string(&mut content).map(|_| content)
+    } else {
+        fs::read_to_string(path)
+    };
 
-                44
-            },
-            AppResult::AlreadyPurgedWildcard => 44,
-        };
+    read.map_err(|error| {
+        let path = if path.as_os_str() == "-" { Path::new("stdin") } else { path };
+
+        if error.kind() == io::ErrorKind::InvalidData {
+            PurgeError::InvalidArguments(format!("{path:?} is not UTF-8."))
+        } else {
+            PurgeError::io(path, error)
+        }
+    })
+}
+
+/// Read keys line by line from a file, or from stdin if `path` is `-`. Empty lines are ignored.
+fn read_keys(path: &Path) -> Result<Vec<String>, PurgeError> {
+    let content = read_input(path)?;
 
-        ExitCode::from(exit_code)
+    Ok(content
+        .lines()
+        .map(|line| line.trim_end_matches('\r'))
+        .filter(|line| !line.is_empty())
+        .map(String::from)
+        .collect())
+}
+
+/// Combine the exclude keys with the ones read from a file, or from stdin if `path` is `-`.
+fn read_exclude_keys(
+    exclude_keys: &[String],
+    path: Option<&Path>,
+) -> Result<Vec<String>, PurgeError> {
+    let mut exclude_keys = exclude_keys.to_vec();
+
+    if let Some(path) = path {
+        exclude_keys.extend(read_keys(path)?);
     }
+
+    Ok(exclude_keys)
 }
 
-#[inline]
-async fn purge<P: AsRef<Path>, L: AsRef<str>, K: AsRef<str>, EK: AsRef<str>>(
-    cache_path: P,
-    levels: L,
-    key: K,
-    exclude_keys: Vec<EK>,
-) -> anyhow::Result<AppResult> {
-    let cache_path = cache_path.as_ref();
-    let levels = levels.as_ref();
-    let key = key.as_ref();
-
-    if key.contains('*') {
-        functions::remove_caches_via_wildcard(cache_path, levels, key, exclude_keys).await
+/// Read the cache zones from an nginx configuration file, or from the output of `nginx -T` on stdin if `path` is `-`.
+fn read_nginx_conf(path: &Path) -> Result<Vec<CacheZoneConfig>, PurgeError> {
+    if path.as_os_str() == "-" {
+        read_config_dump(&read_input(path)?)
     } else {
-        functions::remove_one_cache(cache_path, levels, key, exclude_keys).await
+        read_config_file(path)
     }
 }
 
-fn main() -> anyhow::Result<AppResult> {
+/// A key template, or a URL which cannot be rendered into a key by it, is an incorrect key.
+#[inline]
+fn invalid_key(error: anyhow::Error) -> PurgeError {
+    PurgeError::InvalidKey(format!("{error:#}"))
+}
+
+/// The exit status of an error. The errors which are not typed exit with 1.
+#[inline]
+fn error_exit_code(error: &anyhow::Error) -> u8 {
+    error.downcast_ref::<PurgeError>().map(PurgeError::exit_code).unwrap_or(1)
+}
+
+/// Escape a string as a JSON string.
+fn json_string(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len() + 2);
+
+    escaped.push('"');
+
+    for c in s.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            '\t' => escaped.push_str("\\t"),
+            c if c.is_control() => escaped.push_str(&format!("\\u{:04x}", c as u32)),
+            c => escaped.push(c),
+        }
+    }
+
+    escaped.push('"');
+
+    escaped
+}
+
+/// Summarize the result of a purge in one line of JSON, like `{"status":"ok","exit_code":0}`.
+fn json_summary(result: &anyhow::Result<AppResult>, purge_id: Option<&str>) -> String {
+    let mut fields = Vec::new();
+
+    match result {
+        Ok(result) => {
+            fields.push(format!("\"status\":{}", json_string(result.status())));
+            fields.push(format!("\"exit_code\":{}", result.exit_code()));
+
+            match result {
+                AppResult::AlreadyPurged(file_path) => {
+                    fields.push(format!("\"path\":{}", json_string(&file_path.to_string_lossy())));
+                },
+                AppResult::TooManyEntries(max_entries) => {
+                    fields.push(format!("\"max_entries\":{max_entries}"));
+                },
+                _ => (),
+            }
+        },
+        Err(error) => {
+            let kind = error.downcast_ref::<PurgeError>().map(PurgeError::kind).unwrap_or("other");
+
+            fields.push(String::from("\"status\":\"error\""));
+            fields.push(format!("\"exit_code\":{}", error_exit_code(error)));
+            fields.push(format!("\"kind\":{}", json_string(kind)));
+            fields.push(format!("\"error\":{}", json_string(&format!("{error:#}"))));
+        },
+    }
+
+    if let Some(purge_id) = purge_id {
+        fields.push(format!("\"purge_id\":{}", json_string(purge_id)));
+    }
+
+    format!("{{{}}}", fields.join(","))
+}
+
+fn main() -> ExitCode {
     let args = get_args();
 
-    let runtime = runtime::Runtime::new()?;
+    let json = matches!(
+        args.command,
+        CLICommands::Purge {
+            json: true,
+            ..
+        } | CLICommands::Tag {
+            json: true,
+            ..
+        }
+    );
+
+    let mut purge_trash = None;
+
+    let mut result = run(args, &mut purge_trash);
+
+    // the purge ID is needed to restore the moved caches even if the purge fails
+    let purge_id = match purge_trash.as_deref().map(Trash::finish).transpose() {
+        Ok(purge_id) => purge_id.flatten(),
+        Err(error) => {
+            if result.is_ok() {
+                result = Err(error);
+            } else {
+                eprintln!("Warning: {error:?}");
+            }
+
+            None
+        },
+    };
+
+    if let Some(purge_id) = purge_id {
+        eprintln!("Purge ID: {purge_id}");
+    }
+
+    if json {
+        println!("{}", json_summary(&result, purge_id));
+    }
+
+    match result {
+        Ok(result) => report(result),
+        Err(error) => {
+            eprintln!("Error: {error:?}");
+
+            ExitCode::from(error_exit_code(&error))
+        },
+    }
+}
+
+/// Run a command. The trash of a purge is put into `purge_trash` so that the purge can be finished even if it fails.
+fn run(args: CLIArgs, purge_trash: &mut Option<Arc<Trash>>) -> anyhow::Result<AppResult> {
+    match &args.command {
+        CLICommands::Purge {
+            cache_path,
+            levels,
+            keys,
+            zone,
+            nginx_conf,
+            keys_from,
+            urls,
+            key_template,
+            method,
+            headers,
+            exclude_keys,
+            exclude_from,
+            regex,
+            glob,
+            ignore_host_case,
+            decode_percent,
+            collapse_slashes,
+            trash,
+            force,
+            max_entries,
+            json: _,
+        } => {
+            let options = MatchOptions {
+                mode:             if *regex {
+                    MatchMode::Regex
+                } else if *glob {
+                    MatchMode::Glob
+                } else {
+                    MatchMode::Wildcard
+                },
+                ignore_host_case: *ignore_host_case,
+                decode_percent:   *decode_percent,
+                collapse_slashes: *collapse_slashes,
+            };
 
-    runtime.block_on(async move {
-        match &args.command {
-            CLICommands::Purge {
+            let stdin_readers = [
+                keys_from.as_deref(),
+                exclude_from.as_deref(),
+                zone.as_ref().map(|_| nginx_conf.as_path()),
+            ]
+            .into_iter()
+            .filter(|path| *path == Some(Path::new("-")))
+            .count();
+
+            if stdin_readers > 1 {
+                return Err(PurgeError::InvalidArguments(String::from(
+                    "Only one of the keys, the exclude keys and the nginx configuration can be \
+                     read from stdin.",
+                ))
+                .into());
+            }
+
+            let (cache_path, levels, mut keys, zone) = match (zone, cache_path, levels) {
+                (Some(zone), ..) => {
+                    let zone = find_zone(&read_nginx_conf(nginx_conf)?, zone)?.clone();
+
+                    // all of the positional arguments are keys
+                    let keys = cache_path
+                        .iter()
+                        .map(|key| key.to_string_lossy().into_owned())
+                        .chain(levels.iter().cloned())
+                        .chain(keys.iter().cloned())
+                        .collect();
+
+                    (zone.cache_path.clone(), zone.levels()?.to_string(), keys, Some(zone))
+                },
+                (None, Some(cache_path), Some(levels)) => {
+                    (cache_path.clone(), levels.clone(), keys.clone(), None)
+                },
+                _ => unreachable!(),
+            };
+
+            if let Some(keys_from) = keys_from {
+                keys.extend(read_keys(keys_from)?);
+            }
+
+            if !urls.is_empty() {
+                let key_template = match (key_template, zone.as_ref()) {
+                    (Some(key_template), _) => {
+                        KeyTemplate::parse(key_template).map_err(invalid_key)?
+                    },
+                    (None, Some(zone)) => {
+                        KeyTemplate::parse(zone.cache_key()?).map_err(invalid_key)?
+                    },
+                    (None, None) => {
+                        return Err(PurgeError::InvalidArguments(String::from(
+                            "A key template is needed to purge URLs.",
+                        ))
+                        .into())
+                    },
+                };
+
+                keys.extend(
+                    render_keys(&key_template, urls, method, headers, &options)
+                        .map_err(invalid_key)?,
+                );
+            }
+
+            if keys.is_empty() {
+                return Err(PurgeError::InvalidArguments(String::from(
+                    "At least one key or URL is needed.",
+                ))
+                .into());
+            }
+
+            let exclude_keys = read_exclude_keys(exclude_keys, exclude_from.as_deref())?;
+
+            if let Some(trash) = trash {
+                *purge_trash = Trash::create(trash, cache_path.as_path())?.map(Arc::new);
+            }
+
+            Ok(functions::remove_caches_blocking(
                 cache_path,
                 levels,
-                key,
+                keys,
                 exclude_keys,
+                options,
+                &PurgeOptions {
+                    max_entries: *max_entries,
+                    force: *force,
+                    trash: purge_trash.clone(),
+                    dry_run: DRY_RUN,
+                    ..PurgeOptions::default()
+                },
+            )?)
+        },
+        #[cfg(feature = "async")]
+        CLICommands::Check {
+            cache_path,
+            levels,
+            remove,
+            quarantine,
+        } => block_on(async move {
+            let action = match quarantine {
+                Some(quarantine) => CheckAction::Quarantine(quarantine.clone()),
+                None if *remove => CheckAction::Remove,
+                None => CheckAction::Report,
+            };
+
+            let report = check_cache_zone(cache_path, levels, &action).await?;
+
+            for (path, problem) in report.problems.iter() {
+                println!("{path:?}: {problem}");
+            }
+
+            fix_problems(cache_path, &report, &action).await?;
+
+            println!(
+                "{} files are checked and {} of them have problems.",
+                report.checked,
+                report.problems.len()
+            );
+
+            if report.problems.is_empty() || !matches!(action, CheckAction::Report) {
+                Ok(AppResult::Ok)
+            } else {
+                Err(anyhow!("The cache zone {cache_path:?} has problems."))
+            }
+        }),
+        #[cfg(feature = "async")]
+        CLICommands::Migrate {
+            cache_path,
+            levels,
+            new_levels,
+            to,
+        } => block_on(async move {
+            let new_cache_path = to.as_deref().unwrap_or(cache_path.as_path());
+
+            let report = migrate_cache_zone(cache_path, levels, new_cache_path, new_levels).await?;
+
+            for path in report.skipped.iter() {
+                eprintln!("Warning: {path:?} is skipped because its header cannot be parsed");
+            }
+
+            println!("{} caches are migrated.", report.migrated);
+
+            Ok(AppResult::Ok)
+        }),
+        CLICommands::Tag {
+            cache_path,
+            levels,
+            tags,
+            tag_header,
+            exclude_keys,
+            exclude_from,
+            trash,
+            max_entries,
+            json: _,
+        } => {
+            let exclude_keys = read_exclude_keys(exclude_keys, exclude_from.as_deref())?;
+
+            if let Some(trash) = trash {
+                *purge_trash = Trash::create(trash, cache_path)?.map(Arc::new);
+            }
+
+            Ok(functions::remove_caches_via_tags_blocking(
+                cache_path,
+                levels,
+                tag_header.as_str(),
+                tags.iter().map(|s| s.as_str()).collect::<Vec<&str>>(),
+                exclude_keys,
+                &PurgeOptions {
+                    max_entries: *max_entries,
+                    trash: purge_trash.clone(),
+                    dry_run: DRY_RUN,
+                    ..PurgeOptions::default()
+                },
+            )?)
+        },
+        #[cfg(feature = "async")]
+        CLICommands::Restore {
+            purge_id,
+            trash,
+        } => block_on(async move {
+            let report = restore(trash, purge_id).await?;
+
+            if report.skipped > 0 {
+                eprintln!(
+                    "Warning: {} caches are skipped because nginx has recreated them",
+                    report.skipped
+                );
+            }
+
+            println!("{} caches are restored.", report.restored);
+
+            Ok(AppResult::Ok)
+        }),
+        CLICommands::Trash {
+            command,
+        } => match command {
+            TrashCommands::List {
+                trash,
             } => {
-                purge(
-                    cache_path,
-                    levels,
-                    key,
-                    exclude_keys.iter().map(|s| s.as_str()).collect::<Vec<&str>>(),
-                )
-                .await
+                for trash_entry in list_trash(trash)? {
+                    let created = trash_entry
+                        .created
+                        .duration_since(std::time::UNIX_EPOCH)
+                        .unwrap_or_default()
+                        .as_secs();
+
+                    println!(
+                        "{}\t{created}\t{}\t{:?}",
+                        trash_entry.purge_id,
+                        trash_entry.entries.len(),
+                        trash_entry.cache_path
+                    );
+                }
+
+                Ok(AppResult::Ok)
             },
-            #[cfg(feature = "service")]
-            CLICommands::Start {
-                socket_file_path,
-            } => server_main(socket_file_path.as_path()).await,
-        }
-    })
+            #[cfg(feature = "async")]
+            TrashCommands::Gc {
+                trash,
+                retention,
+            } => block_on(async move {
+                let removed = trash::gc(trash, parse_duration(retention)?).await?;
+
+                for purge_id in removed.iter() {
+                    println!("Remove purge: {purge_id}");
+                }
+
+                println!("{} purges are removed from the trash.", removed.len());
+
+                Ok(AppResult::Ok)
+            }),
+        },
+        #[cfg(feature = "service")]
+        CLICommands::Start {
+            socket_file_path,
+            index_dir,
+            index_tag_header,
+            zone,
+            nginx_conf,
+            key_template,
+            strip_prefix,
+            rewrite,
+            add_query,
+            remove_query,
+            protect,
+            zone_protect,
+            max_entries,
+            force,
+            #[cfg(target_os = "linux")]
+            watch,
+            rewrite_order,
+        } => block_on(async move {
+            let mut conf_zones = Vec::new();
+
+            if let Some(nginx_conf) = nginx_conf {
+                for conf_zone in read_nginx_conf(nginx_conf)? {
+                    // the zones assigned by the --zone option take precedence
+                    if zone.chunks(3).any(|zone| zone[0] == conf_zone.name) {
+                        continue;
+                    }
+
+                    let levels = match conf_zone.levels() {
+                        Ok(levels) => levels.to_string(),
+                        Err(error) => {
+                            eprintln!("Warning: {error:#}");
+
+                            continue;
+                        },
+                    };
+
+                    let key_template = conf_zone.cache_key().ok().map(String::from);
+
+                    conf_zones.push((
+                        conf_zone.name,
+                        conf_zone.cache_path.to_string_lossy().into_owned(),
+                        levels,
+                        key_template,
+                    ));
+                }
+            }
+
+            let zones: Vec<(&str, &str, &str)> = conf_zones
+                .iter()
+                .map(|(name, cache_path, levels, _)| {
+                    (name.as_str(), cache_path.as_str(), levels.as_str())
+                })
+                .chain(
+                    zone.chunks(3)
+                        .map(|zone| (zone[0].as_str(), zone[1].as_str(), zone[2].as_str())),
+                )
+                .collect();
+            let key_templates = conf_zones
+                .iter()
+                .filter_map(|(name, _, _, key_template)| {
+                    key_template.as_deref().map(|key_template| (name.as_str(), key_template))
+                })
+                .chain(
+                    key_template
+                        .chunks(2)
+                        .map(|key_template| (key_template[0].as_str(), key_template[1].as_str())),
+                )
+                .collect();
+
+            let mut rewrite_rules = Vec::with_capacity(rewrite_order.len());
+
+            let mut strip_prefix = strip_prefix.chunks(2);
+            let mut rewrite = rewrite.chunks(3);
+            let mut remove_query = remove_query.chunks(2);
+            let mut add_query = add_query.chunks(3);
+
+            // the rules are applied in the order they are assigned
+            for option in rewrite_order {
+                let rule = match option {
+                    RewriteOption::StripPrefix => strip_prefix
+                        .next()
+                        .map(|rule| (rule[0].as_str(), RewriteRule::StripPrefix(rule[1].clone()))),
+                    RewriteOption::Rewrite => rewrite
+                        .next()
+                        .map(|rule| {
+                            RewriteRule::replace(&rule[1], &rule[2])
+                                .map(|replace| (rule[0].as_str(), replace))
+                        })
+                        .transpose()?,
+                    RewriteOption::RemoveQuery => remove_query
+                        .next()
+                        .map(|rule| (rule[0].as_str(), RewriteRule::RemoveQuery(rule[1].clone()))),
+                    RewriteOption::AddQuery => add_query.next().map(|rule| {
+                        (rule[0].as_str(), RewriteRule::AddQuery {
+                            name:  rule[1].clone(),
+                            value: rule[2].clone(),
+                        })
+                    }),
+                };
+
+                rewrite_rules.extend(rule);
+            }
+
+            let mut zone_max_entries = Vec::with_capacity(max_entries.len() / 2);
+
+            for limit in max_entries.chunks(2) {
+                let n = limit[1].parse().map_err(|_| {
+                    PurgeError::InvalidArguments(format!(
+                        "The maximum number of entries {:?} is incorrect.",
+                        limit[1]
+                    ))
+                })?;
+
+                zone_max_entries.push((limit[0].as_str(), n));
+            }
+
+            #[cfg(target_os = "linux")]
+            let watch_zones =
+                watch.chunks(2).map(|zone| (zone[0].as_str(), zone[1].as_str())).collect();
+            #[cfg(not(target_os = "linux"))]
+            let watch_zones = Vec::new();
+
+            server_main(
+                socket_file_path.as_path(),
+                index_dir.as_deref(),
+                index_tag_header,
+                zones,
+                ZoneSettings {
+                    key_templates,
+                    rewrite_rules,
+                    max_entries: zone_max_entries,
+                    protect: zone_protect
+                        .chunks(2)
+                        .map(|rule| (rule[0].as_str(), rule[1].clone()))
+                        .collect(),
+                },
+                watch_zones,
+                PurgeOptions {
+                    force: *force,
+                    protect: protect.clone(),
+                    dry_run: DRY_RUN,
+                    ..PurgeOptions::default()
+                },
+            )
+            .await
+        }),
+    }
+}
+
+/// Run a command which needs a Tokio runtime. The purges do not need one, so they start without it.
+#[cfg(feature = "async")]
+#[inline]
+fn block_on<F: Future<Output = anyhow::Result<AppResult>>>(future: F) -> anyhow::Result<AppResult> {
+    runtime::Runtime::new()?.block_on(future)
 }
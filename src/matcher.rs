@@ -0,0 +1,857 @@
+use std::{borrow::Cow, collections::HashSet};
+
+use anyhow::{anyhow, Context};
+#[cfg(feature = "service")]
+use serde::Deserialize;
+
+/// The maximum number of patterns a glob can be expanded into by its brace alternatives.
+const MAX_GLOB_ALTERNATIVES: usize = 1024;
+
+/// How a key pattern is interpreted.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "service", derive(Deserialize))]
+#[cfg_attr(feature = "service", serde(rename_all = "lowercase"))]
+pub enum MatchMode {
+    /// `*` matches any sequence of characters and anything may follow the last part of the pattern, so `http/a*b` matches `http/axbyz`. `\*` matches a literal `*`. A pattern without `*` is an exact key.
+    #[default]
+    Wildcard,
+    /// `*` matches any sequence of characters, `?` any single character, `[a-z]` / `[!a-z]` a character class and `{jpg,png}` either alternative, and the whole key has to be matched. Use `\` to escape them.
+    Glob,
+    /// A regular expression which is searched in the key. Use `^` and `$` to anchor it.
+    Regex,
+}
+
+/// How key patterns are interpreted and how keys are normalized before they are matched.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct MatchOptions {
+    pub mode:             MatchMode,
+    /// Compare the host part of keys (before the first `/`) case-insensitively.
+    pub ignore_host_case: bool,
+    /// Decode percent-encoded bytes (e.g. `%2F` to `/`).
+    pub decode_percent:   bool,
+    /// Collapse duplicate slashes after the host part (e.g. `//` to `/`).
+    pub collapse_slashes: bool,
+}
+
+impl From<MatchMode> for MatchOptions {
+    #[inline]
+    fn from(mode: MatchMode) -> Self {
+        MatchOptions {
+            mode,
+            ..MatchOptions::default()
+        }
+    }
+}
+
+impl MatchOptions {
+    /// Whether any normalization is applied to keys.
+    #[inline]
+    pub fn normalizes(&self) -> bool {
+        self.ignore_host_case || self.decode_percent || self.collapse_slashes
+    }
+
+    /// Whether `pattern` has to be matched against the keys of caches, rather than being an exact key. If it is an exact key, return it with the escapes removed.
+    ///
+    /// Keys are never exact if they need to be normalized, because the normalized variants are stored in different files.
+    pub fn literal<K: AsRef<str>>(&self, pattern: K) -> anyhow::Result<Option<String>> {
+        let pattern = pattern.as_ref();
+
+        if self.normalizes() {
+            return Ok(None);
+        }
+
+        match self.mode {
+            MatchMode::Wildcard | MatchMode::Glob => {
+                Ok(Glob::new(pattern, self.mode == MatchMode::Glob)?.literal())
+            },
+            MatchMode::Regex => Ok(None),
+        }
+    }
+
+    /// Escape `key` so that it is matched exactly.
+    pub fn escape<K: AsRef<str>>(&self, key: K) -> String {
+        let key = key.as_ref();
+
+        let special: &[char] = match self.mode {
+            MatchMode::Wildcard => &['*', '\\'],
+            MatchMode::Glob => &['*', '\\', '?', '[', ']', '{', '}', ','],
+            MatchMode::Regex => return format!("^{}$", regex::escape(key)),
+        };
+
+        let mut escaped = String::with_capacity(key.len());
+
+        for c in key.chars() {
+            if special.contains(&c) {
+                escaped.push('\\');
+            }
+
+            escaped.push(c);
+        }
+
+        escaped
+    }
+
+    /// Apply the normalizations to a key stored in a cache file.
+    pub fn normalize_key<'a>(&self, key: &'a [u8]) -> Cow<'a, [u8]> {
+        if !self.normalizes() {
+            return Cow::Borrowed(key);
+        }
+
+        let mut key = key.to_vec();
+
+        if self.decode_percent {
+            key = percent_decode(&key);
+        }
+
+        let end = host_end(&key, |u| *u);
+
+        if self.ignore_host_case {
+            key[..end].make_ascii_lowercase();
+        }
+
+        if self.collapse_slashes {
+            let mut path = key.split_off(end);
+
+            path.dedup_by(|a, b| *a == b'/' && *b == b'/');
+
+            key.extend(path);
+        }
+
+        Cow::Owned(key)
+    }
+}
+
+/// A compiled key pattern which is matched against the keys stored in cache files.
+#[derive(Debug)]
+pub struct KeyMatcher {
+    pattern: KeyPattern,
+    options: MatchOptions,
+}
+
+#[derive(Debug)]
+enum KeyPattern {
+    Glob {
+        /// the alternatives without any wildcards, which can be looked up directly
+        literals: HashSet<Vec<u8>>,
+        glob:     Glob,
+    },
+    Regex(regex::bytes::RegexSet),
+}
+
+impl KeyMatcher {
+    /// Compile `patterns` into one matcher which matches a key if any of the patterns matches it. The normalizations in `options` are applied to glob patterns too, but not to regular expressions.
+    pub fn new_many<K: AsRef<str>, O: Into<MatchOptions>>(
+        patterns: &[K],
+        options: O,
+    ) -> anyhow::Result<Self> {
+        let options = options.into();
+
+        if patterns.is_empty() {
+            return Err(anyhow!("At least one key is needed."));
+        }
+
+        let pattern = match options.mode {
+            MatchMode::Wildcard | MatchMode::Glob => {
+                let mut merged: Option<Glob> = None;
+
+                for pattern in patterns {
+                    let pattern = pattern.as_ref();
+
+                    if pattern.is_empty() {
+                        return Err(anyhow!("The key pattern cannot be empty."));
+                    }
+
+                    let mut glob = Glob::new(pattern, options.mode == MatchMode::Glob)?;
+
+                    glob.normalize(&options);
+
+                    match merged.as_mut() {
+                        Some(merged) => merged.alternatives.extend(glob.alternatives),
+                        None => merged = Some(glob),
+                    }
+                }
+
+                let mut glob = merged.unwrap();
+                let literals = glob.take_literals();
+
+                KeyPattern::Glob {
+                    literals,
+                    glob,
+                }
+            },
+            MatchMode::Regex => match regex::bytes::RegexSet::new(patterns) {
+                Ok(regex_set) => KeyPattern::Regex(regex_set),
+                Err(error) => {
+                    // find out which pattern is invalid
+                    for pattern in patterns {
+                        let pattern = pattern.as_ref();
+
+                        regex::bytes::Regex::new(pattern).with_context(|| {
+                            anyhow!("{pattern:?} is not a valid regular expression.")
+                        })?;
+                    }
+
+                    return Err(error.into());
+                },
+            },
+        };
+
+        Ok(KeyMatcher {
+            pattern,
+            options,
+        })
+    }
+
+    /// Whether this pattern matches every key.
+    #[inline]
+    pub fn is_all(&self) -> bool {
+        match &self.pattern {
+            KeyPattern::Glob {
+                glob, ..
+            } => glob.is_all(),
+            KeyPattern::Regex(_) => false,
+        }
+    }
+
+    #[inline]
+    pub fn is_match<K: AsRef<[u8]>>(&self, key: K) -> bool {
+        let key = self.options.normalize_key(key.as_ref());
+
+        match &self.pattern {
+            KeyPattern::Glob {
+                literals,
+                glob,
+            } => literals.contains(key.as_ref()) || glob.is_match(key),
+            KeyPattern::Regex(regex_set) => regex_set.is_match(&key),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum GlobToken {
+    Byte(u8),
+    /// `?`, one UTF-8 character
+    AnyCharacter,
+    /// `*`
+    AnySequence,
+    /// `[...]`, one ASCII character (or, if negated, any other character)
+    Class {
+        negated: bool,
+        ranges:  Vec<(u8, u8)>,
+    },
+}
+
+#[derive(Debug)]
+enum GlobNode {
+    Token(GlobToken),
+    Alternatives(Vec<Vec<GlobNode>>),
+}
+
+/// A compiled glob pattern. Brace alternatives are expanded into multiple token sequences when it is compiled.
+#[derive(Debug)]
+pub struct Glob {
+    alternatives: Vec<Vec<GlobToken>>,
+}
+
+impl Glob {
+    /// Compile `pattern`. If `full_syntax` is `false`, only `*` and `\` are special and a pattern with `*` matches the keys which start with a match of it, like the wildcard keys have always done. Otherwise, the whole key has to be matched.
+    pub fn new<K: AsRef<str>>(pattern: K, full_syntax: bool) -> anyhow::Result<Self> {
+        let pattern = pattern.as_ref();
+
+        let mut parser = GlobParser {
+            pattern: pattern.as_bytes(),
+            position: 0,
+            full_syntax,
+        };
+
+        let (nodes, _) = parser
+            .parse_sequence(false)
+            .with_context(|| anyhow!("{pattern:?} is not a valid glob pattern."))?;
+
+        let mut alternatives = expand_glob_nodes(nodes)
+            .with_context(|| anyhow!("{pattern:?} is not a valid glob pattern."))?;
+
+        if !full_syntax {
+            for tokens in alternatives.iter_mut() {
+                if tokens.contains(&GlobToken::AnySequence)
+                    && tokens.last() != Some(&GlobToken::AnySequence)
+                {
+                    tokens.push(GlobToken::AnySequence);
+                }
+            }
+        }
+
+        Ok(Glob {
+            alternatives,
+        })
+    }
+
+    /// Apply the normalizations which are applied to keys to this pattern.
+    fn normalize(&mut self, options: &MatchOptions) {
+        for tokens in self.alternatives.iter_mut() {
+            if options.decode_percent {
+                let mut decoded = Vec::with_capacity(tokens.len());
+                let mut i = 0;
+
+                while i < tokens.len() {
+                    let bytes = tokens[i..]
+                        .iter()
+                        .take(3)
+                        .map(|token| match token {
+                            GlobToken::Byte(u) => Some(*u),
+                            _ => None,
+                        })
+                        .collect::<Option<Vec<u8>>>();
+
+                    match bytes.as_deref().and_then(percent_decode_at) {
+                        Some(u) => {
+                            decoded.push(GlobToken::Byte(u));
+                            i += 3;
+                        },
+                        None => {
+                            decoded.push(tokens[i].clone());
+                            i += 1;
+                        },
+                    }
+                }
+
+                *tokens = decoded;
+            }
+
+            let end = host_end(tokens, |token| match token {
+                GlobToken::Byte(u) => *u,
+                _ => 0,
+            });
+
+            if options.collapse_slashes {
+                let mut path = tokens.split_off(end);
+
+                path.dedup_by(|a, b| *a == GlobToken::Byte(b'/') && *b == GlobToken::Byte(b'/'));
+
+                tokens.extend(path);
+            }
+
+            if options.ignore_host_case {
+                for token in tokens[..end].iter_mut() {
+                    match token {
+                        GlobToken::Byte(u) => u.make_ascii_lowercase(),
+                        GlobToken::Class {
+                            ranges, ..
+                        } => {
+                            // add the lowercase counterparts of the uppercase letters
+                            let mut lowercase_ranges = Vec::new();
+
+                            for (start, end) in ranges.iter() {
+                                let start = (*start).max(b'A');
+                                let end = (*end).min(b'Z');
+
+                                if start <= end {
+                                    lowercase_ranges.push((
+                                        start.to_ascii_lowercase(),
+                                        end.to_ascii_lowercase(),
+                                    ));
+                                }
+                            }
+
+                            ranges.extend(lowercase_ranges);
+                        },
+                        _ => (),
+                    }
+                }
+            }
+        }
+    }
+
+    /// Get the key which is the only one this pattern can match, if this pattern has no wildcards.
+    pub fn literal(&self) -> Option<String> {
+        if self.alternatives.len() != 1 {
+            return None;
+        }
+
+        let bytes = self.alternatives[0]
+            .iter()
+            .map(|token| match token {
+                GlobToken::Byte(u) => Some(*u),
+                _ => None,
+            })
+            .collect::<Option<Vec<u8>>>()?;
+
+        // only ASCII backslashes are removed from a `str`, so it is still valid UTF-8
+        String::from_utf8(bytes).ok()
+    }
+
+    /// Remove the alternatives without any wildcards and return them.
+    fn take_literals(&mut self) -> HashSet<Vec<u8>> {
+        let mut literals = HashSet::new();
+
+        self.alternatives.retain(|tokens| {
+            let bytes = tokens
+                .iter()
+                .map(|token| match token {
+                    GlobToken::Byte(u) => Some(*u),
+                    _ => None,
+                })
+                .collect::<Option<Vec<u8>>>();
+
+            match bytes {
+                Some(bytes) => {
+                    literals.insert(bytes);
+
+                    false
+                },
+                None => true,
+            }
+        });
+
+        literals
+    }
+
+    /// Whether this pattern matches every key.
+    #[inline]
+    pub fn is_all(&self) -> bool {
+        self.alternatives.iter().any(|tokens| {
+            !tokens.is_empty() && tokens.iter().all(|token| *token == GlobToken::AnySequence)
+        })
+    }
+
+    #[inline]
+    pub fn is_match<K: AsRef<[u8]>>(&self, key: K) -> bool {
+        let key = key.as_ref();
+
+        self.alternatives.iter().any(|tokens| match_glob_tokens(tokens, key))
+    }
+}
+
+struct GlobParser<'a> {
+    pattern:     &'a [u8],
+    position:    usize,
+    full_syntax: bool,
+}
+
+impl<'a> GlobParser<'a> {
+    #[inline]
+    fn is_special(&self, u: u8) -> bool {
+        match u {
+            b'*' | b'\\' => true,
+            b'?' | b'[' | b']' | b'{' | b'}' | b',' => self.full_syntax,
+            _ => false,
+        }
+    }
+
+    /// Parse until the end of the pattern or, if `in_braces` is `true`, an unescaped `,` or `}`. The terminator is consumed and returned.
+    fn parse_sequence(&mut self, in_braces: bool) -> anyhow::Result<(Vec<GlobNode>, u8)> {
+        let mut nodes = Vec::new();
+
+        while let Some(&u) = self.pattern.get(self.position) {
+            self.position += 1;
+
+            match u {
+                b'\\' => match self.pattern.get(self.position) {
+                    Some(&next) if self.is_special(next) => {
+                        self.position += 1;
+
+                        nodes.push(GlobNode::Token(GlobToken::Byte(next)));
+                    },
+                    _ => nodes.push(GlobNode::Token(GlobToken::Byte(u))),
+                },
+                b'*' => {
+                    // `**` is the same as `*`
+                    if !matches!(nodes.last(), Some(GlobNode::Token(GlobToken::AnySequence))) {
+                        nodes.push(GlobNode::Token(GlobToken::AnySequence));
+                    }
+                },
+                b'?' if self.full_syntax => nodes.push(GlobNode::Token(GlobToken::AnyCharacter)),
+                b'[' if self.full_syntax => nodes.push(GlobNode::Token(self.parse_class()?)),
+                b'{' if self.full_syntax => {
+                    let mut alternatives = Vec::new();
+
+                    loop {
+                        let (alternative, terminator) = self.parse_sequence(true)?;
+
+                        alternatives.push(alternative);
+
+                        if terminator == b'}' {
+                            break;
+                        }
+                    }
+
+                    nodes.push(GlobNode::Alternatives(alternatives));
+                },
+                b',' | b'}' if self.full_syntax && in_braces => return Ok((nodes, u)),
+                _ => nodes.push(GlobNode::Token(GlobToken::Byte(u))),
+            }
+        }
+
+        if in_braces {
+            return Err(anyhow!("A `{{` is not closed."));
+        }
+
+        Ok((nodes, 0))
+    }
+
+    /// Parse a character class. The leading `[` has been consumed.
+    fn parse_class(&mut self) -> anyhow::Result<GlobToken> {
+        let negated = matches!(self.pattern.get(self.position), Some(b'!' | b'^'));
+
+        if negated {
+            self.position += 1;
+        }
+
+        let mut ranges = Vec::new();
+        let mut first = true;
+
+        loop {
+            let Some(&u) = self.pattern.get(self.position) else {
+                return Err(anyhow!("A `[` is not closed."));
+            };
+
+            self.position += 1;
+
+            // a `]` right after the `[` is a member
+            if u == b']' && !first {
+                break;
+            }
+
+            first = false;
+
+            let start = self.parse_class_member(u)?;
+
+            let end = match self.pattern.get(self.position..self.position + 2) {
+                Some([b'-', next]) if *next != b']' => {
+                    self.position += 2;
+
+                    self.parse_class_member(*next)?
+                },
+                _ => start,
+            };
+
+            if start > end {
+                return Err(anyhow!(
+                    "The range `{}-{}` is out of order.",
+                    start as char,
+                    end as char
+                ));
+            }
+
+            ranges.push((start, end));
+        }
+
+        Ok(GlobToken::Class {
+            negated,
+            ranges,
+        })
+    }
+
+    fn parse_class_member(&mut self, u: u8) -> anyhow::Result<u8> {
+        let u = if u == b'\\' {
+            match self.pattern.get(self.position) {
+                Some(&next) => {
+                    self.position += 1;
+
+                    next
+                },
+                None => return Err(anyhow!("A `[` is not closed.")),
+            }
+        } else {
+            u
+        };
+
+        if !u.is_ascii() {
+            return Err(anyhow!("Character classes only support ASCII characters."));
+        }
+
+        Ok(u)
+    }
+}
+
+fn expand_glob_nodes(nodes: Vec<GlobNode>) -> anyhow::Result<Vec<Vec<GlobToken>>> {
+    let mut results: Vec<Vec<GlobToken>> = vec![Vec::new()];
+
+    for node in nodes {
+        match node {
+            GlobNode::Token(token) => {
+                for result in results.iter_mut() {
+                    result.push(token.clone());
+                }
+            },
+            GlobNode::Alternatives(alternatives) => {
+                let mut expanded = Vec::new();
+
+                for alternative in alternatives {
+                    expanded.extend(expand_glob_nodes(alternative)?);
+                }
+
+                if results.len() * expanded.len() > MAX_GLOB_ALTERNATIVES {
+                    return Err(anyhow!(
+                        "There are more than {MAX_GLOB_ALTERNATIVES} alternatives."
+                    ));
+                }
+
+                results = results
+                    .iter()
+                    .flat_map(|result| {
+                        expanded.iter().map(move |tokens| {
+                            let mut result = result.clone();
+
+                            result.extend(tokens.iter().cloned());
+
+                            result
+                        })
+                    })
+                    .collect();
+            },
+        }
+    }
+
+    // `*` next to `*` from an alternative is redundant
+    for result in results.iter_mut() {
+        result.dedup_by(|a, b| *a == GlobToken::AnySequence && *b == GlobToken::AnySequence);
+    }
+
+    Ok(results)
+}
+
+/// The length of the UTF-8 character which starts with `u`. Invalid bytes are treated as single characters.
+#[inline]
+fn utf8_character_length(u: u8) -> usize {
+    match u {
+        0xC0..=0xDF => 2,
+        0xE0..=0xEF => 3,
+        0xF0..=0xF7 => 4,
+        _ => 1,
+    }
+}
+
+/// Match one token which is not `AnySequence` at the start of `key` and return the number of bytes it consumes.
+#[inline]
+fn match_glob_token(token: &GlobToken, key: &[u8]) -> Option<usize> {
+    let &u = key.first()?;
+
+    match token {
+        GlobToken::Byte(b) => (*b == u).then_some(1),
+        GlobToken::AnyCharacter => Some(utf8_character_length(u).min(key.len())),
+        GlobToken::Class {
+            negated,
+            ranges,
+        } => {
+            let hit = ranges.iter().any(|(start, end)| (*start..=*end).contains(&u));
+
+            (hit != *negated).then(|| utf8_character_length(u).min(key.len()))
+        },
+        GlobToken::AnySequence => unreachable!(),
+    }
+}
+
+fn match_glob_tokens(tokens: &[GlobToken], key: &[u8]) -> bool {
+    let mut t = 0;
+    let mut k = 0;
+
+    // the token index after the last `*` and the key index where it stops matching
+    let mut backtrack: Option<(usize, usize)> = None;
+
+    loop {
+        if let Some(token) = tokens.get(t) {
+            if *token == GlobToken::AnySequence {
+                t += 1;
+                backtrack = Some((t, k));
+
+                continue;
+            }
+
+            if let Some(length) = match_glob_token(token, &key[k..]) {
+                t += 1;
+                k += length;
+
+                continue;
+            }
+        } else if k == key.len() {
+            return true;
+        }
+
+        // let the last `*` match one more byte
+        match backtrack {
+            Some((bt, bk)) if bk < key.len() => {
+                t = bt;
+                k = bk + 1;
+                backtrack = Some((t, k));
+            },
+            _ => return false,
+        }
+    }
+}
+
+/// Decode all percent-encoded bytes in `s`. Invalid ones are kept as they are.
+pub(crate) fn percent_decode(s: &[u8]) -> Vec<u8> {
+    let mut decoded = Vec::with_capacity(s.len());
+    let mut i = 0;
+
+    while i < s.len() {
+        match percent_decode_at(&s[i..]) {
+            Some(u) => {
+                decoded.push(u);
+                i += 3;
+            },
+            None => {
+                decoded.push(s[i]);
+                i += 1;
+            },
+        }
+    }
+
+    decoded
+}
+
+/// Decode the percent-encoded byte at the start of `s`, like `%2F`.
+#[inline]
+fn percent_decode_at(s: &[u8]) -> Option<u8> {
+    match s {
+        [b'%', h, l, ..] => {
+            let h = (*h as char).to_digit(16)?;
+            let l = (*l as char).to_digit(16)?;
+
+            Some((h * 16 + l) as u8)
+        },
+        _ => None,
+    }
+}
+
+/// Find the end of the host part, which is before the first `/` (not including the one of `://`).
+fn host_end<T, F: Fn(&T) -> u8>(s: &[T], byte: F) -> usize {
+    let mut i = 0;
+
+    while i < s.len() {
+        if byte(&s[i]) == b'/' {
+            if i > 0 && byte(&s[i - 1]) == b':' && s.get(i + 1).map(&byte) == Some(b'/') {
+                i += 2;
+
+                continue;
+            }
+
+            return i;
+        }
+
+        i += 1;
+    }
+
+    s.len()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn wildcard_keys() {
+        let matcher = KeyMatcher::new_many(&["http/a*b"], MatchMode::Wildcard).unwrap();
+
+        assert!(matcher.is_match("http/ab"));
+        assert!(matcher.is_match("http/axbyz"));
+        assert!(!matcher.is_match("http/axc"));
+        assert!(!matcher.is_match("https/ab"));
+
+        let matcher = KeyMatcher::new_many(&["http/a"], MatchMode::Wildcard).unwrap();
+
+        assert!(matcher.is_match("http/a"));
+        assert!(!matcher.is_match("http/ab"));
+
+        let matcher = KeyMatcher::new_many(&[r"http/a\*"], MatchMode::Wildcard).unwrap();
+
+        assert!(matcher.is_match("http/a*"));
+        assert!(!matcher.is_match("http/ab"));
+
+        assert!(KeyMatcher::new_many(&["*"], MatchMode::Wildcard).unwrap().is_all());
+        assert!(!KeyMatcher::new_many(&["http/*"], MatchMode::Wildcard).unwrap().is_all());
+    }
+
+    #[test]
+    fn glob_keys() {
+        let matcher =
+            KeyMatcher::new_many(&["http/*.{jpg,png}", "http/[a-c]?/x"], MatchMode::Glob).unwrap();
+
+        assert!(matcher.is_match("http/a.jpg"));
+        assert!(matcher.is_match("http/dir/a.png"));
+        assert!(!matcher.is_match("http/a.jpg?v=1"));
+        assert!(matcher.is_match("http/bé/x"));
+        assert!(!matcher.is_match("http/d1/x"));
+        assert!(!matcher.is_match("http/b/x"));
+
+        let matcher = KeyMatcher::new_many(&["http/[!a]"], MatchMode::Glob).unwrap();
+
+        assert!(matcher.is_match("http/b"));
+        assert!(!matcher.is_match("http/a"));
+
+        assert!(Glob::new("http/[a", true).is_err());
+        assert!(Glob::new("http/{a", true).is_err());
+        assert!(Glob::new("http/[a", false).is_ok());
+    }
+
+    #[test]
+    fn literal_patterns() {
+        let options = MatchOptions::from(MatchMode::Glob);
+
+        assert_eq!(options.literal(r"http/a\?b").unwrap(), Some("http/a?b".to_string()));
+        assert_eq!(options.literal("http/a?b").unwrap(), None);
+        assert_eq!(options.literal("http/{a}").unwrap(), Some("http/a".to_string()));
+        assert_eq!(options.literal("http/{a,b}").unwrap(), None);
+
+        let options = MatchOptions::from(MatchMode::Wildcard);
+
+        assert_eq!(options.literal("http/a?b").unwrap(), Some("http/a?b".to_string()));
+        assert_eq!(options.literal("http/a*").unwrap(), None);
+        assert_eq!(MatchOptions::from(MatchMode::Regex).literal("http/a").unwrap(), None);
+    }
+
+    #[test]
+    fn escape_keys() {
+        let key = r"http/a*b?c[d]{e,f}\g.h";
+
+        for mode in [MatchMode::Wildcard, MatchMode::Glob, MatchMode::Regex] {
+            let options = MatchOptions::from(mode);
+            let escaped = options.escape(key);
+            let matcher = KeyMatcher::new_many(&[&escaped], options).unwrap();
+
+            assert!(matcher.is_match(key), "{mode:?}: {escaped}");
+            assert!(!matcher.is_match(format!("{key}x")), "{mode:?}: {escaped}");
+            assert!(!matcher.is_match("http/axb"), "{mode:?}: {escaped}");
+        }
+
+        assert_eq!(MatchOptions::from(MatchMode::Wildcard).escape("http/a*?"), r"http/a\*?");
+    }
+
+    #[test]
+    fn normalize_keys() {
+        let options = MatchOptions {
+            mode:             MatchMode::Wildcard,
+            ignore_host_case: true,
+            decode_percent:   true,
+            collapse_slashes: true,
+        };
+
+        assert_eq!(
+            options.normalize_key(b"httpGET://Example.COM//A%2fB//c%zz").as_ref(),
+            b"httpget://example.com/A/B/c%zz"
+        );
+        assert_eq!(
+            MatchOptions::default().normalize_key(b"http/A//b%2F").as_ref(),
+            b"http/A//b%2F"
+        );
+        assert!(matches!(MatchOptions::default().normalize_key(b"http/a"), Cow::Borrowed(_)));
+
+        let matcher = KeyMatcher::new_many(&["HTTP/Blog/x%2Fy*"], options).unwrap();
+
+        assert!(matcher.is_match("http//Blog/x/y/z"));
+        assert!(matcher.is_match("Http/Blog//x%2Fy"));
+        assert!(!matcher.is_match("http/blog/x/y"));
+
+        let matcher = KeyMatcher::new_many(&["[A-C]ttp/a"], MatchOptions {
+            mode: MatchMode::Glob,
+            ignore_host_case: true,
+            ..MatchOptions::default()
+        })
+        .unwrap();
+
+        assert!(matcher.is_match("bttp/a"));
+        assert!(matcher.is_match("Bttp/a"));
+        assert!(!matcher.is_match("bttp/A"));
+    }
+}
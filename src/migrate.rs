@@ -0,0 +1,193 @@
+use std::{
+    io,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{anyhow, Context};
+
+use crate::{
+    cache_file::validate_cache_file,
+    check::is_cache_file_name,
+    functions::{
+        canonicalize_cache_path, create_cache_file_path, move_file, parse_levels,
+        remove_empty_ancestors, resolve_levels,
+    },
+};
+
+#[derive(Debug, Default)]
+pub struct MigrateReport {
+    /// the number of cache files which have been moved
+    pub migrated: usize,
+    /// the cache files which cannot be migrated because their headers cannot be parsed
+    pub skipped:  Vec<PathBuf>,
+}
+
+/// Move every cache file of a cache zone into the directory layout of `new_levels` under `new_cache_path`, which can be the same as `cache_path`.
+pub async fn migrate_cache_zone<P: AsRef<Path>, L: AsRef<str>, NP: AsRef<Path>, NL: AsRef<str>>(
+    cache_path: P,
+    levels: L,
+    new_cache_path: NP,
+    new_levels: NL,
+) -> anyhow::Result<MigrateReport> {
+    let new_levels = parse_levels(new_levels)?;
+    let new_cache_path = new_cache_path.as_ref();
+
+    let mut report = MigrateReport::default();
+
+    let cache_path = match canonicalize_cache_path(cache_path)? {
+        Some(cache_path) => cache_path,
+        None => return Ok(report),
+    };
+
+    let levels = match resolve_levels(cache_path.as_path(), levels).await? {
+        Some(levels) => levels,
+        None => return Ok(report),
+    };
+
+    tokio::fs::create_dir_all(new_cache_path)
+        .await
+        .with_context(|| anyhow!("{new_cache_path:?}"))?;
+
+    let new_cache_path =
+        new_cache_path.canonicalize().with_context(|| anyhow!("{new_cache_path:?}"))?;
+
+    if new_cache_path == cache_path && new_levels == levels {
+        return Err(anyhow!("The cache zone {cache_path:?} already uses these levels."));
+    }
+
+    // collect the files first, so that the moved files are not visited again
+    let file_paths = collect_cache_files(cache_path.as_path(), levels.len()).await?;
+
+    for file_path in file_paths {
+        let key = match validate_cache_file(file_path.as_path()) {
+            Ok(key) => key,
+            Err(_) => {
+                // removed by nginx during the migration
+                if file_path.exists() {
+                    report.skipped.push(file_path);
+                }
+
+                continue;
+            },
+        };
+
+        let new_file_path = create_cache_file_path(new_cache_path.as_path(), &new_levels, &key);
+
+        if new_file_path == file_path {
+            continue;
+        }
+
+        match move_file(file_path.as_path(), new_file_path.as_path()).await {
+            Ok(_) => report.migrated += 1,
+            Err(error) if error.kind() == io::ErrorKind::NotFound => continue,
+            Err(error) => {
+                return Err(error).with_context(|| anyhow!("{file_path:?} -> {new_file_path:?}"))
+            },
+        }
+
+        remove_empty_ancestors(file_path.as_path(), levels.len()).await?;
+    }
+
+    Ok(report)
+}
+
+/// Find the cache files in the level directories of a cache zone.
+async fn collect_cache_files(
+    cache_path: &Path,
+    number_of_levels: usize,
+) -> anyhow::Result<Vec<PathBuf>> {
+    let mut file_paths = Vec::new();
+
+    // (directory, depth)
+    let mut directories = vec![(cache_path.to_path_buf(), 0)];
+
+    while let Some((directory, depth)) = directories.pop() {
+        let mut entries = match tokio::fs::read_dir(directory.as_path()).await {
+            Ok(entries) => entries,
+            Err(error) if error.kind() == io::ErrorKind::NotFound => continue,
+            Err(error) => return Err(error).with_context(|| anyhow!("{directory:?}")),
+        };
+
+        while let Some(entry) =
+            entries.next_entry().await.with_context(|| anyhow!("{directory:?}"))?
+        {
+            let path = entry.path();
+
+            let file_type = match entry.file_type().await {
+                Ok(file_type) => file_type,
+                Err(error) if error.kind() == io::ErrorKind::NotFound => continue,
+                Err(error) => return Err(error).with_context(|| anyhow!("{path:?}")),
+            };
+
+            if depth < number_of_levels {
+                if file_type.is_dir() {
+                    directories.push((path, depth + 1));
+                }
+            } else if file_type.is_file()
+                && is_cache_file_name(&entry.file_name().to_string_lossy())
+            {
+                file_paths.push(path);
+            }
+        }
+    }
+
+    Ok(file_paths)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+    use crate::test_zone::TestZone;
+
+    #[tokio::test]
+    async fn migrate_in_place() {
+        let test_zone = TestZone::new("migrate", "1:2");
+
+        let keys: [&[u8]; 3] = [b"http/blog/", b"http/blog/2", b"http/static/\xff.jpg"];
+
+        for key in keys {
+            test_zone.add(key, "");
+        }
+
+        let corrupt = test_zone.add("http/static/a.jpg", "");
+
+        fs::write(&corrupt, "not a cache file").unwrap();
+
+        let report =
+            migrate_cache_zone(&test_zone.path, "auto", &test_zone.path, "2").await.unwrap();
+
+        assert_eq!(report.migrated, 3);
+        assert_eq!(report.skipped, [corrupt.as_path()]);
+
+        let mut expected: Vec<PathBuf> =
+            keys.iter().map(|key| create_cache_file_path(&test_zone.path, [2], key)).collect();
+
+        expected.push(corrupt);
+        expected.sort();
+
+        assert_eq!(test_zone.files(), expected);
+
+        assert!(migrate_cache_zone(&test_zone.path, "1:2", &test_zone.path, "1:2").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn migrate_to_another_path() {
+        let test_zone = TestZone::new("migrate-from", "1:2");
+        let new_test_zone = TestZone::new("migrate-to", "2:2");
+
+        let content = fs::read(test_zone.add("http/blog/", "Cache-Tag: a\r\n")).unwrap();
+
+        let report =
+            migrate_cache_zone(&test_zone.path, "1:2", &new_test_zone.path, "2:2").await.unwrap();
+
+        let new_file_path = create_cache_file_path(&new_test_zone.path, [2, 2], "http/blog/");
+
+        assert_eq!(report.migrated, 1);
+        // the empty level directories are removed
+        assert!(fs::read_dir(&test_zone.path).unwrap().next().is_none());
+        assert_eq!(new_test_zone.files(), [new_file_path.as_path()]);
+        assert_eq!(fs::read(new_file_path).unwrap(), content);
+    }
+}
@@ -0,0 +1,672 @@
+use std::{
+    collections::HashMap,
+    fs,
+    path::{Component, Path, PathBuf},
+};
+
+use anyhow::anyhow;
+
+use crate::{error::PurgeError, matcher::Glob};
+
+/// The modules which have their own `*_cache_path`, `*_cache_key`, `*_cache` and `*_pass` directives.
+const CACHE_MODULES: [&str; 4] = ["proxy", "fastcgi", "uwsgi", "scgi"];
+
+/// The depth limit of nested `include` directives, to stop include loops.
+const MAX_INCLUDE_DEPTH: usize = 32;
+
+/// A cache zone defined by a `*_cache_path` directive.
+#[derive(Debug, Clone)]
+pub struct CacheZoneConfig {
+    /// the name in `keys_zone`
+    pub name:           String,
+    /// `proxy`, `fastcgi`, `uwsgi` or `scgi`
+    pub module:         &'static str,
+    pub cache_path:     PathBuf,
+    pub levels:         Option<String>,
+    /// the size in `keys_zone`, like `10m`
+    pub keys_zone_size: Option<String>,
+    pub max_size:       Option<String>,
+    pub inactive:       Option<String>,
+    /// the distinct `*_cache_key` values of the locations which use this zone
+    pub cache_keys:     Vec<String>,
+}
+
+impl CacheZoneConfig {
+    /// The levels of this zone. Zones without `levels` are not supported.
+    #[inline]
+    pub fn levels(&self) -> Result<&str, PurgeError> {
+        self.levels.as_deref().ok_or_else(|| {
+            PurgeError::InvalidLevels(format!(
+                "The cache zone {:?} has no levels, which is not supported.",
+                self.name
+            ))
+        })
+    }
+
+    /// The cache key of this zone, if all the locations which use this zone share the same one.
+    pub fn cache_key(&self) -> Result<&str, PurgeError> {
+        match self.cache_keys.as_slice() {
+            [key] => Ok(key),
+            [] => Err(PurgeError::InvalidArguments(format!(
+                "No location uses the cache zone {:?}.",
+                self.name
+            ))),
+            keys => Err(PurgeError::InvalidArguments(format!(
+                "The cache zone {:?} has more than one cache key: {keys:?}. Assign one of them \
+                 explicitly.",
+                self.name
+            ))),
+        }
+    }
+}
+
+/// Find a cache zone by its name.
+#[inline]
+pub fn find_zone<'a>(
+    zones: &'a [CacheZoneConfig],
+    name: &str,
+) -> Result<&'a CacheZoneConfig, PurgeError> {
+    zones
+        .iter()
+        .find(|zone| zone.name == name)
+        .ok_or_else(|| PurgeError::ZoneNotFound(name.to_string()))
+}
+
+/// Read the cache zones from an nginx configuration file, following its `include` directives. A file which cannot be read is a `PurgeError::Io`, and a configuration which cannot be parsed is a `PurgeError::InvalidArguments`.
+pub fn read_config_file<P: AsRef<Path>>(path: P) -> Result<Vec<CacheZoneConfig>, PurgeError> {
+    let path = path.as_ref();
+
+    let source = ConfigSource::Files {
+        prefix: path.parent().map(Path::to_path_buf).unwrap_or_default(),
+    };
+
+    let directives = source.load(path, 0)?;
+
+    Ok(collect_cache_zones(&directives))
+}
+
+/// Read the cache zones from the output of `nginx -T`, which contains every configuration file prefixed by a `# configuration file PATH:` line.
+pub fn read_config_dump(dump: &str) -> Result<Vec<CacheZoneConfig>, PurgeError> {
+    let mut files: Vec<(PathBuf, String)> = Vec::new();
+
+    for line in dump.lines() {
+        if let Some(path) =
+            line.strip_prefix("# configuration file ").and_then(|line| line.strip_suffix(':'))
+        {
+            files.push((PathBuf::from(path), String::new()));
+        } else if let Some((_, content)) = files.last_mut() {
+            content.push_str(line);
+            content.push('\n');
+        }
+    }
+
+    let main = match files.first() {
+        Some((path, _)) => path.clone(),
+        None => {
+            return Err(PurgeError::InvalidArguments(String::from(
+                "The input is not the output of `nginx -T`.",
+            )))
+        },
+    };
+
+    let source = ConfigSource::Dump {
+        prefix: main.parent().map(Path::to_path_buf).unwrap_or_default(),
+        files:  files.into_iter().collect(),
+    };
+
+    let directives = source.load(&main, 0)?;
+
+    Ok(collect_cache_zones(&directives))
+}
+
+#[derive(Debug)]
+struct Directive {
+    name:  String,
+    args:  Vec<String>,
+    block: Option<Vec<Directive>>,
+}
+
+#[derive(Debug)]
+enum ConfigSource {
+    Files { prefix: PathBuf },
+    Dump { prefix: PathBuf, files: HashMap<PathBuf, String> },
+}
+
+impl ConfigSource {
+    fn prefix(&self) -> &Path {
+        match self {
+            ConfigSource::Files {
+                prefix,
+            }
+            | ConfigSource::Dump {
+                prefix, ..
+            } => prefix,
+        }
+    }
+
+    fn read(&self, path: &Path) -> Result<String, PurgeError> {
+        match self {
+            ConfigSource::Files {
+                ..
+            } => fs::read_to_string(path).map_err(|error| PurgeError::io(path, error)),
+            ConfigSource::Dump {
+                files, ..
+            } => files.get(path).cloned().ok_or_else(|| {
+                PurgeError::InvalidArguments(format!(
+                    "{path:?} is not in the output of `nginx -T`."
+                ))
+            }),
+        }
+    }
+
+    /// Read and parse a configuration file, with its `include` directives replaced by the included directives.
+    fn load(&self, path: &Path, depth: usize) -> Result<Vec<Directive>, PurgeError> {
+        if depth > MAX_INCLUDE_DEPTH {
+            return Err(PurgeError::InvalidArguments(format!("{path:?} is included too deeply.")));
+        }
+
+        let content = self.read(path)?;
+
+        let directives = parse(&content)
+            .map_err(|error| PurgeError::InvalidArguments(format!("{path:?}: {error:#}")))?;
+
+        self.resolve_includes(directives, depth)
+    }
+
+    fn resolve_includes(
+        &self,
+        directives: Vec<Directive>,
+        depth: usize,
+    ) -> Result<Vec<Directive>, PurgeError> {
+        let mut resolved = Vec::with_capacity(directives.len());
+
+        for mut directive in directives {
+            if directive.name == "include" && directive.block.is_none() {
+                for pattern in directive.args.iter() {
+                    for path in self.expand_include(pattern)? {
+                        resolved.extend(self.load(&path, depth + 1)?);
+                    }
+                }
+            } else {
+                if let Some(block) = directive.block.take() {
+                    directive.block = Some(self.resolve_includes(block, depth)?);
+                }
+
+                resolved.push(directive);
+            }
+        }
+
+        Ok(resolved)
+    }
+
+    /// Find the files matched by the path of an `include` directive, which may contain wildcards.
+    fn expand_include(&self, pattern: &str) -> Result<Vec<PathBuf>, PurgeError> {
+        let pattern = self.prefix().join(pattern);
+
+        let invalid_pattern = |error: anyhow::Error| {
+            PurgeError::InvalidArguments(format!("The include path {pattern:?}: {error:#}"))
+        };
+
+        if !pattern.to_string_lossy().contains(['*', '?', '[']) {
+            return Ok(vec![pattern]);
+        }
+
+        let mut paths = match self {
+            ConfigSource::Files {
+                ..
+            } => {
+                let mut paths = vec![PathBuf::new()];
+
+                for component in pattern.components() {
+                    let component = component.as_os_str().to_string_lossy();
+
+                    if !component.contains(['*', '?', '[']) {
+                        paths.iter_mut().for_each(|path| path.push(component.as_ref()));
+
+                        continue;
+                    }
+
+                    let glob = Glob::new(component.as_ref(), true).map_err(invalid_pattern)?;
+
+                    let mut matched = Vec::new();
+
+                    for path in paths {
+                        let entries = match fs::read_dir(&path) {
+                            Ok(entries) => entries,
+                            Err(_) => continue,
+                        };
+
+                        for entry in entries {
+                            let name =
+                                entry.map_err(|error| PurgeError::io(&path, error))?.file_name();
+
+                            if glob.is_match(name.to_string_lossy().as_bytes()) {
+                                matched.push(path.join(name));
+                            }
+                        }
+                    }
+
+                    paths = matched;
+                }
+
+                paths
+            },
+            ConfigSource::Dump {
+                files, ..
+            } => {
+                let globs = pattern
+                    .components()
+                    .map(|component| Glob::new(component.as_os_str().to_string_lossy(), true))
+                    .collect::<anyhow::Result<Vec<_>>>()
+                    .map_err(invalid_pattern)?;
+
+                files
+                    .keys()
+                    .filter(|path| {
+                        let components: Vec<Component> = path.components().collect();
+
+                        components.len() == globs.len()
+                            && components.iter().zip(globs.iter()).all(|(component, glob)| {
+                                glob.is_match(component.as_os_str().to_string_lossy().as_bytes())
+                            })
+                    })
+                    .cloned()
+                    .collect()
+            },
+        };
+
+        // nginx includes the matched files in alphabetical order
+        paths.sort();
+
+        Ok(paths)
+    }
+}
+
+/// Split the content of a configuration file into words, `;`, `{` and `}`.
+fn tokenize(content: &str) -> anyhow::Result<Vec<(String, bool)>> {
+    // (token, whether the token is a word)
+    let mut tokens = Vec::new();
+    let mut chars = content.chars().peekable();
+    let mut line = 1;
+
+    while let Some(c) = chars.next() {
+        match c {
+            '\n' => line += 1,
+            c if c.is_whitespace() => (),
+            '#' => {
+                for c in chars.by_ref() {
+                    if c == '\n' {
+                        line += 1;
+
+                        break;
+                    }
+                }
+            },
+            ';' | '{' | '}' => tokens.push((c.to_string(), false)),
+            '"' | '\'' => {
+                let mut word = String::new();
+
+                loop {
+                    match chars.next() {
+                        Some('\\') => match chars.next() {
+                            Some(e) if e == c || e == '\\' => word.push(e),
+                            Some(e) => {
+                                word.push('\\');
+                                word.push(e);
+                            },
+                            None => break,
+                        },
+                        Some(e) if e == c => break,
+                        Some(e) => {
+                            if e == '\n' {
+                                line += 1;
+                            }
+
+                            word.push(e);
+                        },
+                        None => return Err(anyhow!("A quote is not closed at line {line}.")),
+                    }
+                }
+
+                tokens.push((word, true));
+            },
+            _ => {
+                let mut word = String::from(c);
+
+                while let Some(&c) = chars.peek() {
+                    match c {
+                        // `${name}` is a variable
+                        '{' if word.ends_with('$') => {
+                            for c in chars.by_ref() {
+                                word.push(c);
+
+                                if c == '}' {
+                                    break;
+                                }
+                            }
+                        },
+                        ';' | '{' | '}' => break,
+                        c if c.is_whitespace() => break,
+                        '\\' => {
+                            chars.next();
+
+                            if let Some(e) = chars.next() {
+                                word.push(e);
+                            }
+                        },
+                        _ => {
+                            word.push(c);
+                            chars.next();
+                        },
+                    }
+                }
+
+                tokens.push((word, true));
+            },
+        }
+    }
+
+    Ok(tokens)
+}
+
+fn parse(content: &str) -> anyhow::Result<Vec<Directive>> {
+    let tokens = tokenize(content)?;
+
+    let mut tokens = tokens.into_iter();
+
+    let (directives, closed) = parse_block(&mut tokens)?;
+
+    if closed {
+        return Err(anyhow!("Unexpected `}}`."));
+    }
+
+    Ok(directives)
+}
+
+/// Parse directives until the end of the block. Return whether the block is closed by `}`.
+fn parse_block<I: Iterator<Item = (String, bool)>>(
+    tokens: &mut I,
+) -> anyhow::Result<(Vec<Directive>, bool)> {
+    let mut directives = Vec::new();
+    let mut words: Vec<String> = Vec::new();
+
+    while let Some((token, is_word)) = tokens.next() {
+        if is_word {
+            words.push(token);
+
+            continue;
+        }
+
+        match token.as_str() {
+            ";" | "{" => {
+                if words.is_empty() {
+                    return Err(anyhow!("Unexpected `{token}`."));
+                }
+
+                let name = words.remove(0);
+                let args = std::mem::take(&mut words);
+
+                let block = if token == "{" {
+                    let (block, closed) = parse_block(tokens)?;
+
+                    if !closed {
+                        return Err(anyhow!("The block of `{name}` is not closed."));
+                    }
+
+                    Some(block)
+                } else {
+                    None
+                };
+
+                directives.push(Directive {
+                    name,
+                    args,
+                    block,
+                });
+            },
+            _ => {
+                if !words.is_empty() {
+                    return Err(anyhow!("The directive `{}` is not terminated by `;`.", words[0]));
+                }
+
+                return Ok((directives, true));
+            },
+        }
+    }
+
+    if !words.is_empty() {
+        return Err(anyhow!("The directive `{}` is not terminated by `;`.", words[0]));
+    }
+
+    Ok((directives, false))
+}
+
+/// The cache settings inherited from outer blocks, of each module.
+#[derive(Debug, Clone, Default)]
+struct CacheContext {
+    /// `proxy_cache` and so on
+    zones: [Option<String>; 4],
+    /// `proxy_cache_key` and so on
+    keys:  [Option<String>; 4],
+}
+
+fn collect_cache_zones(directives: &[Directive]) -> Vec<CacheZoneConfig> {
+    let mut zones = Vec::new();
+
+    collect_cache_paths(directives, &mut zones);
+    collect_cache_keys(directives, CacheContext::default(), &mut zones);
+
+    zones
+}
+
+fn collect_cache_paths(directives: &[Directive], zones: &mut Vec<CacheZoneConfig>) {
+    for directive in directives {
+        if let Some(block) = directive.block.as_ref() {
+            collect_cache_paths(block, zones);
+
+            continue;
+        }
+
+        let module = match CACHE_MODULES
+            .iter()
+            .find(|module| directive.name.strip_suffix("_cache_path") == Some(module))
+        {
+            Some(module) => *module,
+            None => continue,
+        };
+
+        let (cache_path, parameters) = match directive.args.split_first() {
+            Some(args) => args,
+            None => continue,
+        };
+
+        let mut zone = CacheZoneConfig {
+            name: String::new(),
+            module,
+            cache_path: PathBuf::from(cache_path),
+            levels: None,
+            keys_zone_size: None,
+            max_size: None,
+            inactive: None,
+            cache_keys: Vec::new(),
+        };
+
+        for parameter in parameters {
+            let (name, value) = match parameter.split_once('=') {
+                Some(parameter) => parameter,
+                None => continue,
+            };
+
+            match name {
+                "levels" => zone.levels = Some(value.to_string()),
+                "keys_zone" => match value.split_once(':') {
+                    Some((name, size)) => {
+                        zone.name = name.to_string();
+                        zone.keys_zone_size = Some(size.to_string());
+                    },
+                    None => zone.name = value.to_string(),
+                },
+                "max_size" => zone.max_size = Some(value.to_string()),
+                "inactive" => zone.inactive = Some(value.to_string()),
+                _ => (),
+            }
+        }
+
+        if !zone.name.is_empty() {
+            zones.push(zone);
+        }
+    }
+}
+
+fn collect_cache_keys(
+    directives: &[Directive],
+    mut context: CacheContext,
+    zones: &mut [CacheZoneConfig],
+) {
+    let mut passes = [false; 4];
+
+    for directive in directives.iter().filter(|directive| directive.block.is_none()) {
+        for (i, module) in CACHE_MODULES.iter().enumerate() {
+            let setting = match directive.name.strip_prefix(module) {
+                Some(setting) => setting,
+                None => continue,
+            };
+
+            match setting {
+                "_cache" => {
+                    context.zones[i] = directive
+                        .args
+                        .first()
+                        .filter(|zone| zone.as_str() != "off" && !zone.contains('$'))
+                        .cloned();
+                },
+                "_cache_key" => context.keys[i] = directive.args.first().cloned(),
+                "_pass" => passes[i] = true,
+                _ => (),
+            }
+        }
+    }
+
+    for (i, module) in CACHE_MODULES.iter().enumerate() {
+        if !passes[i] {
+            continue;
+        }
+
+        let zone = match context.zones[i].as_ref() {
+            Some(zone) => zone,
+            None => continue,
+        };
+
+        let key = match context.keys[i].as_deref() {
+            Some(key) => key,
+            // the default value of `proxy_cache_key`; the other modules have none
+            None if *module == "proxy" => "$scheme$proxy_host$request_uri",
+            None => continue,
+        };
+
+        if let Some(zone) = zones.iter_mut().find(|z| z.name == *zone && z.module == *module) {
+            if !zone.cache_keys.iter().any(|k| k == key) {
+                zone.cache_keys.push(key.to_string());
+            }
+        }
+    }
+
+    for directive in directives {
+        if let Some(block) = directive.block.as_ref() {
+            collect_cache_keys(block, context.clone(), zones);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DUMP: &str = r#"nginx: the configuration file /etc/nginx/nginx.conf syntax is ok
+# configuration file /etc/nginx/nginx.conf:
+http {
+    proxy_cache_path /var/cache/nginx/a levels=1:2 keys_zone=a:10m max_size=1g inactive=7d;
+    include conf.d/*.conf;
+    proxy_cache a;
+
+    server {
+        location / {
+            proxy_pass http://backend;
+        }
+
+        location /api {
+            proxy_cache_key "$host${request_uri}";
+            proxy_pass http://backend;
+        }
+
+        location /off {
+            proxy_cache off;
+            proxy_cache_key $uri;
+            proxy_pass http://backend;
+        }
+    }
+}
+
+# configuration file /etc/nginx/conf.d/b.conf:
+fastcgi_cache_path '/var/cache/nginx/b c' keys_zone=b; # no levels
+server {
+    fastcgi_cache b;
+    fastcgi_cache_key $scheme\ $request_uri;
+    location ~ \.php$ { fastcgi_pass unix:/run/php.sock; }
+}
+"#;
+
+    #[test]
+    fn read_zones_from_dump() {
+        let zones = read_config_dump(DUMP).unwrap();
+
+        assert_eq!(zones.len(), 2);
+
+        let a = find_zone(&zones, "a").unwrap();
+
+        assert_eq!(a.module, "proxy");
+        assert_eq!(a.cache_path, Path::new("/var/cache/nginx/a"));
+        assert_eq!(a.levels().unwrap(), "1:2");
+        assert_eq!(a.keys_zone_size.as_deref(), Some("10m"));
+        assert_eq!(a.max_size.as_deref(), Some("1g"));
+        assert_eq!(a.inactive.as_deref(), Some("7d"));
+        assert_eq!(a.cache_keys, ["$scheme$proxy_host$request_uri", "$host${request_uri}"]);
+        assert!(a.cache_key().is_err());
+
+        let b = find_zone(&zones, "b").unwrap();
+
+        assert_eq!(b.module, "fastcgi");
+        assert_eq!(b.cache_path, Path::new("/var/cache/nginx/b c"));
+        assert!(b.levels().is_err());
+        assert_eq!(b.keys_zone_size, None);
+        assert_eq!(b.cache_key().unwrap(), "$scheme $request_uri");
+
+        assert!(matches!(find_zone(&zones, "c"), Err(PurgeError::ZoneNotFound(_))));
+    }
+
+    #[test]
+    fn invalid_configs() {
+        assert!(matches!(
+            read_config_dump("nginx: the configuration file is ok"),
+            Err(PurgeError::InvalidArguments(_))
+        ));
+
+        assert!(matches!(read_config_file("/nonexistent/nginx.conf"), Err(PurgeError::Io { .. })));
+
+        for content in ["http {", "}", "a b", "a 'b;", "{ a; }"] {
+            assert!(parse(content).is_err(), "{content}");
+        }
+
+        let dump = "# configuration file /a.conf:\nhttp {\n";
+
+        assert!(matches!(read_config_dump(dump), Err(PurgeError::InvalidArguments(_))));
+
+        let dump = "# configuration file /a.conf:\ninclude b.conf;\n";
+
+        assert!(read_config_dump(dump).is_err());
+
+        let dump = "# configuration file /a.conf:\ninclude a.conf;\n";
+
+        assert!(read_config_dump(dump).is_err());
+    }
+}
@@ -0,0 +1,62 @@
+use std::path::{Path, PathBuf};
+
+use crate::matcher::{KeyMatcher, MatchMode};
+
+/// The key patterns which can never be purged, in the `wildcard` mode.
+#[derive(Debug, Default)]
+pub struct ProtectedKeys {
+    /// the patterns which protect the caches in every cache zone
+    global: Vec<String>,
+    /// (cache path, its canonical form if it exists, pattern)
+    zones:  Vec<(PathBuf, Option<PathBuf>, String)>,
+}
+
+impl ProtectedKeys {
+    /// Create the protected key patterns for all cache zones and for specific cache paths.
+    pub fn new<P: AsRef<Path>>(
+        global: Vec<String>,
+        zones: Vec<(P, String)>,
+    ) -> anyhow::Result<Self> {
+        for pattern in global.iter().chain(zones.iter().map(|(_, pattern)| pattern)) {
+            KeyMatcher::new_many(&[pattern], MatchMode::Wildcard)?;
+        }
+
+        let zones = zones
+            .into_iter()
+            .map(|(cache_path, pattern)| {
+                let cache_path = cache_path.as_ref();
+
+                (cache_path.to_path_buf(), cache_path.canonicalize().ok(), pattern)
+            })
+            .collect();
+
+        Ok(ProtectedKeys {
+            global,
+            zones,
+        })
+    }
+
+    /// Get the protected patterns of a cache zone, which can be set to `PurgeOptions::protect`.
+    pub fn patterns(&self, cache_path: &Path) -> Vec<String> {
+        if self.zones.is_empty() {
+            return self.global.clone();
+        }
+
+        let canonical = cache_path.canonicalize().ok();
+
+        self.global
+            .iter()
+            .chain(
+                self.zones
+                    .iter()
+                    .filter(|(path, canonical_path, _)| {
+                        path == cache_path
+                            || canonical_path.is_some() && *canonical_path == canonical
+                            || canonical.as_deref() == Some(path.as_path())
+                    })
+                    .map(|(_, _, pattern)| pattern),
+            )
+            .cloned()
+            .collect()
+    }
+}
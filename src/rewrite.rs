@@ -0,0 +1,146 @@
+use anyhow::{anyhow, Context};
+use regex::Regex;
+
+/// A rule which rewrites the keys of the purge requests of a cache zone into cache keys.
+#[derive(Debug, Clone)]
+pub enum RewriteRule {
+    /// Remove the prefix if the key starts with it.
+    StripPrefix(String),
+    /// Replace the first match of the regular expression. The replacement can refer to the capture groups like `$1`.
+    Replace { regex: Regex, replacement: String },
+    /// Remove the query parameter with this name.
+    RemoveQuery(String),
+    /// Set the query parameter. The parameters with the same name are removed first.
+    AddQuery { name: String, value: String },
+}
+
+impl RewriteRule {
+    #[inline]
+    pub fn replace<R: AsRef<str>, T: Into<String>>(
+        regex: R,
+        replacement: T,
+    ) -> anyhow::Result<Self> {
+        let regex = regex.as_ref();
+
+        Ok(RewriteRule::Replace {
+            regex:       Regex::new(regex)
+                .with_context(|| anyhow!("{regex:?} is not a valid regular expression."))?,
+            replacement: replacement.into(),
+        })
+    }
+
+    pub fn apply(&self, key: String) -> String {
+        match self {
+            RewriteRule::StripPrefix(prefix) => match key.strip_prefix(prefix.as_str()) {
+                Some(key) => key.to_string(),
+                None => key,
+            },
+            RewriteRule::Replace {
+                regex,
+                replacement,
+            } => regex.replace(&key, replacement.as_str()).into_owned(),
+            RewriteRule::RemoveQuery(name) => {
+                let (path, params) = split_query(&key);
+
+                join_query(path, params.into_iter().filter(|(n, _)| n != name))
+            },
+            RewriteRule::AddQuery {
+                name,
+                value,
+            } => {
+                let (path, params) = split_query(&key);
+
+                join_query(
+                    path,
+                    params
+                        .into_iter()
+                        .filter(|(n, _)| n != name)
+                        .chain([(name.as_str(), Some(value.as_str()))]),
+                )
+            },
+        }
+    }
+}
+
+/// Apply the rules to a key one by one.
+#[inline]
+pub fn apply_rewrite_rules(rules: &[RewriteRule], key: String) -> String {
+    rules.iter().fold(key, |key, rule| rule.apply(key))
+}
+
+/// Split a key into the part before `?` and the query parameters.
+fn split_query(key: &str) -> (&str, Vec<(&str, Option<&str>)>) {
+    match key.split_once('?') {
+        Some((path, query)) => (
+            path,
+            query
+                .split('&')
+                .filter(|pair| !pair.is_empty())
+                .map(|pair| match pair.split_once('=') {
+                    Some((name, value)) => (name, Some(value)),
+                    None => (pair, None),
+                })
+                .collect(),
+        ),
+        None => (key, Vec::new()),
+    }
+}
+
+fn join_query<'a, I: Iterator<Item = (&'a str, Option<&'a str>)>>(path: &str, params: I) -> String {
+    let mut key = path.to_string();
+
+    for (i, (name, value)) in params.enumerate() {
+        key.push(if i == 0 { '?' } else { '&' });
+        key.push_str(name);
+
+        if let Some(value) = value {
+            key.push('=');
+            key.push_str(value);
+        }
+    }
+
+    key
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn remove_query() {
+        let rule = RewriteRule::RemoveQuery("utm".to_string());
+
+        assert_eq!(rule.apply("http/a?utm=1&b=2&utm&c".to_string()), "http/a?b=2&c");
+        assert_eq!(rule.apply("http/a?utm=1".to_string()), "http/a");
+        assert_eq!(rule.apply("http/a?&b=&&".to_string()), "http/a?b=");
+        assert_eq!(rule.apply("http/a".to_string()), "http/a");
+    }
+
+    #[test]
+    fn add_query() {
+        let rule = RewriteRule::AddQuery {
+            name: "v".to_string(), value: "2".to_string()
+        };
+
+        assert_eq!(rule.apply("http/a".to_string()), "http/a?v=2");
+        assert_eq!(rule.apply("http/a?v=1&b&v".to_string()), "http/a?b&v=2");
+        assert_eq!(rule.apply("http/a?".to_string()), "http/a?v=2");
+    }
+
+    #[test]
+    fn apply_rules_in_order() {
+        let rules = [
+            RewriteRule::StripPrefix("/cache".to_string()),
+            RewriteRule::replace("^/(\\w+)/", "http$1/").unwrap(),
+            RewriteRule::RemoveQuery("session".to_string()),
+        ];
+
+        assert_eq!(
+            apply_rewrite_rules(&rules, "/cache/get/img/a.jpg?session=x&w=100".to_string()),
+            "httpget/img/a.jpg?w=100"
+        );
+        assert_eq!(apply_rewrite_rules(&rules, "a?session".to_string()), "a");
+
+        assert!(RewriteRule::replace("(", "").is_err());
+    }
+}
@@ -0,0 +1,164 @@
+use std::{os::unix::fs::MetadataExt, path::Path};
+
+use crate::{error::PurgeError, functions::format_levels};
+
+/// Directories which are never purged as a whole, even with `--force`.
+const SYSTEM_DIRECTORIES: [&str; 29] = [
+    "/",
+    "/bin",
+    "/boot",
+    "/dev",
+    "/etc",
+    "/home",
+    "/lib",
+    "/lib32",
+    "/lib64",
+    "/media",
+    "/mnt",
+    "/opt",
+    "/proc",
+    "/root",
+    "/run",
+    "/sbin",
+    "/srv",
+    "/sys",
+    "/tmp",
+    "/usr",
+    "/usr/lib",
+    "/usr/local",
+    "/usr/share",
+    "/var",
+    "/var/cache",
+    "/var/lib",
+    "/var/log",
+    "/var/tmp",
+    "/var/www",
+];
+
+/// Check a directory before all of the files in it are removed. `path` should be canonical.
+///
+/// System directories are always refused. Mount roots and directories whose top-level entries are not the level directories of `levels` are refused unless `force` is `true`.
+pub(crate) fn check_directory_to_wipe(
+    path: &Path,
+    levels: &[usize],
+    force: bool,
+) -> Result<(), PurgeError> {
+    if SYSTEM_DIRECTORIES.iter().any(|directory| path == Path::new(directory))
+        || std::env::var_os("HOME").is_some_and(|home| path == Path::new(&home))
+    {
+        return Err(PurgeError::Refused(format!(
+            "{path:?} is a system directory and cannot be purged."
+        )));
+    }
+
+    if force {
+        return Ok(());
+    }
+
+    if let Some(parent) = path.parent() {
+        let metadata = path.metadata().map_err(|error| PurgeError::io(path, error))?;
+        let parent_metadata = parent.metadata().map_err(|error| PurgeError::io(parent, error))?;
+
+        if metadata.dev() != parent_metadata.dev() {
+            return Err(PurgeError::Refused(format!(
+                "{path:?} is a mount root. Use the --force option to purge it anyway."
+            )));
+        }
+    }
+
+    for dir_entry in path.read_dir().map_err(|error| PurgeError::io(path, error))? {
+        let dir_entry = dir_entry.map_err(|error| PurgeError::io(path, error))?;
+
+        let is_dir = dir_entry.file_type().map(|file_type| file_type.is_dir()).unwrap_or(false);
+
+        if !is_dir || !is_level_directory_name(&dir_entry.file_name().to_string_lossy(), levels[0])
+        {
+            return Err(PurgeError::Refused(format!(
+                "{path:?} does not look like a cache zone whose levels are {} because of {:?}. \
+                 Use the --force option to purge it anyway.",
+                format_levels(levels),
+                dir_entry.file_name()
+            )));
+        }
+    }
+
+    Ok(())
+}
+
+#[inline]
+fn is_level_directory_name(name: &str, level: usize) -> bool {
+    name.len() == level && name.bytes().all(|u| matches!(u, b'0'..=b'9' | b'a'..=b'f'))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+    use crate::{test_zone::TestZone, AppResult, CacheZone, MatchOptions, PurgeOptions};
+
+    #[test]
+    fn refuse_system_directories() {
+        for path in ["/", "/etc", "/var/cache"] {
+            assert!(
+                matches!(
+                    check_directory_to_wipe(Path::new(path), &[1, 2], true),
+                    Err(PurgeError::Refused(_))
+                ),
+                "{path}"
+            );
+        }
+    }
+
+    #[test]
+    fn refuse_directories_not_like_cache_zones() {
+        let test_zone = TestZone::new("safety", "1:2");
+
+        test_zone.add("http/blog/", "");
+
+        assert!(check_directory_to_wipe(&test_zone.path, &[1, 2], false).is_ok());
+
+        // the level directories are one hex digit
+        assert!(matches!(
+            check_directory_to_wipe(&test_zone.path, &[2], false),
+            Err(PurgeError::Refused(_))
+        ));
+
+        fs::write(test_zone.path.join("index.html"), "").unwrap();
+
+        assert!(matches!(
+            check_directory_to_wipe(&test_zone.path, &[1, 2], false),
+            Err(PurgeError::Refused(_))
+        ));
+        assert!(check_directory_to_wipe(&test_zone.path, &[1, 2], true).is_ok());
+    }
+
+    #[test]
+    fn purge_all_only_with_force() {
+        let test_zone = TestZone::new("safety-purge", "1:2");
+
+        test_zone.add("http/blog/", "");
+        fs::create_dir(test_zone.path.join("www")).unwrap();
+
+        let files = test_zone.files();
+
+        let zone = CacheZone::new(&test_zone.path, "1:2").unwrap();
+
+        assert!(matches!(
+            zone.purge_pattern_blocking("*", MatchOptions::default()),
+            Err(PurgeError::Refused(_))
+        ));
+        assert_eq!(test_zone.files(), files);
+
+        let zone = zone.with_options(PurgeOptions {
+            force: true,
+            ..PurgeOptions::default()
+        });
+
+        assert!(matches!(
+            zone.purge_pattern_blocking("*", MatchOptions::default()).unwrap(),
+            AppResult::Ok
+        ));
+        assert!(test_zone.files().is_empty());
+    }
+}
@@ -1,20 +1,23 @@
 use std::{
+    collections::HashMap,
     fs::Permissions,
     io,
     io::IsTerminal,
     os::unix::fs::{FileTypeExt, PermissionsExt},
     path::{Path, PathBuf},
+    sync::Arc,
 };
 
 use anyhow::{anyhow, Context as AnyhowContext};
 use axum::{
+    extract::State,
     http::{header, HeaderValue, StatusCode},
     response::IntoResponse,
-    routing::any,
-    Router,
+    routing::{any, post},
+    Json, Router,
 };
 use axum_extra::extract::Query;
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 use tokio::{fs, net::UnixListener};
 use tower_http::{
     set_header::SetResponseHeaderLayer,
@@ -23,7 +26,55 @@ use tower_http::{
 use tracing::Level;
 use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt, EnvFilter};
 
-use crate::{purge, uds_serve::serve, AppResult};
+use crate::{
+    error::PurgeError,
+    functions::{self, BatchItem, PurgeOptions},
+    index::IndexStore,
+    key_template::{render_keys, KeyTemplate},
+    matcher::{MatchMode, MatchOptions},
+    protect::ProtectedKeys,
+    rewrite::{apply_rewrite_rules, RewriteRule},
+    uds_serve::serve,
+    AppResult,
+};
+
+/// A named cache zone.
+#[derive(Debug, Clone)]
+pub struct Zone {
+    pub cache_path:    PathBuf,
+    pub levels:        String,
+    pub key_template:  Option<KeyTemplate>,
+    /// the rules which rewrite the keys of the purge requests, in the order they are applied
+    pub rewrite_rules: Vec<RewriteRule>,
+    /// the maximum number of caches which a wildcard purge can remove
+    pub max_entries:   Option<usize>,
+}
+
+/// The settings of the named cache zones, which refer to the zones by their names.
+#[derive(Debug, Default)]
+pub struct ZoneSettings<'a> {
+    pub key_templates: Vec<(&'a str, &'a str)>,
+    pub rewrite_rules: Vec<(&'a str, RewriteRule)>,
+    pub max_entries:   Vec<(&'a str, usize)>,
+    /// the key patterns which can never be purged in the zones
+    pub protect:       Vec<(&'a str, String)>,
+}
+
+/// (cache path, levels)
+type ZoneLocation = (PathBuf, String);
+
+/// The items of a batch purge in the same zone, with the smallest limit of the items.
+type BatchGroup = (ZoneLocation, Option<usize>, Vec<(usize, BatchItem)>);
+
+#[derive(Debug, Clone)]
+struct AppState {
+    indexes:       Option<Arc<IndexStore>>,
+    /// name -> zone
+    zones:         Arc<HashMap<String, Zone>>,
+    /// the options of every purge, without its limit and the protected keys of its zone
+    purge_options: Arc<PurgeOptions>,
+    protected:     Arc<ProtectedKeys>,
+}
 
 #[derive(Debug, Deserialize)]
 #[serde(untagged)]
@@ -42,44 +93,588 @@ impl From<OneOrManyString> for Vec<String> {
     }
 }
 
+/// Find the cache zone by its name, or by its `cache_path` and `levels`.
+fn resolve_zone(
+    zones: &HashMap<String, Zone>,
+    zone: Option<String>,
+    cache_path: Option<PathBuf>,
+    levels: Option<String>,
+) -> Result<Zone, PurgeError> {
+    match (zone, cache_path, levels) {
+        (Some(name), None, None) => zones.get(&name).cloned().ok_or(PurgeError::ZoneNotFound(name)),
+        (None, Some(cache_path), Some(levels)) => Ok(Zone {
+            max_entries: zone_max_entries(zones, cache_path.as_path()),
+            cache_path,
+            levels,
+            key_template: None,
+            rewrite_rules: Vec::new(),
+        }),
+        _ => Err(PurgeError::InvalidArguments(String::from(
+            "Either `zone` or both `cache_path` and `levels` are needed.",
+        ))),
+    }
+}
+
+/// Collect the keys rewritten by the rules of the zone, and the ones rendered from the URLs by the key template.
+fn resolve_keys(
+    zone: &Zone,
+    keys: Option<OneOrManyString>,
+    urls: Option<OneOrManyString>,
+    key_template: Option<String>,
+    method: Option<String>,
+    headers: Option<OneOrManyString>,
+    options: &MatchOptions,
+) -> Result<Vec<String>, PurgeError> {
+    let mut keys: Vec<String> = keys
+        .map(|k| {
+            Vec::from(k)
+                .into_iter()
+                .map(|key| apply_rewrite_rules(&zone.rewrite_rules, key))
+                .collect()
+        })
+        .unwrap_or_default();
+
+    if let Some(urls) = urls {
+        let urls: Vec<String> = urls.into();
+        let headers: Vec<String> = headers.map(|h| h.into()).unwrap_or_default();
+        let method = method.as_deref().unwrap_or("GET");
+
+        let rendered = match key_template {
+            Some(key_template) => KeyTemplate::parse(key_template).and_then(|key_template| {
+                render_keys(&key_template, &urls, method, &headers, options)
+            }),
+            None => match zone.key_template.as_ref() {
+                Some(key_template) => render_keys(key_template, &urls, method, &headers, options),
+                None => {
+                    return Err(PurgeError::InvalidArguments(String::from(
+                        "A key template is needed to purge URLs.",
+                    )))
+                },
+            },
+        }
+        .map_err(|error| PurgeError::InvalidArguments(format!("{error:#}")))?;
+
+        keys.extend(rendered);
+    }
+
+    if keys.is_empty() {
+        return Err(PurgeError::InvalidArguments(String::from(
+            "At least one key or URL is needed.",
+        )));
+    }
+
+    Ok(keys)
+}
+
+/// The smallest limit of the named zones in a cache path, so that the limit also applies when a zone is requested by its `cache_path`.
+fn zone_max_entries(zones: &HashMap<String, Zone>, cache_path: &Path) -> Option<usize> {
+    let canonical = cache_path.canonicalize().ok();
+
+    zones
+        .values()
+        .filter(|zone| {
+            zone.max_entries.is_some()
+                && (zone.cache_path == cache_path
+                    || canonical.is_some() && zone.cache_path.canonicalize().ok() == canonical)
+        })
+        .filter_map(|zone| zone.max_entries)
+        .min()
+}
+
+/// The limit of a wildcard purge in a zone. The limit of the zone is lifted if `lift_max_entries` is `true`, but the limit of the request still applies.
+#[inline]
+fn resolve_max_entries(
+    zone_max_entries: Option<usize>,
+    max_entries: Option<usize>,
+    lift_max_entries: bool,
+) -> Option<usize> {
+    match (max_entries, zone_max_entries) {
+        (Some(a), Some(b)) if !lift_max_entries => Some(a.min(b)),
+        (None, Some(b)) if !lift_max_entries => Some(b),
+        _ => max_entries,
+    }
+}
+
+/// The options of a purge in a cache zone, with the limit of the purge and the protected keys of the zone.
+#[inline]
+fn zone_purge_options(
+    purge_options: &PurgeOptions,
+    protected: &ProtectedKeys,
+    cache_path: &Path,
+    max_entries: Option<usize>,
+) -> PurgeOptions {
+    PurgeOptions {
+        max_entries,
+        protect: protected.patterns(cache_path),
+        ..purge_options.clone()
+    }
+}
+
 #[derive(Debug, Deserialize)]
 struct Args {
-    cache_path:   PathBuf,
-    levels:       String,
-    key:          String,
-    remove_first: Option<String>,
-    exclude_keys: Option<OneOrManyString>,
+    zone:             Option<String>,
+    cache_path:       Option<PathBuf>,
+    levels:           Option<String>,
+    key:              Option<OneOrManyString>,
+    url:              Option<OneOrManyString>,
+    key_template:     Option<String>,
+    method:           Option<String>,
+    header:           Option<OneOrManyString>,
+    remove_first:     Option<String>,
+    exclude_keys:     Option<OneOrManyString>,
+    #[serde(rename = "match", default)]
+    match_mode:       MatchMode,
+    #[serde(default)]
+    ignore_host_case: bool,
+    #[serde(default)]
+    decode_percent:   bool,
+    #[serde(default)]
+    collapse_slashes: bool,
+    max_entries:      Option<usize>,
+    #[serde(default)]
+    lift_max_entries: bool,
 }
 
 async fn index_handler(
+    State(AppState {
+        indexes,
+        zones,
+        purge_options,
+        protected,
+    }): State<AppState>,
     Query(Args {
+        zone,
         cache_path,
         levels,
         mut key,
+        url,
+        key_template,
+        method,
+        header,
         remove_first,
         exclude_keys,
+        match_mode,
+        ignore_host_case,
+        decode_percent,
+        collapse_slashes,
+        max_entries,
+        lift_max_entries,
     }): Query<Args>,
 ) -> impl IntoResponse {
     if let Some(remove_first) = remove_first {
-        if let Some(index) = key.find(remove_first.as_str()) {
-            key.replace_range(index..index + remove_first.len(), "");
+        key = key.map(|key| {
+            let mut keys: Vec<String> = key.into();
+
+            for key in keys.iter_mut() {
+                if let Some(index) = key.find(remove_first.as_str()) {
+                    key.replace_range(index..index + remove_first.len(), "");
+                }
+            }
+
+            OneOrManyString::Many(keys)
+        });
+    }
+
+    let exclude_keys: Vec<String> = exclude_keys.map(|e| e.into()).unwrap_or_else(Vec::new);
+
+    let options = MatchOptions {
+        mode: match_mode,
+        ignore_host_case,
+        decode_percent,
+        collapse_slashes,
+    };
+
+    let (zone, keys) = match resolve_zone(&zones, zone, cache_path, levels).and_then(|zone| {
+        let keys = resolve_keys(&zone, key, url, key_template, method, header, &options)?;
+
+        Ok((zone, keys))
+    }) {
+        Ok(resolved) => resolved,
+        Err(error) => return create_response(Err(error)),
+    };
+
+    let purge_options = zone_purge_options(
+        &purge_options,
+        &protected,
+        zone.cache_path.as_path(),
+        resolve_max_entries(zone.max_entries, max_entries, lift_max_entries),
+    );
+
+    let result = match indexes {
+        Some(indexes) if keys.iter().any(|key| !matches!(options.literal(key), Ok(Some(_)))) => {
+            match indexes.get(&zone.cache_path, &zone.levels).await {
+                Ok(Some(index)) => {
+                    functions::remove_caches_with_index(
+                        index,
+                        keys,
+                        exclude_keys,
+                        options,
+                        &purge_options,
+                    )
+                    .await
+                },
+                Ok(None) => Ok(AppResult::AlreadyPurgedWildcard),
+                Err(error) => Err(PurgeError::read(&zone.cache_path, error)),
+            }
+        },
+        _ => {
+            functions::remove_caches(
+                zone.cache_path,
+                zone.levels,
+                keys,
+                exclude_keys,
+                options,
+                &purge_options,
+            )
+            .await
+        },
+    };
+
+    create_response(result)
+}
+
+#[derive(Debug, Deserialize)]
+struct TagArgs {
+    cache_path:       PathBuf,
+    levels:           String,
+    tags:             OneOrManyString,
+    tag_header:       Option<String>,
+    exclude_keys:     Option<OneOrManyString>,
+    max_entries:      Option<usize>,
+    #[serde(default)]
+    lift_max_entries: bool,
+}
+
+async fn tag_handler(
+    State(AppState {
+        indexes,
+        zones,
+        purge_options,
+        protected,
+    }): State<AppState>,
+    Query(TagArgs {
+        cache_path,
+        levels,
+        tags,
+        tag_header,
+        exclude_keys,
+        max_entries,
+        lift_max_entries,
+    }): Query<TagArgs>,
+) -> impl IntoResponse {
+    let purge_options = zone_purge_options(
+        &purge_options,
+        &protected,
+        cache_path.as_path(),
+        resolve_max_entries(
+            zone_max_entries(&zones, cache_path.as_path()),
+            max_entries,
+            lift_max_entries,
+        ),
+    );
+
+    let tags: Vec<String> = tags.into();
+    let tag_header = tag_header.unwrap_or_else(|| String::from("Cache-Tag"));
+    let exclude_keys: Vec<String> = exclude_keys.map(|e| e.into()).unwrap_or_else(Vec::new);
+
+    let result = match indexes {
+        Some(indexes) => match indexes.get(&cache_path, &levels).await {
+            Ok(Some(index)) => {
+                functions::remove_caches_via_tags_with_index(
+                    index,
+                    tag_header,
+                    tags,
+                    exclude_keys,
+                    &purge_options,
+                )
+                .await
+            },
+            Ok(None) => Ok(AppResult::AlreadyPurgedWildcard),
+            Err(error) => Err(PurgeError::read(&cache_path, error)),
+        },
+        None => {
+            functions::remove_caches_via_tags(
+                cache_path,
+                levels,
+                tag_header,
+                tags,
+                exclude_keys,
+                &purge_options,
+            )
+            .await
+        },
+    };
+
+    create_response(result)
+}
+
+#[derive(Debug, Deserialize)]
+struct BatchItemArgs {
+    zone:             Option<String>,
+    cache_path:       Option<PathBuf>,
+    levels:           Option<String>,
+    key:              Option<OneOrManyString>,
+    url:              Option<OneOrManyString>,
+    key_template:     Option<String>,
+    method:           Option<String>,
+    header:           Option<OneOrManyString>,
+    exclude_keys:     Option<OneOrManyString>,
+    #[serde(rename = "match", default)]
+    match_mode:       MatchMode,
+    #[serde(default)]
+    ignore_host_case: bool,
+    #[serde(default)]
+    decode_percent:   bool,
+    #[serde(default)]
+    collapse_slashes: bool,
+    max_entries:      Option<usize>,
+    #[serde(default)]
+    lift_max_entries: bool,
+}
+
+#[derive(Debug, Serialize)]
+struct BatchItemResult {
+    status: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    path:   Option<PathBuf>,
+    /// the kind of the error, such as `zone_not_found`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    kind:   Option<&'static str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error:  Option<String>,
+}
+
+#[derive(Debug, Default, Serialize)]
+struct BatchCounts {
+    ok:                      usize,
+    already_purged:          usize,
+    cache_ignored:           usize,
+    already_purged_wildcard: usize,
+    too_many_entries:        usize,
+    error:                   usize,
+}
+
+#[derive(Debug, Serialize)]
+struct BatchResponse {
+    results: Vec<BatchItemResult>,
+    counts:  BatchCounts,
+}
+
+async fn batch_handler(
+    State(AppState {
+        indexes,
+        zones,
+        purge_options,
+        protected,
+    }): State<AppState>,
+    Json(items): Json<Vec<BatchItemArgs>>,
+) -> impl IntoResponse {
+    let mut results: Vec<Option<Result<AppResult, PurgeError>>> =
+        items.iter().map(|_| None).collect();
+
+    // the items grouped by their cache paths and levels
+    let mut groups: Vec<BatchGroup> = Vec::new();
+
+    for (i, item) in items.into_iter().enumerate() {
+        let options = MatchOptions {
+            mode:             item.match_mode,
+            ignore_host_case: item.ignore_host_case,
+            decode_percent:   item.decode_percent,
+            collapse_slashes: item.collapse_slashes,
+        };
+
+        let resolved =
+            resolve_zone(&zones, item.zone, item.cache_path, item.levels).and_then(|zone| {
+                let keys = resolve_keys(
+                    &zone,
+                    item.key,
+                    item.url,
+                    item.key_template,
+                    item.method,
+                    item.header,
+                    &options,
+                )?;
+
+                Ok((zone, keys))
+            });
+
+        let (zone, keys) = match resolved {
+            Ok(resolved) => resolved,
+            Err(error) => {
+                results[i] = Some(Err(error));
+
+                continue;
+            },
+        };
+
+        let max_entries =
+            resolve_max_entries(zone.max_entries, item.max_entries, item.lift_max_entries);
+
+        let zone = (zone.cache_path, zone.levels);
+
+        let batch_item = BatchItem {
+            keys,
+            exclude_keys: item.exclude_keys.map(|e| e.into()).unwrap_or_else(Vec::new),
+            options,
+        };
+
+        match groups.iter_mut().find(|(z, ..)| *z == zone) {
+            Some((_, group_max_entries, group)) => {
+                *group_max_entries = match (*group_max_entries, max_entries) {
+                    (Some(a), Some(b)) => Some(a.min(b)),
+                    (a, b) => a.or(b),
+                };
+
+                group.push((i, batch_item));
+            },
+            None => groups.push((zone, max_entries, vec![(i, batch_item)])),
         }
     }
 
-    match purge(cache_path, levels, key, exclude_keys.map(|e| e.into()).unwrap_or_else(Vec::new))
-        .await
-    {
+    for ((cache_path, levels), max_entries, group) in groups {
+        let (item_indices, batch_items): (Vec<usize>, Vec<BatchItem>) = group.into_iter().unzip();
+
+        let purge_options =
+            zone_purge_options(&purge_options, &protected, cache_path.as_path(), max_entries);
+
+        let index = match indexes.as_ref() {
+            Some(indexes) => indexes.get(&cache_path, &levels).await,
+            None => Ok(None),
+        };
+
+        let zone_results = match index {
+            Ok(Some(index)) => {
+                functions::remove_caches_via_batch(
+                    &cache_path,
+                    &levels,
+                    Some(index),
+                    batch_items,
+                    &purge_options,
+                )
+                .await
+            },
+            Ok(None) => {
+                functions::remove_caches_via_batch(
+                    &cache_path,
+                    &levels,
+                    None,
+                    batch_items,
+                    &purge_options,
+                )
+                .await
+            },
+            Err(error) => Err(PurgeError::read(&cache_path, error)),
+        };
+
+        match zone_results {
+            Ok(zone_results) => {
+                for (i, result) in item_indices.into_iter().zip(zone_results) {
+                    results[i] = Some(result);
+                }
+            },
+            Err(error) => {
+                for i in item_indices {
+                    results[i] = Some(Err(error.clone()));
+                }
+            },
+        }
+    }
+
+    let mut counts = BatchCounts::default();
+
+    let results: Vec<BatchItemResult> = results
+        .into_iter()
+        .map(|result| {
+            let (status, path, kind, error) = match result
+                .unwrap_or(Ok(AppResult::AlreadyPurgedWildcard))
+            {
+                Ok(AppResult::Ok) => {
+                    counts.ok += 1;
+
+                    ("ok", None, None, None)
+                },
+                Ok(AppResult::AlreadyPurged(file_path)) => {
+                    counts.already_purged += 1;
+
+                    ("already_purged", Some(file_path), None, None)
+                },
+                Ok(AppResult::CacheIgnored) => {
+                    counts.cache_ignored += 1;
+
+                    ("cache_ignored", None, None, None)
+                },
+                Ok(AppResult::AlreadyPurgedWildcard) => {
+                    counts.already_purged_wildcard += 1;
+
+                    ("already_purged_wildcard", None, None, None)
+                },
+                Ok(AppResult::TooManyEntries(_)) => {
+                    counts.too_many_entries += 1;
+
+                    ("too_many_entries", None, None, None)
+                },
+                Err(error) => {
+                    counts.error += 1;
+
+                    let kind = error.kind();
+
+                    ("error", None, Some(kind), Some(format!("{:#}", anyhow::Error::from(error))))
+                },
+            };
+
+            BatchItemResult {
+                status,
+                path,
+                kind,
+                error,
+            }
+        })
+        .collect();
+
+    let status_code = if counts.too_many_entries > 0 {
+        StatusCode::CONFLICT
+    } else if counts.ok > 0 {
+        StatusCode::OK
+    } else if counts.error > 0 {
+        StatusCode::INTERNAL_SERVER_ERROR
+    } else {
+        StatusCode::ACCEPTED
+    };
+
+    (
+        status_code,
+        Json(BatchResponse {
+            results,
+            counts,
+        }),
+    )
+}
+
+#[inline]
+fn create_response(result: Result<AppResult, PurgeError>) -> (StatusCode, String) {
+    match result {
         Ok(result) => match result {
             AppResult::Ok => (StatusCode::OK, "Ok.".to_string()),
+            AppResult::TooManyEntries(max_entries) => (
+                StatusCode::CONFLICT,
+                format!(
+                    "The purge stopped after removing {max_entries} caches because of the limit."
+                ),
+            ),
             _ => (StatusCode::ACCEPTED, "No cache needs to be purged.".to_string()),
         },
-        Err(error) => (StatusCode::INTERNAL_SERVER_ERROR, format!("{error:?}")),
+        Err(error) => (
+            StatusCode::from_u16(error.status_code()).unwrap_or(StatusCode::INTERNAL_SERVER_ERROR),
+            format!("{}: {:#}", error.kind(), anyhow::Error::from(error)),
+        ),
     }
 }
 
-fn create_app() -> Router {
+fn create_app(state: AppState) -> Router {
     Router::new()
         .route("/", any(index_handler))
+        .route("/tag", any(tag_handler))
+        .route("/purge", post(batch_handler))
         .layer(SetResponseHeaderLayer::overriding(
             header::CACHE_CONTROL,
             HeaderValue::from_static("no-store"),
@@ -90,9 +685,19 @@ fn create_app() -> Router {
                 .on_request(DefaultOnRequest::new().level(Level::INFO))
                 .on_response(DefaultOnResponse::new().level(Level::INFO)),
         )
+        .with_state(state)
 }
 
-pub async fn server_main(socket_file_path: &Path) -> anyhow::Result<AppResult> {
+/// Serve the purge API. `purge_options` applies to every purge, and its protected keys are the ones of every zone.
+pub async fn server_main(
+    socket_file_path: &Path,
+    index_dir: Option<&Path>,
+    index_tag_header: &str,
+    zones: Vec<(&str, &str, &str)>,
+    zone_settings: ZoneSettings<'_>,
+    watch_zones: Vec<(&str, &str)>,
+    purge_options: PurgeOptions,
+) -> anyhow::Result<AppResult> {
     let mut ansi_color = io::stdout().is_terminal();
 
     if ansi_color && enable_ansi_support::enable_ansi_support().is_err() {
@@ -104,7 +709,83 @@ pub async fn server_main(socket_file_path: &Path) -> anyhow::Result<AppResult> {
         .with(EnvFilter::builder().with_default_directive(Level::INFO.into()).from_env_lossy())
         .init();
 
-    let app = create_app();
+    let indexes = match index_dir {
+        Some(index_dir) => {
+            fs::create_dir_all(index_dir).await.with_context(|| anyhow!("{index_dir:?}"))?;
+
+            Some(Arc::new(IndexStore::new(index_dir, index_tag_header)))
+        },
+        None => None,
+    };
+
+    if let Some(indexes) = indexes.as_ref() {
+        for (cache_path, levels) in watch_zones {
+            let index = indexes
+                .get(cache_path, levels)
+                .await?
+                .ok_or_else(|| anyhow!("{cache_path:?} does not exist"))?;
+
+            #[cfg(target_os = "linux")]
+            crate::watcher::spawn_watcher(index)?;
+            #[cfg(not(target_os = "linux"))]
+            drop(index);
+        }
+    }
+
+    let mut named_zones = HashMap::with_capacity(zones.len());
+
+    for (name, cache_path, levels) in zones {
+        if levels != "auto" {
+            functions::parse_levels(levels)?;
+        }
+
+        named_zones.insert(name.to_string(), Zone {
+            cache_path:    PathBuf::from(cache_path),
+            levels:        levels.to_string(),
+            key_template:  None,
+            rewrite_rules: Vec::new(),
+            max_entries:   None,
+        });
+    }
+
+    for (name, key_template) in zone_settings.key_templates {
+        let zone =
+            named_zones.get_mut(name).ok_or_else(|| PurgeError::ZoneNotFound(name.to_string()))?;
+
+        zone.key_template = Some(KeyTemplate::parse(key_template)?);
+    }
+
+    for (name, rule) in zone_settings.rewrite_rules {
+        let zone =
+            named_zones.get_mut(name).ok_or_else(|| PurgeError::ZoneNotFound(name.to_string()))?;
+
+        zone.rewrite_rules.push(rule);
+    }
+
+    for (name, max_entries) in zone_settings.max_entries {
+        let zone =
+            named_zones.get_mut(name).ok_or_else(|| PurgeError::ZoneNotFound(name.to_string()))?;
+
+        zone.max_entries = Some(max_entries);
+    }
+
+    let mut protected_zones = Vec::with_capacity(zone_settings.protect.len());
+
+    for (name, pattern) in zone_settings.protect {
+        let zone =
+            named_zones.get(name).ok_or_else(|| PurgeError::ZoneNotFound(name.to_string()))?;
+
+        protected_zones.push((zone.cache_path.clone(), pattern));
+    }
+
+    let protected = ProtectedKeys::new(purge_options.protect.clone(), protected_zones)?;
+
+    let app = create_app(AppState {
+        indexes,
+        zones: Arc::new(named_zones),
+        purge_options: Arc::new(purge_options),
+        protected: Arc::new(protected),
+    });
 
     let uds = {
         match fs::metadata(socket_file_path).await {
@@ -0,0 +1,110 @@
+//! Cache zones of real cache files in temporary directories, for the tests.
+
+use std::{
+    env, fs,
+    path::{Path, PathBuf},
+    process,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use crate::functions::{create_cache_file_path, parse_levels};
+
+/// A cache zone in a new temporary directory, which is removed when this is dropped.
+pub(crate) struct TestZone {
+    pub(crate) path:   PathBuf,
+    pub(crate) levels: Vec<usize>,
+}
+
+impl TestZone {
+    /// Create an empty cache zone whose levels are like `1:2`. `name` makes the directory easier to find if a test fails.
+    pub(crate) fn new(name: &str, levels: &str) -> Self {
+        static COUNT: AtomicUsize = AtomicUsize::new(0);
+
+        let path = env::temp_dir().join(format!(
+            "nginx-cache-purge-{name}-{}-{}",
+            process::id(),
+            COUNT.fetch_add(1, Ordering::Relaxed)
+        ));
+
+        let _ = fs::remove_dir_all(&path);
+        fs::create_dir_all(&path).unwrap();
+
+        TestZone {
+            path: path.canonicalize().unwrap(), levels: parse_levels(levels).unwrap()
+        }
+    }
+
+    /// Write the cache file of `key` at the path nginx uses, with the response headers like `Cache-Tag: a b\r\n`. Return the path of the file.
+    pub(crate) fn add<K: AsRef<[u8]>>(&self, key: K, headers: &str) -> PathBuf {
+        let file_path = create_cache_file_path(&self.path, &self.levels, key.as_ref());
+
+        fs::create_dir_all(file_path.parent().unwrap()).unwrap();
+        fs::write(&file_path, cache_file(key.as_ref(), headers)).unwrap();
+
+        file_path
+    }
+
+    /// The paths of all files in this cache zone, sorted.
+    pub(crate) fn files(&self) -> Vec<PathBuf> {
+        fn collect(directory: &Path, files: &mut Vec<PathBuf>) {
+            for entry in fs::read_dir(directory).unwrap() {
+                let path = entry.unwrap().path();
+
+                if path.is_dir() {
+                    collect(&path, files);
+                } else {
+                    files.push(path);
+                }
+            }
+        }
+
+        let mut files = Vec::new();
+
+        collect(&self.path, &mut files);
+
+        files.sort();
+
+        files
+    }
+}
+
+impl Drop for TestZone {
+    #[inline]
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.path);
+    }
+}
+
+/// Build the content of a cache file like nginx 1.9.x and later writes on 64-bit platforms.
+pub(crate) fn cache_file(key: &[u8], headers: &str) -> Vec<u8> {
+    let response = format!("HTTP/1.1 200 OK\r\n{headers}\r\n");
+
+    // the fixed part of the header is 336 bytes, and none of them can be a newline because the key is read after the first one
+    let mut padding = 0;
+
+    let (header_start, body_start) = loop {
+        let header_start = (336 + padding + "\nKEY: ".len() + key.len() + 1) as u16;
+        let body_start = header_start + response.len() as u16;
+
+        if !header_start.to_ne_bytes().contains(&b'\n')
+            && !body_start.to_ne_bytes().contains(&b'\n')
+        {
+            break (header_start, body_start);
+        }
+
+        padding += 1;
+    };
+
+    let mut content = 5u64.to_ne_bytes().to_vec();
+
+    content.resize(336 + padding, 1);
+    content[54..56].copy_from_slice(&header_start.to_ne_bytes());
+    content[56..58].copy_from_slice(&body_start.to_ne_bytes());
+    content.extend_from_slice(b"\nKEY: ");
+    content.extend_from_slice(key);
+    content.push(b'\n');
+    content.extend_from_slice(response.as_bytes());
+    content.extend_from_slice(b"hello");
+
+    content
+}
@@ -0,0 +1,553 @@
+use std::{
+    fs::{self, OpenOptions},
+    io::{self, Write},
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{anyhow, Context};
+
+#[cfg(feature = "async")]
+use crate::functions::{remove_dir_all, remove_empty_ancestors, unblock};
+use crate::{
+    error::PurgeError,
+    functions::{move_file_blocking, remove_dir_all_blocking},
+};
+
+const MANIFEST_FILE_NAME: &str = "manifest";
+const FILES_DIRECTORY_NAME: &str = "files";
+
+/// Where the removed cache files of a purge are moved to, instead of being unlinked. It can be set to `PurgeOptions::trash`.
+#[derive(Debug)]
+pub struct Trash {
+    purge_id:   String,
+    /// `<trash directory>/<purge ID>`
+    path:       PathBuf,
+    cache_path: PathBuf,
+    /// the canonical form of `cache_path`, which is used by wildcard purges
+    canonical:  PathBuf,
+}
+
+impl Trash {
+    /// Create a purge of a cache zone in the trash directory. Return `None` if the cache path does not exist.
+    pub fn create<T: AsRef<Path>, P: AsRef<Path>>(
+        trash_dir: T,
+        cache_path: P,
+    ) -> anyhow::Result<Option<Self>> {
+        let trash_dir = trash_dir.as_ref();
+        let cache_path = cache_path.as_ref();
+
+        let canonical = match cache_path.canonicalize() {
+            Ok(canonical) => canonical,
+            Err(error) if error.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(error) => return Err(error).with_context(|| anyhow!("{cache_path:?}")),
+        };
+
+        let absolute_trash_dir =
+            absolute_path(trash_dir).with_context(|| anyhow!("{trash_dir:?}"))?;
+
+        if absolute_trash_dir.starts_with(canonical.as_path()) {
+            return Err(PurgeError::Refused(format!(
+                "The trash directory {trash_dir:?} cannot be in the cache zone {cache_path:?}."
+            ))
+            .into());
+        }
+
+        fs::create_dir_all(trash_dir).with_context(|| anyhow!("{trash_dir:?}"))?;
+
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+        let base_purge_id = format!("{}-{}", now.as_secs(), std::process::id());
+
+        let mut purge_id = base_purge_id.clone();
+        let mut n = 1;
+
+        // another purge may have been created in the same second, and its manifest must not be overwritten
+        let path = loop {
+            let path = trash_dir.join(&purge_id);
+
+            match fs::create_dir(path.as_path()) {
+                Ok(_) => break path,
+                Err(error) if error.kind() == io::ErrorKind::AlreadyExists => {
+                    purge_id = format!("{base_purge_id}-{n}");
+                    n += 1;
+                },
+                Err(error) => return Err(error).with_context(|| anyhow!("{path:?}")),
+            }
+        };
+
+        let files_path = path.join(FILES_DIRECTORY_NAME);
+
+        fs::create_dir(files_path.as_path()).with_context(|| anyhow!("{files_path:?}"))?;
+
+        let manifest_path = path.join(MANIFEST_FILE_NAME);
+
+        fs::write(
+            manifest_path.as_path(),
+            format!("cache_path\t{}\ncreated\t{}\n", canonical.to_string_lossy(), now.as_secs()),
+        )
+        .with_context(|| anyhow!("{manifest_path:?}"))?;
+
+        Ok(Some(Trash {
+            purge_id,
+            path,
+            cache_path: cache_path.to_path_buf(),
+            canonical,
+        }))
+    }
+
+    /// Finish the purge. Return its ID if anything has been moved into the trash, otherwise remove the empty purge from the trash.
+    pub fn finish(&self) -> anyhow::Result<Option<&str>> {
+        let manifest_path = self.path.join(MANIFEST_FILE_NAME);
+
+        let manifest = fs::read_to_string(manifest_path.as_path())
+            .with_context(|| anyhow!("{manifest_path:?}"))?;
+
+        if manifest.lines().any(|line| line.starts_with("file\t") || line.starts_with("dir\t")) {
+            Ok(Some(self.purge_id.as_str()))
+        } else {
+            fs::remove_dir_all(self.path.as_path()).with_context(|| anyhow!("{:?}", self.path))?;
+
+            Ok(None)
+        }
+    }
+
+    /// Move a file or a directory in the cache zone into the trash and record it in the manifest.
+    pub(crate) fn put(&self, path: &Path, is_dir: bool) -> io::Result<()> {
+        let relative_path = path
+            .strip_prefix(self.canonical.as_path())
+            .or_else(|_| path.strip_prefix(self.cache_path.as_path()))
+            .map_err(|_| {
+                io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("{path:?} is not in the cache zone {:?}", self.cache_path),
+                )
+            })?;
+
+        let to = self.path.join(FILES_DIRECTORY_NAME).join(relative_path);
+
+        if is_dir {
+            move_dir(path, to.as_path())?;
+        } else {
+            move_file_blocking(path, to.as_path())?;
+        }
+
+        // one small write with `O_APPEND` is atomic, so the concurrent purge tasks do not need a lock
+        let mut manifest =
+            OpenOptions::new().append(true).open(self.path.join(MANIFEST_FILE_NAME))?;
+
+        manifest.write_all(
+            format!(
+                "{}\t{}\n",
+                if is_dir { "dir" } else { "file" },
+                relative_path.to_string_lossy()
+            )
+            .as_bytes(),
+        )
+    }
+}
+
+/// Move a file without replacing the destination if it exists, which fails with `AlreadyExists`. The file is hard linked and then unlinked, or, if the link fails, such as across file systems, copied to a temporary file beside the destination which is linked instead.
+#[cfg(feature = "async")]
+fn move_file_no_replace(from: &Path, to: &Path) -> io::Result<()> {
+    if let Some(parent) = to.parent() {
+        fs::create_dir_all(parent)?;
+    }
+
+    match fs::hard_link(from, to) {
+        Ok(_) => return fs::remove_file(from),
+        Err(error)
+            if matches!(error.kind(), io::ErrorKind::AlreadyExists | io::ErrorKind::NotFound) =>
+        {
+            return Err(error)
+        },
+        Err(_) => (),
+    }
+
+    let mut temp = to.as_os_str().to_os_string();
+    temp.push(format!(".{}", std::process::id()));
+
+    let result = fs::copy(from, &temp).and_then(|_| fs::hard_link(&temp, to));
+
+    let _ = fs::remove_file(&temp);
+
+    result?;
+
+    fs::remove_file(from)
+}
+
+/// Make a path absolute with the canonical form of its nearest existing ancestor, so that a directory which has not been created yet can be compared with canonical paths.
+fn absolute_path(path: &Path) -> io::Result<PathBuf> {
+    let mut existing = path;
+    let mut missing = Vec::new();
+
+    loop {
+        match existing.canonicalize() {
+            Ok(canonical) => {
+                return Ok(missing.into_iter().rev().fold(canonical, |path, name| path.join(name)))
+            },
+            Err(error) if error.kind() == io::ErrorKind::NotFound => {
+                match (existing.parent(), existing.file_name()) {
+                    (Some(parent), Some(name)) => {
+                        missing.push(name);
+
+                        existing =
+                            if parent.as_os_str().is_empty() { Path::new(".") } else { parent };
+                    },
+                    _ => return Err(error),
+                }
+            },
+            Err(error) => return Err(error),
+        }
+    }
+}
+
+/// Move a directory with a rename, or move the files in it one by one if the rename fails, such as across file systems.
+fn move_dir(from: &Path, to: &Path) -> io::Result<()> {
+    if let Some(parent) = to.parent() {
+        fs::create_dir_all(parent)?;
+    }
+
+    match fs::rename(from, to) {
+        Ok(_) => return Ok(()),
+        Err(error) if error.kind() == io::ErrorKind::NotFound => return Err(error),
+        Err(_) => (),
+    }
+
+    let mut directories = vec![(from.to_path_buf(), to.to_path_buf())];
+
+    while let Some((from, to)) = directories.pop() {
+        for entry in fs::read_dir(from.as_path())? {
+            let entry = entry?;
+            let to = to.join(entry.file_name());
+
+            if entry.file_type()?.is_dir() {
+                directories.push((entry.path(), to));
+            } else {
+                move_file_blocking(entry.path(), to)?;
+            }
+        }
+    }
+
+    remove_dir_all_blocking(from)
+}
+
+/// A purge in the trash.
+#[derive(Debug)]
+pub struct TrashEntry {
+    pub purge_id:   String,
+    pub cache_path: PathBuf,
+    /// the time when the purge started
+    pub created:    SystemTime,
+    /// the removed files and directories, relative to the cache path
+    pub entries:    Vec<PathBuf>,
+}
+
+fn read_manifest(trash_dir: &Path, purge_id: &str) -> anyhow::Result<TrashEntry> {
+    let manifest_path = trash_dir.join(purge_id).join(MANIFEST_FILE_NAME);
+
+    let manifest = fs::read_to_string(manifest_path.as_path())
+        .with_context(|| anyhow!("{manifest_path:?}"))?;
+
+    let mut cache_path = None;
+    let mut created = None;
+    let mut entries = Vec::new();
+
+    for line in manifest.lines() {
+        match line.split_once('\t') {
+            Some(("cache_path", value)) => cache_path = Some(PathBuf::from(value)),
+            Some(("created", value)) => {
+                created = value.parse().ok().map(|secs| UNIX_EPOCH + Duration::from_secs(secs))
+            },
+            Some(("file" | "dir", value)) => entries.push(PathBuf::from(value)),
+            _ => (),
+        }
+    }
+
+    match (cache_path, created) {
+        (Some(cache_path), Some(created)) => Ok(TrashEntry {
+            purge_id: purge_id.to_string(),
+            cache_path,
+            created,
+            entries,
+        }),
+        _ => Err(anyhow!("The content of {manifest_path:?} is incorrect.")),
+    }
+}
+
+/// List the purges in the trash, from the oldest to the newest.
+pub fn list_trash<P: AsRef<Path>>(trash_dir: P) -> anyhow::Result<Vec<TrashEntry>> {
+    let trash_dir = trash_dir.as_ref();
+
+    let mut trash_entries = Vec::new();
+
+    let dir_entries = match fs::read_dir(trash_dir) {
+        Ok(dir_entries) => dir_entries,
+        Err(error) if error.kind() == io::ErrorKind::NotFound => return Ok(trash_entries),
+        Err(error) => return Err(error).with_context(|| anyhow!("{trash_dir:?}")),
+    };
+
+    for dir_entry in dir_entries {
+        let dir_entry = dir_entry.with_context(|| anyhow!("{trash_dir:?}"))?;
+
+        if !dir_entry.path().join(MANIFEST_FILE_NAME).is_file() {
+            continue;
+        }
+
+        trash_entries.push(read_manifest(trash_dir, &dir_entry.file_name().to_string_lossy())?);
+    }
+
+    trash_entries.sort_by(|a, b| a.created.cmp(&b.created).then(a.purge_id.cmp(&b.purge_id)));
+
+    Ok(trash_entries)
+}
+
+#[derive(Debug, Default)]
+pub struct RestoreReport {
+    pub restored: usize,
+    /// the files which have been recreated by nginx since the purge
+    pub skipped:  usize,
+}
+
+/// Move the files of a purge back into the cache zone, except for the ones which nginx has recreated in the meantime, and then remove the purge from the trash.
+#[cfg(feature = "async")]
+pub async fn restore<P: AsRef<Path>>(
+    trash_dir: P,
+    purge_id: &str,
+) -> anyhow::Result<RestoreReport> {
+    let trash_dir = trash_dir.as_ref();
+
+    if purge_id.is_empty() || purge_id.contains(['/', '\\']) || purge_id.starts_with('.') {
+        return Err(anyhow!("{purge_id:?} is not a purge ID."));
+    }
+
+    let trash_entry = read_manifest(trash_dir, purge_id)?;
+
+    let files_path = trash_dir.join(purge_id).join(FILES_DIRECTORY_NAME);
+
+    let mut report = RestoreReport::default();
+
+    let mut directories = vec![files_path.clone()];
+
+    while let Some(directory) = directories.pop() {
+        let mut entries = match tokio::fs::read_dir(directory.as_path()).await {
+            Ok(entries) => entries,
+            Err(error) if error.kind() == io::ErrorKind::NotFound => continue,
+            Err(error) => return Err(error).with_context(|| anyhow!("{directory:?}")),
+        };
+
+        while let Some(entry) =
+            entries.next_entry().await.with_context(|| anyhow!("{directory:?}"))?
+        {
+            let path = entry.path();
+
+            if entry.file_type().await.with_context(|| anyhow!("{path:?}"))?.is_dir() {
+                directories.push(path);
+
+                continue;
+            }
+
+            let relative_path = path.strip_prefix(files_path.as_path()).unwrap();
+            let to = trash_entry.cache_path.join(relative_path);
+
+            let (from, to_path) = (path.clone(), to.clone());
+
+            match unblock(move || move_file_no_replace(from.as_path(), to_path.as_path())).await {
+                Ok(_) => (),
+                // a newer cache has been stored
+                Err(error) if error.kind() == io::ErrorKind::AlreadyExists => {
+                    report.skipped += 1;
+
+                    continue;
+                },
+                Err(error) => return Err(error).with_context(|| anyhow!("{path:?} -> {to:?}")),
+            }
+
+            report.restored += 1;
+
+            remove_empty_ancestors(path.as_path(), relative_path.components().count() - 1).await?;
+        }
+    }
+
+    // the skipped files are outdated
+    let path = trash_dir.join(purge_id);
+
+    remove_dir_all(path.as_path()).await.with_context(|| anyhow!("{path:?}"))?;
+
+    Ok(report)
+}
+
+/// Permanently remove the purges which are older than `retention` from the trash. Return their IDs.
+#[cfg(feature = "async")]
+pub async fn gc<P: AsRef<Path>>(trash_dir: P, retention: Duration) -> anyhow::Result<Vec<String>> {
+    let trash_dir = trash_dir.as_ref();
+
+    let mut removed = Vec::new();
+
+    for trash_entry in list_trash(trash_dir)? {
+        let age = SystemTime::now().duration_since(trash_entry.created).unwrap_or_default();
+
+        if age <= retention {
+            continue;
+        }
+
+        let path = trash_dir.join(trash_entry.purge_id.as_str());
+
+        remove_dir_all(path.as_path()).await.with_context(|| anyhow!("{path:?}"))?;
+
+        removed.push(trash_entry.purge_id);
+    }
+
+    Ok(removed)
+}
+
+/// Parse a duration like `30`, `90s`, `15m`, `12h` or `7d`. A number without a unit is in seconds.
+pub fn parse_duration(duration: &str) -> anyhow::Result<Duration> {
+    let (number, unit) = match duration.find(|c: char| !c.is_ascii_digit()) {
+        Some(index) => duration.split_at(index),
+        None => (duration, "s"),
+    };
+
+    let number: u64 =
+        number.parse().map_err(|_| anyhow!("{duration:?} is not a correct duration."))?;
+
+    let seconds = match unit {
+        "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 24 * 60 * 60,
+        _ => return Err(anyhow!("{duration:?} is not a correct duration.")),
+    };
+
+    Ok(Duration::from_secs(number.saturating_mul(seconds)))
+}
+
+#[cfg(test)]
+mod tests {
+    #[cfg(feature = "async")]
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::test_zone::TestZone;
+    #[cfg(feature = "async")]
+    use crate::{AppResult, CacheZone, MatchOptions, PurgeOptions};
+
+    /// A cache zone and a trash directory beside it.
+    fn zone_with_trash(name: &str) -> (TestZone, TestZone) {
+        (TestZone::new(name, "1:2"), TestZone::new(&format!("{name}-trash"), "1"))
+    }
+
+    #[test]
+    fn create_purges() {
+        let (test_zone, trash_dir) = zone_with_trash("trash-create");
+
+        // purges created in the same second have distinct IDs
+        let a = Trash::create(&trash_dir.path, &test_zone.path).unwrap().unwrap();
+        let b = Trash::create(&trash_dir.path, &test_zone.path).unwrap().unwrap();
+
+        assert_ne!(a.purge_id, b.purge_id);
+        assert_eq!(list_trash(&trash_dir.path).unwrap().len(), 2);
+
+        // an empty purge is removed
+        assert_eq!(a.finish().unwrap(), None);
+        assert_eq!(list_trash(&trash_dir.path).unwrap().len(), 1);
+
+        let error = Trash::create(test_zone.path.join("trash"), &test_zone.path).unwrap_err();
+
+        assert!(matches!(error.downcast_ref::<PurgeError>(), Some(PurgeError::Refused(_))));
+        assert!(Trash::create(&trash_dir.path, test_zone.path.join("none")).unwrap().is_none());
+    }
+
+    #[cfg(feature = "async")]
+    #[tokio::test]
+    async fn purge_into_trash_and_restore() {
+        let (test_zone, trash_dir) = zone_with_trash("trash-restore");
+
+        let blog = test_zone.add("http/blog/", "");
+        let blog_2 = test_zone.add("http/blog/2", "");
+        let image = test_zone.add("http/static/a.jpg", "");
+
+        let trash = Arc::new(Trash::create(&trash_dir.path, &test_zone.path).unwrap().unwrap());
+
+        let zone = CacheZone::new(&test_zone.path, "1:2").unwrap().with_options(PurgeOptions {
+            trash: Some(trash.clone()),
+            ..PurgeOptions::default()
+        });
+
+        assert!(matches!(
+            zone.purge_pattern("http/blog/*", MatchOptions::default()).await.unwrap(),
+            AppResult::Ok
+        ));
+        assert_eq!(test_zone.files(), [image.as_path()]);
+
+        let purge_id = trash.finish().unwrap().unwrap().to_string();
+
+        let trash_entries = list_trash(&trash_dir.path).unwrap();
+
+        assert_eq!(trash_entries.len(), 1);
+        assert_eq!(trash_entries[0].purge_id, purge_id);
+        assert_eq!(trash_entries[0].entries.len(), 2);
+
+        // nginx has stored a newer cache of this key since the purge
+        test_zone.add("http/blog/2", "X-New: 1\r\n");
+
+        let report = restore(&trash_dir.path, &purge_id).await.unwrap();
+
+        assert_eq!((report.restored, report.skipped), (1, 1));
+        assert!(blog.exists());
+        assert_eq!(
+            zone.inspect("http/blog/2").unwrap().unwrap().header.header_values("X-New").count(),
+            1
+        );
+        assert!(blog_2.exists());
+        assert!(list_trash(&trash_dir.path).unwrap().is_empty());
+        assert!(restore(&trash_dir.path, &purge_id).await.is_err());
+    }
+
+    #[cfg(feature = "async")]
+    #[tokio::test]
+    async fn purge_all_into_trash_and_restore() {
+        let (test_zone, trash_dir) = zone_with_trash("trash-all");
+
+        test_zone.add("http/blog/", "");
+        test_zone.add("http/static/a.jpg", "");
+
+        let files = test_zone.files();
+
+        let trash = Arc::new(Trash::create(&trash_dir.path, &test_zone.path).unwrap().unwrap());
+
+        let zone = CacheZone::new(&test_zone.path, "1:2").unwrap().with_options(PurgeOptions {
+            trash: Some(trash.clone()),
+            ..PurgeOptions::default()
+        });
+
+        assert!(matches!(
+            zone.purge_pattern("*", MatchOptions::default()).await.unwrap(),
+            AppResult::Ok
+        ));
+        assert!(test_zone.files().is_empty());
+
+        let purge_id = trash.finish().unwrap().unwrap().to_string();
+
+        let report = restore(&trash_dir.path, &purge_id).await.unwrap();
+
+        assert_eq!((report.restored, report.skipped), (2, 0));
+        assert_eq!(test_zone.files(), files);
+    }
+
+    #[test]
+    fn parse_durations() {
+        assert_eq!(parse_duration("30").unwrap(), Duration::from_secs(30));
+        assert_eq!(parse_duration("90s").unwrap(), Duration::from_secs(90));
+        assert_eq!(parse_duration("15m").unwrap(), Duration::from_secs(15 * 60));
+        assert_eq!(parse_duration("12h").unwrap(), Duration::from_secs(12 * 60 * 60));
+        assert_eq!(parse_duration("7d").unwrap(), Duration::from_secs(7 * 24 * 60 * 60));
+        assert_eq!(
+            parse_duration(&format!("{}d", u64::MAX)).unwrap(),
+            Duration::from_secs(u64::MAX)
+        );
+
+        for duration in ["", "d", "-1s", "1.5h", "10 m", "10w", "1hm"] {
+            assert!(parse_duration(duration).is_err(), "{duration}");
+        }
+    }
+}
@@ -0,0 +1,237 @@
+use std::{
+    collections::HashSet,
+    fs, io,
+    path::{Path, PathBuf},
+    sync::mpsc as std_mpsc,
+    thread,
+};
+#[cfg(feature = "async")]
+use std::{
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+#[cfg(feature = "async")]
+use futures_core::Stream;
+#[cfg(feature = "async")]
+use tokio::sync::mpsc;
+
+use crate::{
+    cache_file::read_cache_file_header, check::is_cache_file_name, error::PurgeError,
+    matcher::KeyMatcher, zone::CacheEntry,
+};
+
+/// How many entries can be read ahead of the consumer by default.
+pub const DEFAULT_PREFETCH: usize = 64;
+
+/// How to walk a cache zone.
+#[derive(Debug)]
+pub struct WalkOptions {
+    /// Only the entries whose keys match are yielded.
+    pub filter:       Option<KeyMatcher>,
+    /// Whether to read the stored response headers. If `false`, only the keys are read.
+    pub read_headers: bool,
+    /// How many entries can be read ahead of the consumer.
+    pub prefetch:     usize,
+}
+
+impl Default for WalkOptions {
+    #[inline]
+    fn default() -> Self {
+        WalkOptions {
+            filter: None, read_headers: true, prefetch: DEFAULT_PREFETCH
+        }
+    }
+}
+
+/// The files which are not read during a walk, such as the files of the excluded keys.
+pub(crate) type SkipPaths = HashSet<PathBuf>;
+
+/// A lazy `Stream` of the cache entries of a cache zone. The cache files are read on a blocking thread, and the walk stops when the stream is dropped.
+///
+/// An error ends the stream. The files which are removed during the walk are skipped.
+#[cfg(feature = "async")]
+#[derive(Debug)]
+pub struct EntryStream {
+    receiver: mpsc::Receiver<Result<CacheEntry, PurgeError>>,
+}
+
+#[cfg(feature = "async")]
+impl EntryStream {
+    /// Start walking a cache zone. This has to be called within a Tokio runtime.
+    pub(crate) fn new(
+        cache_path: PathBuf,
+        levels: Vec<usize>,
+        options: WalkOptions,
+        skip_paths: SkipPaths,
+    ) -> Self {
+        // a Tokio channel cannot be empty
+        let (sender, receiver) = mpsc::channel(options.prefetch.max(1));
+
+        tokio::task::spawn_blocking(move || {
+            walk(cache_path.as_path(), &levels, &options, &skip_paths, |entry| {
+                sender.blocking_send(entry).is_ok()
+            })
+        });
+
+        EntryStream {
+            receiver,
+        }
+    }
+
+    /// Get the next cache entry. Return `None` if the walk has finished.
+    #[inline]
+    pub async fn next(&mut self) -> Option<Result<CacheEntry, PurgeError>> {
+        self.receiver.recv().await
+    }
+}
+
+#[cfg(feature = "async")]
+impl Stream for EntryStream {
+    type Item = Result<CacheEntry, PurgeError>;
+
+    #[inline]
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.receiver.poll_recv(cx)
+    }
+}
+
+/// A lazy `Iterator` of the cache entries of a cache zone, which does not need a Tokio runtime. The cache files are read on another thread, and the walk stops when the iterator is dropped.
+///
+/// An error ends the iteration. The files which are removed during the walk are skipped.
+#[derive(Debug)]
+pub struct EntryIter {
+    receiver: std_mpsc::Receiver<Result<CacheEntry, PurgeError>>,
+}
+
+impl EntryIter {
+    pub(crate) fn new(
+        cache_path: PathBuf,
+        levels: Vec<usize>,
+        options: WalkOptions,
+        skip_paths: SkipPaths,
+    ) -> Self {
+        let (sender, receiver) = std_mpsc::sync_channel(options.prefetch);
+
+        thread::spawn(move || {
+            walk(cache_path.as_path(), &levels, &options, &skip_paths, |entry| {
+                sender.send(entry).is_ok()
+            })
+        });
+
+        EntryIter {
+            receiver,
+        }
+    }
+}
+
+impl Iterator for EntryIter {
+    type Item = Result<CacheEntry, PurgeError>;
+
+    #[inline]
+    fn next(&mut self) -> Option<Self::Item> {
+        self.receiver.recv().ok()
+    }
+}
+
+/// Read the key, the stored response headers (if `read_headers` is `true`) and the size of a cache file.
+pub(crate) fn read_cache_entry(
+    path: PathBuf,
+    read_headers: bool,
+) -> Result<CacheEntry, PurgeError> {
+    let size = path.metadata().map_err(|error| PurgeError::io(&path, error))?.len();
+
+    let header = read_cache_file_header(path.as_path(), read_headers)
+        .map_err(|error| PurgeError::read(&path, error))?;
+
+    Ok(CacheEntry {
+        path,
+        key: header.key.clone(),
+        header,
+        size,
+    })
+}
+
+/// Walk the directories of a cache zone depth-first and pass the cache entries to `emit`. The walk stops after an error, or when `emit` returns `false`.
+pub(crate) fn walk(
+    cache_path: &Path,
+    levels: &[usize],
+    options: &WalkOptions,
+    skip_paths: &SkipPaths,
+    mut emit: impl FnMut(Result<CacheEntry, PurgeError>) -> bool,
+) {
+    if let Err(error) = walk_directory(cache_path, 0, levels.len(), options, skip_paths, &mut emit)
+    {
+        emit(Err(error));
+    }
+}
+
+/// Return `Ok(false)` if the walk has been stopped by `emit`.
+fn walk_directory(
+    directory: &Path,
+    depth: usize,
+    number_of_levels: usize,
+    options: &WalkOptions,
+    skip_paths: &SkipPaths,
+    emit: &mut impl FnMut(Result<CacheEntry, PurgeError>) -> bool,
+) -> Result<bool, PurgeError> {
+    let dir_entries = match fs::read_dir(directory) {
+        Ok(dir_entries) => dir_entries,
+        // removed by someone else
+        Err(error) if error.kind() == io::ErrorKind::NotFound => return Ok(true),
+        Err(error) => return Err(PurgeError::io(directory, error)),
+    };
+
+    for dir_entry in dir_entries {
+        let dir_entry = dir_entry.map_err(|error| PurgeError::io(directory, error))?;
+        let path = dir_entry.path();
+
+        let file_type = match dir_entry.file_type() {
+            Ok(file_type) => file_type,
+            Err(error) if error.kind() == io::ErrorKind::NotFound => continue,
+            Err(error) => return Err(PurgeError::io(&path, error)),
+        };
+
+        if depth < number_of_levels {
+            if file_type.is_dir()
+                && !walk_directory(
+                    path.as_path(),
+                    depth + 1,
+                    number_of_levels,
+                    options,
+                    skip_paths,
+                    emit,
+                )?
+            {
+                return Ok(false);
+            }
+
+            continue;
+        }
+
+        if !file_type.is_file()
+            || !is_cache_file_name(&dir_entry.file_name().to_string_lossy())
+            || skip_paths.contains(&path)
+        {
+            continue;
+        }
+
+        let entry = match read_cache_entry(path, options.read_headers) {
+            Ok(entry) => entry,
+            Err(error) if error.is_not_found() => continue,
+            Err(error) => return Err(error),
+        };
+
+        if let Some(filter) = options.filter.as_ref() {
+            if !filter.is_match(&entry.key) {
+                continue;
+            }
+        }
+
+        if !emit(Ok(entry)) {
+            return Ok(false);
+        }
+    }
+
+    Ok(true)
+}
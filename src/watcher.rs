@@ -0,0 +1,267 @@
+use std::{
+    collections::HashMap,
+    io,
+    path::{Path, PathBuf},
+    sync::Arc,
+    thread,
+    time::{Duration, Instant},
+};
+
+use anyhow::{anyhow, Context};
+use inotify::{EventMask, Inotify, WatchDescriptor, WatchMask};
+use tokio::sync::Mutex;
+
+use crate::index::CacheIndex;
+
+/// How often the index of a watched cache zone is saved if it has changed.
+const SAVE_INTERVAL: Duration = Duration::from_secs(30);
+
+/// A watched level directory.
+#[derive(Debug)]
+struct WatchedDirectory {
+    /// relative to the cache path, e.g. `6/33`
+    relative_path: String,
+    /// 1 for the first level
+    level:         usize,
+}
+
+struct Watcher {
+    inotify:          Inotify,
+    index:            Arc<Mutex<CacheIndex>>,
+    cache_path:       PathBuf,
+    number_of_levels: usize,
+    directories:      HashMap<WatchDescriptor, WatchedDirectory>,
+}
+
+impl Watcher {
+    /// Add watches to `path` (whose level is `level`) and all of its sub level directories. The paths of the last-level directories are collected into `new_directories`.
+    fn add_watches(
+        &mut self,
+        path: &Path,
+        relative_path: String,
+        level: usize,
+        new_directories: &mut Vec<String>,
+    ) -> anyhow::Result<()> {
+        let mask = if level == self.number_of_levels {
+            WatchMask::CLOSE_WRITE
+                | WatchMask::MOVED_TO
+                | WatchMask::DELETE
+                | WatchMask::MOVED_FROM
+                | WatchMask::ONLYDIR
+        } else {
+            WatchMask::CREATE
+                | WatchMask::MOVED_TO
+                | WatchMask::DELETE
+                | WatchMask::MOVED_FROM
+                | WatchMask::ONLYDIR
+        };
+
+        let wd = match self.inotify.watches().add(path, mask) {
+            Ok(wd) => wd,
+            // the directory has been removed in the meantime
+            Err(error) if error.kind() == io::ErrorKind::NotFound => return Ok(()),
+            Err(error) => return Err(error).with_context(|| anyhow!("{path:?}")),
+        };
+
+        self.directories.insert(wd, WatchedDirectory {
+            relative_path: relative_path.clone(),
+            level,
+        });
+
+        if level == self.number_of_levels {
+            new_directories.push(relative_path);
+
+            return Ok(());
+        }
+
+        let read_dir = match path.read_dir() {
+            Ok(read_dir) => read_dir,
+            Err(error) if error.kind() == io::ErrorKind::NotFound => return Ok(()),
+            Err(error) => return Err(error).with_context(|| anyhow!("{path:?}")),
+        };
+
+        for dir_entry in read_dir {
+            let dir_entry = dir_entry.with_context(|| anyhow!("{path:?}"))?;
+
+            match dir_entry.file_type() {
+                Ok(file_type) if file_type.is_dir() => (),
+                Ok(_) => continue,
+                Err(error) if error.kind() == io::ErrorKind::NotFound => continue,
+                Err(error) => return Err(error).with_context(|| anyhow!("{dir_entry:?}")),
+            }
+
+            let Ok(name) = dir_entry.file_name().into_string() else {
+                continue;
+            };
+
+            let relative_path =
+                if relative_path.is_empty() { name } else { format!("{relative_path}/{name}") };
+
+            self.add_watches(
+                dir_entry.path().as_path(),
+                relative_path,
+                level + 1,
+                new_directories,
+            )?;
+        }
+
+        Ok(())
+    }
+
+    /// Watch the whole cache zone and do a full rescan. This is also used to recover from an overflowed event queue.
+    fn watch_all(&mut self) -> anyhow::Result<()> {
+        let cache_path = self.cache_path.clone();
+
+        let mut new_directories = Vec::new();
+
+        self.add_watches(cache_path.as_path(), String::new(), 0, &mut new_directories)?;
+
+        let mut index = self.index.blocking_lock();
+
+        index.rescan()?;
+        index.set_watched(true);
+
+        Ok(())
+    }
+
+    fn run(mut self) -> anyhow::Result<()> {
+        self.watch_all()?;
+
+        tracing::info!("watching {:?}", self.cache_path);
+
+        let mut buffer = vec![0; 64 * 1024];
+        let mut last_save = Instant::now();
+
+        loop {
+            let events: Vec<_> = self
+                .inotify
+                .read_events_blocking(&mut buffer)
+                .with_context(|| anyhow!("{:?}", self.cache_path))?
+                .map(|event| event.to_owned())
+                .collect();
+
+            let mut overflowed = false;
+            let mut new_directories = Vec::new();
+            let mut removed_directories = Vec::new();
+            let mut updated_files = Vec::new();
+            let mut removed_files = Vec::new();
+
+            for event in events {
+                if event.mask.contains(EventMask::Q_OVERFLOW) {
+                    overflowed = true;
+
+                    continue;
+                }
+
+                if event.mask.contains(EventMask::IGNORED) {
+                    self.directories.remove(&event.wd);
+
+                    continue;
+                }
+
+                let (Some(directory), Some(name)) =
+                    (self.directories.get(&event.wd), event.name.as_ref())
+                else {
+                    continue;
+                };
+
+                let Some(name) = name.to_str() else {
+                    continue;
+                };
+
+                let level = directory.level;
+
+                let relative_path = if directory.relative_path.is_empty() {
+                    name.to_string()
+                } else {
+                    format!("{}/{name}", directory.relative_path)
+                };
+
+                if level == self.number_of_levels {
+                    if event.mask.intersects(EventMask::CLOSE_WRITE | EventMask::MOVED_TO) {
+                        updated_files.push(relative_path);
+                    } else if event.mask.intersects(EventMask::DELETE | EventMask::MOVED_FROM) {
+                        removed_files.push(relative_path);
+                    }
+                } else if event.mask.contains(EventMask::ISDIR) {
+                    if event.mask.intersects(EventMask::CREATE | EventMask::MOVED_TO) {
+                        let path = self.cache_path.join(relative_path.as_str());
+
+                        self.add_watches(
+                            path.as_path(),
+                            relative_path,
+                            level + 1,
+                            &mut new_directories,
+                        )?;
+                    } else if event.mask.intersects(EventMask::DELETE | EventMask::MOVED_FROM) {
+                        removed_directories.push(relative_path);
+                    }
+                }
+            }
+
+            if overflowed {
+                tracing::warn!("the event queue of {:?} overflowed, rescanning", self.cache_path);
+
+                self.watch_all()?;
+
+                continue;
+            }
+
+            let mut index = self.index.blocking_lock();
+
+            for relative_path in removed_directories {
+                index.remove_directory(relative_path);
+            }
+
+            for relative_path in new_directories {
+                index.update_directory(relative_path)?;
+            }
+
+            for relative_path in removed_files {
+                index.remove_entry(self.cache_path.join(relative_path));
+            }
+
+            for relative_path in updated_files {
+                index.update_entry(self.cache_path.join(relative_path))?;
+            }
+
+            if last_save.elapsed() >= SAVE_INTERVAL {
+                index.save()?;
+
+                last_save = Instant::now();
+            }
+        }
+    }
+}
+
+/// Watch the level directories of a cache zone with inotify in a new thread and keep its index up to date.
+pub fn spawn_watcher(index: Arc<Mutex<CacheIndex>>) -> anyhow::Result<()> {
+    let (cache_path, number_of_levels) = {
+        let index = index.try_lock().map_err(|_| anyhow!("The index is being used."))?;
+
+        (index.cache_path().to_path_buf(), index.levels().len())
+    };
+
+    let inotify = Inotify::init().with_context(|| anyhow!("{cache_path:?}"))?;
+
+    let watcher = Watcher {
+        inotify,
+        index: index.clone(),
+        cache_path,
+        number_of_levels,
+        directories: HashMap::new(),
+    };
+
+    thread::spawn(move || {
+        let cache_path = watcher.cache_path.clone();
+
+        if let Err(error) = watcher.run() {
+            tracing::error!("stopped watching {cache_path:?}: {error:?}");
+
+            // the index is no longer kept up to date
+            index.blocking_lock().set_watched(false);
+        }
+    });
+
+    Ok(())
+}
@@ -0,0 +1,414 @@
+use std::path::{Path, PathBuf};
+
+use crate::{
+    cache_file::CacheFileHeader,
+    error::PurgeError,
+    functions::{
+        create_cache_file_path, format_levels, parse_exclude_keys, parse_levels,
+        remove_caches_blocking, remove_caches_via_patterns_blocking,
+        remove_caches_via_patterns_with_progress, remove_caches_via_tags_blocking,
+        remove_one_cache_blocking, resolve_levels_blocking, Exclusions, PurgeOptions,
+    },
+    matcher::MatchOptions,
+    walk::{read_cache_entry, EntryIter, SkipPaths, WalkOptions},
+    AppResult,
+};
+#[cfg(feature = "async")]
+use crate::{
+    functions::{
+        remove_caches, remove_caches_via_patterns, remove_caches_via_tags, remove_one_cache,
+        resolve_levels,
+    },
+    walk::EntryStream,
+};
+
+/// A cache zone, which is the directory set by `proxy_cache_path` (or `fastcgi_cache_path`, etc.) and its levels.
+#[derive(Debug, Clone)]
+pub struct CacheZone {
+    path:    PathBuf,
+    levels:  Vec<usize>,
+    /// how the caches are purged
+    options: PurgeOptions,
+}
+
+/// A cache file in a cache zone.
+#[derive(Debug)]
+pub struct CacheEntry {
+    pub path:   PathBuf,
+    /// the raw bytes of the key
+    pub key:    Vec<u8>,
+    /// the stored response headers
+    pub header: CacheFileHeader,
+    /// the size of the cache file in bytes
+    pub size:   u64,
+}
+
+impl CacheZone {
+    /// Create a cache zone whose levels are like `1:2`.
+    #[inline]
+    pub fn new<P: Into<PathBuf>, L: AsRef<str>>(path: P, levels: L) -> Result<Self, PurgeError> {
+        Ok(CacheZone {
+            path:    path.into(),
+            levels:  parse_levels(levels)?,
+            options: PurgeOptions::default(),
+        })
+    }
+
+    /// Create a cache zone whose levels are detected from its layout. Return `None` if there is no cache file to detect the levels.
+    #[cfg(feature = "async")]
+    #[inline]
+    pub async fn detect<P: Into<PathBuf>>(path: P) -> Result<Option<Self>, PurgeError> {
+        let path = path.into();
+
+        Ok(resolve_levels(path.as_path(), "auto").await?.map(|levels| CacheZone {
+            path,
+            levels,
+            options: PurgeOptions::default(),
+        }))
+    }
+
+    /// Like `detect`, but without a Tokio runtime.
+    #[inline]
+    pub fn detect_blocking<P: Into<PathBuf>>(path: P) -> Result<Option<Self>, PurgeError> {
+        let path = path.into();
+
+        Ok(resolve_levels_blocking(path.as_path(), "auto")?.map(|levels| CacheZone {
+            path,
+            levels,
+            options: PurgeOptions::default(),
+        }))
+    }
+
+    /// Set how the caches are purged, such as the protected keys or the trash. By default, every cache can be purged and the files are removed.
+    #[inline]
+    pub fn with_options(mut self, options: PurgeOptions) -> Self {
+        self.options = options;
+
+        self
+    }
+
+    #[inline]
+    pub fn path(&self) -> &Path {
+        self.path.as_path()
+    }
+
+    #[inline]
+    pub fn levels(&self) -> &[usize] {
+        self.levels.as_slice()
+    }
+
+    #[inline]
+    pub fn options(&self) -> &PurgeOptions {
+        &self.options
+    }
+
+    /// Get the path of the cache file of a key, whether it exists or not.
+    #[inline]
+    pub fn path_for_key<K: AsRef<[u8]>>(&self, key: K) -> PathBuf {
+        create_cache_file_path(self.path.as_path(), &self.levels, key)
+    }
+
+    /// Read the cache file of a key. Return `None` if the key is not cached.
+    pub fn inspect<K: AsRef<[u8]>>(&self, key: K) -> Result<Option<CacheEntry>, PurgeError> {
+        match read_cache_entry(self.path_for_key(key), true) {
+            Ok(entry) => Ok(Some(entry)),
+            Err(error) if error.is_not_found() => Ok(None),
+            Err(error) => Err(error),
+        }
+    }
+
+    /// Walk every cache file in the cache zone lazily. This has to be called within a Tokio runtime.
+    #[cfg(feature = "async")]
+    #[inline]
+    pub fn entries(&self) -> EntryStream {
+        self.walk(WalkOptions::default())
+    }
+
+    /// Walk the cache files in the cache zone lazily with `options`, such as a key filter. This has to be called within a Tokio runtime.
+    #[cfg(feature = "async")]
+    #[inline]
+    pub fn walk(&self, options: WalkOptions) -> EntryStream {
+        EntryStream::new(self.path.clone(), self.levels.clone(), options, SkipPaths::new())
+    }
+
+    /// Like `entries`, but without a Tokio runtime.
+    #[inline]
+    pub fn entries_blocking(&self) -> EntryIter {
+        self.walk_blocking(WalkOptions::default())
+    }
+
+    /// Like `walk`, but without a Tokio runtime.
+    #[inline]
+    pub fn walk_blocking(&self, options: WalkOptions) -> EntryIter {
+        EntryIter::new(self.path.clone(), self.levels.clone(), options, SkipPaths::new())
+    }
+
+    /// Purge the cache of an exact key. A protected key is not purged.
+    #[cfg(feature = "async")]
+    #[inline]
+    pub async fn purge_key<K: AsRef<str>>(&self, key: K) -> Result<AppResult, PurgeError> {
+        let exclusions = self.protected_exclusions()?;
+
+        remove_one_cache(
+            self.path.as_path(),
+            &self.levels,
+            key.as_ref(),
+            &exclusions,
+            &self.options,
+        )
+        .await
+    }
+
+    /// Like `purge_key`, but without a Tokio runtime.
+    #[inline]
+    pub fn purge_key_blocking<K: AsRef<str>>(&self, key: K) -> Result<AppResult, PurgeError> {
+        let exclusions = self.protected_exclusions()?;
+
+        remove_one_cache_blocking(
+            self.path.as_path(),
+            &self.levels,
+            key.as_ref(),
+            &exclusions,
+            &self.options,
+        )
+    }
+
+    /// Purge the caches whose keys match a pattern, which is interpreted with `options`.
+    #[cfg(feature = "async")]
+    #[inline]
+    pub async fn purge_pattern<K: AsRef<str>>(
+        &self,
+        pattern: K,
+        options: MatchOptions,
+    ) -> Result<AppResult, PurgeError> {
+        remove_caches_via_patterns(
+            self.path.as_path(),
+            format_levels(&self.levels),
+            vec![pattern],
+            Vec::<&str>::new(),
+            options,
+            &self.options,
+        )
+        .await
+    }
+
+    /// Like `purge_pattern`, but without a Tokio runtime.
+    #[inline]
+    pub fn purge_pattern_blocking<K: AsRef<str>>(
+        &self,
+        pattern: K,
+        options: MatchOptions,
+    ) -> Result<AppResult, PurgeError> {
+        remove_caches_via_patterns_blocking(
+            self.path.as_path(),
+            format_levels(&self.levels),
+            vec![pattern],
+            Vec::<&str>::new(),
+            options,
+            &self.options,
+        )
+    }
+
+    /// Like `purge_pattern_blocking`, but `progress` is called with each removed cache. The purge stops if it returns `false`, and the caches which are being removed at that time are still reported.
+    #[inline]
+    pub fn purge_pattern_with_progress<K: AsRef<str>>(
+        &self,
+        pattern: K,
+        options: MatchOptions,
+        progress: impl FnMut(&CacheEntry) -> bool,
+    ) -> Result<AppResult, PurgeError> {
+        remove_caches_via_patterns_with_progress(
+            self.path.as_path(),
+            format_levels(&self.levels),
+            vec![pattern],
+            Vec::<&str>::new(),
+            options,
+            &self.options,
+            progress,
+        )
+    }
+
+    /// Purge the caches of multiple keys and key patterns except for the excluded ones, like the `purge` command. A wildcard purge stops after `max_entries` caches have been removed, which overrides the limit in the options of the zone.
+    #[cfg(feature = "async")]
+    #[inline]
+    pub async fn purge_keys<K: AsRef<str>, EK: AsRef<str>>(
+        &self,
+        keys: Vec<K>,
+        exclude_keys: Vec<EK>,
+        options: MatchOptions,
+        max_entries: Option<usize>,
+    ) -> Result<AppResult, PurgeError> {
+        remove_caches(
+            self.path.as_path(),
+            format_levels(&self.levels),
+            keys,
+            exclude_keys,
+            options,
+            &self.options_with_max_entries(max_entries),
+        )
+        .await
+    }
+
+    /// Like `purge_keys`, but without a Tokio runtime.
+    #[inline]
+    pub fn purge_keys_blocking<K: AsRef<str>, EK: AsRef<str>>(
+        &self,
+        keys: Vec<K>,
+        exclude_keys: Vec<EK>,
+        options: MatchOptions,
+        max_entries: Option<usize>,
+    ) -> Result<AppResult, PurgeError> {
+        remove_caches_blocking(
+            self.path.as_path(),
+            format_levels(&self.levels),
+            keys,
+            exclude_keys,
+            options,
+            &self.options_with_max_entries(max_entries),
+        )
+    }
+
+    /// Purge the caches whose stored response header `tag_header` contains any of the `tags`.
+    #[cfg(feature = "async")]
+    #[inline]
+    pub async fn purge_tags<H: Into<String>, T: AsRef<str>>(
+        &self,
+        tag_header: H,
+        tags: Vec<T>,
+    ) -> Result<AppResult, PurgeError> {
+        remove_caches_via_tags(
+            self.path.as_path(),
+            format_levels(&self.levels),
+            tag_header,
+            tags,
+            Vec::<&str>::new(),
+            &self.options,
+        )
+        .await
+    }
+
+    /// Like `purge_tags`, but without a Tokio runtime.
+    #[inline]
+    pub fn purge_tags_blocking<H: Into<String>, T: AsRef<str>>(
+        &self,
+        tag_header: H,
+        tags: Vec<T>,
+    ) -> Result<AppResult, PurgeError> {
+        remove_caches_via_tags_blocking(
+            self.path.as_path(),
+            format_levels(&self.levels),
+            tag_header,
+            tags,
+            Vec::<&str>::new(),
+            &self.options,
+        )
+    }
+
+    /// Only the protected keys are excluded.
+    #[inline]
+    fn protected_exclusions(&self) -> Result<Option<Exclusions>, PurgeError> {
+        parse_exclude_keys(
+            self.path.as_path(),
+            &self.levels,
+            Vec::<&str>::new(),
+            MatchOptions::default(),
+            &self.options.protect,
+        )
+    }
+
+    #[inline]
+    fn options_with_max_entries(&self, max_entries: Option<usize>) -> PurgeOptions {
+        PurgeOptions {
+            max_entries: max_entries.or(self.options.max_entries),
+            ..self.options.clone()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+    use crate::test_zone::TestZone;
+
+    #[test]
+    fn inspect_and_walk() {
+        let test_zone = TestZone::new("zone", "1:2");
+
+        let blog = test_zone.add("http/blog/", "Cache-Tag: a b\r\n");
+        let image = test_zone.add(b"http/static/\xff.jpg", "");
+
+        let zone = CacheZone::detect_blocking(&test_zone.path).unwrap().unwrap();
+
+        assert_eq!(zone.levels(), [1, 2]);
+        assert_eq!(zone.path_for_key("http/blog/"), blog);
+        assert_eq!(zone.path_for_key(b"http/static/\xff.jpg"), image);
+
+        let entry = zone.inspect("http/blog/").unwrap().unwrap();
+
+        assert_eq!(entry.path, blog);
+        assert_eq!(entry.key, b"http/blog/");
+        assert_eq!(entry.header.tags("Cache-Tag").collect::<Vec<_>>(), [b"a", b"b"]);
+        assert_eq!(entry.size, fs::metadata(&blog).unwrap().len());
+        assert!(zone.inspect("http/none").unwrap().is_none());
+
+        let mut keys: Vec<Vec<u8>> =
+            zone.entries_blocking().map(|entry| entry.unwrap().key).collect();
+
+        keys.sort();
+
+        assert_eq!(keys, [b"http/blog/".to_vec(), b"http/static/\xff.jpg".to_vec()]);
+    }
+
+    #[test]
+    fn purge() {
+        let test_zone = TestZone::new("zone-purge", "1:2");
+
+        test_zone.add("http/blog/", "Cache-Tag: a\r\n");
+        test_zone.add("http/blog/2", "Cache-Tag: b\r\n");
+
+        let image = test_zone.add("http/static/a.jpg", "");
+
+        let zone = CacheZone::new(&test_zone.path, "1:2").unwrap();
+
+        assert!(matches!(zone.purge_key_blocking("http/blog/").unwrap(), AppResult::Ok));
+        assert!(matches!(
+            zone.purge_key_blocking("http/blog/").unwrap(),
+            AppResult::AlreadyPurged(_)
+        ));
+        assert!(matches!(zone.purge_tags_blocking("Cache-Tag", vec!["b"]).unwrap(), AppResult::Ok));
+        assert_eq!(test_zone.files(), [image]);
+        assert!(matches!(
+            zone.purge_pattern_blocking("http/*", MatchOptions::default()).unwrap(),
+            AppResult::Ok
+        ));
+        assert!(test_zone.files().is_empty());
+    }
+
+    #[test]
+    fn dry_run() {
+        let test_zone = TestZone::new("zone-dry-run", "1:2");
+
+        test_zone.add("http/blog/", "");
+        test_zone.add("http/static/a.jpg", "");
+
+        let files = test_zone.files();
+
+        let zone = CacheZone::new(&test_zone.path, "1:2").unwrap().with_options(PurgeOptions {
+            dry_run: true,
+            ..PurgeOptions::default()
+        });
+
+        assert!(matches!(zone.purge_key_blocking("http/blog/").unwrap(), AppResult::Ok));
+        assert!(matches!(
+            zone.purge_pattern_blocking("http/*", MatchOptions::default()).unwrap(),
+            AppResult::Ok
+        ));
+        assert!(matches!(
+            zone.purge_pattern_blocking("*", MatchOptions::default()).unwrap(),
+            AppResult::Ok
+        ));
+        assert_eq!(test_zone.files(), files);
+    }
+}